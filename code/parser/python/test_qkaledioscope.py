@@ -0,0 +1,43 @@
+import json
+
+import pytest
+
+import qkaledioscope
+
+BELL = """
+extern h(q : qubit);
+extern cnot(c : qubit, t : qubit);
+extern m(q : qubit) -> bit;
+extern print(s : string);
+
+def qmain() -> bit {
+    h(%0);
+    cnot(%0, %1);
+    var first: bit = m(%0);
+    var second: bit = m(%1);
+    print("measured a Bell pair");
+    return first;
+}
+"""
+
+
+def test_interpret_bell_pair():
+    results = qkaledioscope.interpret(BELL)
+    first, second = results["measurements"]
+    assert first == second
+    assert results["return_value"] == first
+    assert results["output"] == ["measured a Bell pair"]
+
+
+def test_parse_returns_json():
+    ast = json.loads(qkaledioscope.parse(BELL))
+    assert len(ast) == 5
+
+
+def test_compile_returns_ir():
+    assert "define" in qkaledioscope.compile(BELL)
+
+
+def test_errors_are_raised_with_diagnostics():
+    with pytest.raises(qkaledioscope.QKaledioscopeError, match="qmain"):
+        qkaledioscope.interpret("def main() {}")
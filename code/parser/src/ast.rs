@@ -1,25 +1,33 @@
 use miette::{SourceSpan};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 // NB: Located should not be used for structs that are atomic --- that is, that
 //     wrap a single value, such as Identifier. Those structs and enums which
 //     have Identifiers as items should use Located to say where they got those
 //     Identifiers, however.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct Located<T: std::fmt::Debug> {
     pub value: T,
-    pub location: Option<(usize, usize)>
-}
-impl<T> From<T> for Located<T> where T: std::fmt::Debug {
-    fn from(value: T) -> Self {
-        Located { value, location: None }
-    }
+    /// The start and end offsets of this node in the source it was parsed
+    /// from.
+    pub location: (usize, usize)
 }
 impl<T> Located<T> where T: std::fmt::Debug {
+    pub fn new(value: T, location: (usize, usize)) -> Self {
+        Located { value, location }
+    }
+
+    /// Wraps a node that doesn't come from any source text, such as one
+    /// generated by the compiler itself. Synthesized nodes are given an empty
+    /// span at the start of the source, so that diagnostics which refer to
+    /// them still render.
+    pub fn synthesized(value: T) -> Self {
+        Located { value, location: (0, 0) }
+    }
+
     pub fn as_sourcespan(&self) -> SourceSpan {
-        // TODO: Remove unwrap by making located not use an option.
-        let loc = self.location.unwrap();
-        (loc.0, loc.1 - loc.0).into()
+        let (start, end) = self.location;
+        (start, end - start).into()
     }
 }
 impl<T> Clone for Located<T> where T: std::fmt::Debug + Clone {
@@ -28,10 +36,33 @@ impl<T> Clone for Located<T> where T: std::fmt::Debug + Clone {
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct Program(pub Vec<Located<FileElement>>);
+impl Program {
+    pub fn new(elements: Vec<Located<FileElement>>) -> Self {
+        Program(elements)
+    }
+}
+impl std::fmt::Display for Program {
+    /// Formats a program as canonical Quantum Kaledioscope source, with
+    /// definitions separated from their neighbors by blank lines.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut previous: Option<&FileElement> = None;
+        for element in &self.0 {
+            if let Some(previous) = previous {
+                let is_definition = |element: &FileElement| matches!(element, FileElement::Definition { .. });
+                if is_definition(previous) || is_definition(&element.value) {
+                    writeln!(f)?;
+                }
+            }
+            writeln!(f, "{}", element.value)?;
+            previous = Some(&element.value);
+        }
+        Ok(())
+    }
+}
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum FileElement {
     Declaration(Located<Prototype>),
     // TODO: Finish adding items to Definition.
@@ -39,52 +70,674 @@ pub enum FileElement {
         prototype: Located<Prototype>,
         body: Vec<Located<Statement>>,
     },
+    /// A value that's computed once when the program starts, and that can
+    /// then be used by name from any function.
+    Constant {
+        name: Located<Identifier>,
+        type_sig: Located<Type>,
+        expr: Located<Expression>,
+    },
+    /// A variable that every function can read and assign to.
+    Global {
+        name: Located<Identifier>,
+        type_sig: Located<Type>,
+        expr: Located<Expression>,
+    },
+    /// The path of another file whose definitions this one uses, as in
+    /// `include "helpers.qk";`. Reading a program from a file replaces each
+    /// include with what's in the file it names (see
+    /// `ast_builder::build_ast`), so these are only left in programs that
+    /// haven't been read that way, such as those being formatted.
+    ///
+    /// ```
+    /// use parser::ast::FileElement;
+    /// let program = parser::parse_program("include \"helpers.qk\";\ndef qmain() { }").unwrap();
+    /// assert!(matches!(&program.0[0].value, FileElement::Include(path) if path == "helpers.qk"));
+    /// assert!(program.to_string().starts_with("include \"helpers.qk\";\n"));
+    /// ```
+    Include(String),
 }
 
-#[derive(Debug, Serialize, Clone)]
+impl FileElement {
+    /// The prototype of the function declared or defined by this element,
+    /// or `None` if this element isn't a function.
+    pub fn prototype(&self) -> Option<&Located<Prototype>> {
+        match self {
+            FileElement::Declaration(prototype) => Some(prototype),
+            FileElement::Definition { prototype, .. } => Some(prototype),
+            FileElement::Constant { .. } | FileElement::Global { .. } | FileElement::Include(_) => None,
+        }
+    }
+}
+impl std::fmt::Display for FileElement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(doc) = self.prototype().and_then(|prototype| prototype.value.doc.as_ref()) {
+            for line in doc.lines() {
+                if line.is_empty() {
+                    writeln!(f, "##")?;
+                } else {
+                    writeln!(f, "## {line}")?;
+                }
+            }
+        }
+        match self {
+            FileElement::Declaration(prototype) => write!(f, "extern {};", prototype.value),
+            FileElement::Definition { prototype, body } => {
+                write!(f, "def {} ", prototype.value)?;
+                fmt_body(f, body)
+            },
+            FileElement::Constant { name, type_sig, expr } =>
+                write!(f, "const {}: {} = {};", name.value, type_sig.value, expr.value),
+            FileElement::Global { name, type_sig, expr } =>
+                write!(f, "var {}: {} = {};", name.value, type_sig.value, expr.value),
+            FileElement::Include(path) => write!(f, "include {};", Expression::StringLiteral(path.clone())),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Prototype {
     pub name: Located<Identifier>,
     pub arguments: Vec<Located<ArgumentDeclaration>>,
     pub return_type: Option<Located<Type>>,
+    /// Documentation written in `##` comments just before the declaration
+    /// or definition of this function, with each line's leading `##`
+    /// removed. Documentation is written back out when the program is
+    /// displayed, such that it survives being parsed again:
+    ///
+    /// ```
+    /// let source = "
+    ///     ## Flips a coin.
+    ///     ##
+    ///     ## Uses qubit %0.
+    ///     def coin_flip() -> bit { h(%0); return measure %0; }
+    ///     ## Applies a Hadamard gate.
+    ///     extern h(q : qubit);
+    /// ";
+    /// let program = parser::parse_program(source).unwrap();
+    /// let reparsed = parser::parse_program(&program.to_string()).unwrap();
+    /// let docs = |program: &parser::ast::Program| program.0
+    ///     .iter()
+    ///     .map(|element| element.value.prototype().unwrap().value.doc.clone())
+    ///     .collect::<Vec<_>>();
+    /// assert_eq!(docs(&reparsed), vec![
+    ///     Some("Flips a coin.\n\nUses qubit %0.".to_string()),
+    ///     Some("Applies a Hadamard gate.".to_string()),
+    /// ]);
+    /// assert_eq!(docs(&reparsed), docs(&program));
+    /// assert_eq!(reparsed.to_string(), program.to_string());
+    /// ```
+    pub doc: Option<String>,
+}
+
+impl std::fmt::Display for Prototype {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let arguments = self.arguments.iter().map(|arg| arg.value.to_string()).collect::<Vec<_>>();
+        write!(f, "{}({})", self.name.value, arguments.join(", "))?;
+        if let Some(return_type) = &self.return_type {
+            write!(f, " -> {}", return_type.value)?;
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct ArgumentDeclaration(pub Located<Identifier>, pub Located<Type>);
+impl std::fmt::Display for ArgumentDeclaration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.0.value, self.1.value)
+    }
+}
 
-#[derive(Debug, Serialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum Type {
     Number,
     Qubit,
     Bit,
+    /// A reference to a built-in single-qubit gate, such as `h` in
+    /// `controlled(h, %0, %1)`. Gates can only be passed directly to
+    /// built-in functions, and so there's no keyword for this type.
+    Gate,
+    String,
+    /// A fixed number of values, each with its own type, such as
+    /// `(bit, number)`.
+    Tuple(Vec<Type>),
+    /// A fixed number of classical bits, written `bit[n]`.
+    Register(usize),
+    /// The type of calls to functions without a return type. Like gates,
+    /// there's no keyword for this type, as nothing can be declared with it.
+    Unit,
+}
+impl std::fmt::Display for Type {
+    /// Formats a type using the same keyword used to write it in Quantum
+    /// Kaledioscope source.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Type::Number => "number",
+            Type::Qubit => "qubit",
+            Type::Bit => "bit",
+            Type::Gate => "gate",
+            Type::String => "string",
+            Type::Tuple(types) => return write!(f, "({})", DisplayList(types)),
+            Type::Register(size) => return write!(f, "bit[{size}]"),
+            Type::Unit => "no value",
+        })
+    }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
 pub struct Identifier(pub String);
+impl std::fmt::Display for Identifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum Statement {
-    VariableDeclaration(Located<Identifier>, Located<Type>, Located<Expression>),
+    /// Declares a new variable, with a type that's inferred from its value
+    /// if not given explicitly.
+    VariableDeclaration(Located<Identifier>, Option<Located<Type>>, Located<Expression>),
+    /// Declares a new variable for each element of a tuple, as in
+    /// `var (a, b) = f();`.
+    TupleDeclaration(Vec<Located<Identifier>>, Option<Located<Type>>, Located<Expression>),
     Assignment(Located<Identifier>, Located<Expression>),
+    /// Sets one bit of a register, as in `r[0] = measure %0;`.
+    IndexAssignment(Located<Identifier>, Located<Expression>, Located<Expression>),
     Call(Located<Identifier>, Vec<Located<Expression>>),
     If {
         condition: Located<Expression>,
         true_body: Vec<Located<Statement>>,
         false_body: Vec<Located<Statement>>
     },
+    /// Runs `body` for as long as `condition` holds.
     While {
         condition: Located<Expression>,
         body: Vec<Located<Statement>>,
     },
-    Return(Located<Expression>),
+    /// Exits the current function, optionally with a value.
+    Return(Option<Located<Expression>>),
+    /// Marks a point that gates on the given qubits (or on every qubit, if
+    /// none are given) shouldn't be reordered across. Barriers don't do
+    /// anything when a program is run.
+    Barrier(Vec<Located<Expression>>),
+}
+impl std::fmt::Display for Statement {
+    /// Formats a statement as Quantum Kaledioscope source, without any
+    /// leading indentation. Nested bodies are indented relative to the
+    /// statement itself.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Statement::VariableDeclaration(ident, Some(type_sig), expr) =>
+                write!(f, "var {}: {} = {};", ident.value, type_sig.value, expr.value),
+            Statement::VariableDeclaration(ident, None, expr) =>
+                write!(f, "var {} = {};", ident.value, expr.value),
+            Statement::TupleDeclaration(idents, Some(type_sig), expr) => {
+                let idents = idents.iter().map(|ident| &ident.value).collect::<Vec<_>>();
+                write!(f, "var ({}): {} = {};", DisplayList(&idents), type_sig.value, expr.value)
+            },
+            Statement::TupleDeclaration(idents, None, expr) => {
+                let idents = idents.iter().map(|ident| &ident.value).collect::<Vec<_>>();
+                write!(f, "var ({}) = {};", DisplayList(&idents), expr.value)
+            },
+            Statement::Assignment(ident, expr) =>
+                write!(f, "{} = {};", ident.value, expr.value),
+            Statement::IndexAssignment(ident, index, expr) =>
+                write!(f, "{}[{}] = {};", ident.value, index.value, expr.value),
+            Statement::Call(ident, arguments) =>
+                write!(f, "{};", DisplayCall(ident, arguments)),
+            Statement::If { condition, true_body, false_body } => {
+                write!(f, "if {} ", condition.value)?;
+                fmt_body(f, true_body)?;
+                if !false_body.is_empty() {
+                    write!(f, " else ")?;
+                    fmt_body(f, false_body)?;
+                }
+                Ok(())
+            },
+            Statement::While { condition, body } => {
+                write!(f, "while {} ", condition.value)?;
+                fmt_body(f, body)
+            },
+            Statement::Return(Some(expr)) => write!(f, "return {};", expr.value),
+            Statement::Return(None) => write!(f, "return;"),
+            Statement::Barrier(qubits) if qubits.is_empty() => write!(f, "barrier;"),
+            Statement::Barrier(qubits) => {
+                let qubits = qubits.iter().map(|qubit| &qubit.value).collect::<Vec<_>>();
+                write!(f, "barrier {};", DisplayList(&qubits))
+            },
+        }
+    }
+}
+
+/// Writes a body of statements between curly braces, indenting each
+/// statement by one level.
+fn fmt_body(f: &mut std::fmt::Formatter<'_>, body: &[Located<Statement>]) -> std::fmt::Result {
+    if body.is_empty() {
+        return write!(f, "{{}}");
+    }
+    writeln!(f, "{{")?;
+    for statement in body {
+        for line in statement.value.to_string().lines() {
+            writeln!(f, "    {line}")?;
+        }
+    }
+    write!(f, "}}")
+}
+
+/// Formats a call to a function, either as a statement or an expression.
+struct DisplayCall<'a>(&'a Located<Identifier>, &'a [Located<Expression>]);
+impl std::fmt::Display for DisplayCall<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let arguments = self.1.iter().map(|arg| &arg.value).collect::<Vec<_>>();
+        write!(f, "{}({})", self.0.value, DisplayList(&arguments))
+    }
+}
+
+/// Formats a list of items separated by commas, as in the arguments to a
+/// call or the elements of a tuple.
+struct DisplayList<'a, T>(&'a [T]);
+impl<T: std::fmt::Display> std::fmt::Display for DisplayList<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let items = self.0.iter().map(|item| item.to_string()).collect::<Vec<_>>();
+        f.write_str(&items.join(", "))
+    }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+impl BinaryOperator {
+    /// How tightly this operator binds to its operands, matching the
+    /// precedence climber used to parse binary expressions. All operators
+    /// associate to the left.
+    pub fn precedence(&self) -> u8 {
+        match self {
+            BinaryOperator::Equal | BinaryOperator::NotEqual |
+            BinaryOperator::Less | BinaryOperator::LessEqual |
+            BinaryOperator::Greater | BinaryOperator::GreaterEqual => 1,
+            BinaryOperator::Add | BinaryOperator::Subtract => 2,
+            BinaryOperator::Multiply | BinaryOperator::Divide => 3,
+        }
+    }
 
+    /// Whether this operator compares the order of two numbers, such that
+    /// several of them can be chained together. Comparing the result of one
+    /// ordering comparison with another thus needs parentheses to keep it
+    /// from being read as a chain:
+    ///
+    /// ```
+    /// let program = parser::parse_program("const b: bit = (1.0 < 2.0) < 3.0;").unwrap();
+    /// assert_eq!(program.0[0].value.to_string(), "const b: bit = (1.0 < 2.0) < 3.0;");
+    /// ```
+    pub fn is_ordering(&self) -> bool {
+        matches!(self, BinaryOperator::Less | BinaryOperator::LessEqual | BinaryOperator::Greater | BinaryOperator::GreaterEqual)
+    }
+}
+impl std::fmt::Display for BinaryOperator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BinaryOperator::Add => "+",
+            BinaryOperator::Subtract => "-",
+            BinaryOperator::Multiply => "*",
+            BinaryOperator::Divide => "/",
+            BinaryOperator::Equal => "==",
+            BinaryOperator::NotEqual => "!=",
+            BinaryOperator::Less => "<",
+            BinaryOperator::LessEqual => "<=",
+            BinaryOperator::Greater => ">",
+            BinaryOperator::GreaterEqual => ">=",
+        })
+    }
+}
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum Expression {
     Call(Located<Identifier>, Vec<Located<Expression>>),
+    BinaryOp(Box<Located<Expression>>, Located<BinaryOperator>, Box<Located<Expression>>),
+    /// Two or more ordering comparisons written one after another, as in
+    /// `0.0 <= x < 10.0`.
+    ChainedComparison(Box<Located<Expression>>, Vec<(Located<BinaryOperator>, Located<Expression>)>),
+    Not(Box<Located<Expression>>),
+    /// Groups several values into one, as in `(a, b)`.
+    Tuple(Vec<Located<Expression>>),
+    /// A register written out one bit at a time, as in `[true, false]`.
+    Register(Vec<Located<Expression>>),
+    /// Reads one bit of a register, as in `r[0]`.
+    Index(Located<Identifier>, Box<Located<Expression>>),
+    /// Measures a qubit in the computational basis, evaluating to `true` if
+    /// it was found in the |1⟩ state.
+    Measure(Box<Located<Expression>>),
     Identifier(Identifier),
     QubitLiteral(usize),
     NumberLiteral(f64),
     BitLiteral(bool),
+    /// A string, with any escape sequences in its literal already decoded.
+    ///
+    /// ```
+    /// use parser::ast::{Expression, FileElement};
+    /// let program = parser::parse_program(r#"const s: string = "tab\there\n\"quoted\" \\";"#).unwrap();
+    /// match &program.0[0].value {
+    ///     FileElement::Constant { expr, .. } =>
+    ///         assert!(matches!(&expr.value, Expression::StringLiteral(s) if s == "tab\there\n\"quoted\" \\")),
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    StringLiteral(String),
+}
+impl std::fmt::Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expression::Call(ident, arguments) => write!(f, "{}", DisplayCall(ident, arguments)),
+            Expression::BinaryOp(lhs, op, rhs) => {
+                // Operands only need parentheses when they bind more loosely
+                // than this operator, or (since all operators are left
+                // associative) equally loosely on the right-hand side. An
+                // ordering comparison on the left of another would instead
+                // be read back as a chained comparison.
+                let precedence = op.value.precedence();
+                let needs_parens = |operand: &Expression, is_rhs: bool| match operand {
+                    Expression::BinaryOp(_, inner, _) =>
+                        inner.value.precedence() < precedence ||
+                        (is_rhs && inner.value.precedence() == precedence) ||
+                        (inner.value.is_ordering() && op.value.is_ordering()),
+                    Expression::ChainedComparison(..) => true,
+                    _ => false
+                };
+                for (operand, is_rhs) in [(&lhs.value, false), (&rhs.value, true)] {
+                    if is_rhs {
+                        write!(f, " {} ", op.value)?;
+                    }
+                    if needs_parens(operand, is_rhs) {
+                        write!(f, "({operand})")?;
+                    } else {
+                        write!(f, "{operand}")?;
+                    }
+                }
+                Ok(())
+            },
+            Expression::ChainedComparison(first, rest) => {
+                // Comparisons in operands would otherwise be read as part of
+                // the chain.
+                let write_operand = |f: &mut std::fmt::Formatter<'_>, operand: &Expression| match operand {
+                    Expression::BinaryOp(_, op, _) if op.value.precedence() == 1 => write!(f, "({operand})"),
+                    Expression::ChainedComparison(..) => write!(f, "({operand})"),
+                    _ => write!(f, "{operand}"),
+                };
+                write_operand(f, &first.value)?;
+                for (op, operand) in rest {
+                    write!(f, " {} ", op.value)?;
+                    write_operand(f, &operand.value)?;
+                }
+                Ok(())
+            },
+            // Since not applies to a single primary expression, binary
+            // expressions need to be wrapped in parentheses to negate them.
+            Expression::Not(operand) => match &operand.value {
+                Expression::BinaryOp(..) | Expression::ChainedComparison(..) => write!(f, "not ({})", operand.value),
+                _ => write!(f, "not {}", operand.value),
+            },
+            Expression::Tuple(elements) => {
+                let elements = elements.iter().map(|element| &element.value).collect::<Vec<_>>();
+                write!(f, "({})", DisplayList(&elements))
+            },
+            Expression::Register(elements) => {
+                let elements = elements.iter().map(|element| &element.value).collect::<Vec<_>>();
+                write!(f, "[{}]", DisplayList(&elements))
+            },
+            Expression::Index(ident, index) => write!(f, "{}[{}]", ident.value, index.value),
+            Expression::Measure(operand) => match &operand.value {
+                Expression::BinaryOp(..) | Expression::ChainedComparison(..) => write!(f, "measure ({})", operand.value),
+                _ => write!(f, "measure {}", operand.value),
+            },
+            Expression::Identifier(ident) => write!(f, "{ident}"),
+            Expression::QubitLiteral(idx) => write!(f, "%{idx}"),
+            // Number literals always include a decimal point so that they
+            // read as numbers rather than as integers.
+            Expression::NumberLiteral(num) if num.fract() == 0.0 => write!(f, "{num}.0"),
+            Expression::NumberLiteral(num) => write!(f, "{num}"),
+            Expression::BitLiteral(bit) => write!(f, "{bit}"),
+            Expression::StringLiteral(s) => {
+                // Escape anything that would otherwise end the literal early
+                // or be hard to read, matching the escapes that the parser
+                // understands.
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        c => write!(f, "{c}")?,
+                    }
+                }
+                write!(f, "\"")
+            },
+        }
+    }
+}
+
+/// Walks over the AST for a program. Each method recurses into the children
+/// of the node it's given by default, so that a pass can override only the
+/// nodes it cares about. Overriding methods can call the matching `walk_*`
+/// function to keep recursing.
+pub trait Visitor {
+    fn visit_program(&mut self, program: &Program) {
+        walk_program(self, program)
+    }
+
+    fn visit_file_element(&mut self, element: &Located<FileElement>) {
+        walk_file_element(self, element)
+    }
+
+    /// Visits a function body, or one of the blocks nested in a statement.
+    fn visit_body(&mut self, body: &[Located<Statement>]) {
+        walk_body(self, body)
+    }
+
+    fn visit_statement(&mut self, statement: &Located<Statement>) {
+        walk_statement(self, statement)
+    }
+
+    fn visit_expression(&mut self, expr: &Located<Expression>) {
+        walk_expression(self, expr)
+    }
+}
+
+pub fn walk_program<V: Visitor + ?Sized>(visitor: &mut V, program: &Program) {
+    for element in &program.0 {
+        visitor.visit_file_element(element);
+    }
+}
+
+pub fn walk_file_element<V: Visitor + ?Sized>(visitor: &mut V, element: &Located<FileElement>) {
+    match &element.value {
+        FileElement::Declaration(_) | FileElement::Include(_) => (),
+        FileElement::Definition { body, .. } => visitor.visit_body(body),
+        FileElement::Constant { expr, .. } | FileElement::Global { expr, .. } => visitor.visit_expression(expr),
+    }
+}
+
+/// Visits each statement in a body, in order.
+pub fn walk_body<V: Visitor + ?Sized>(visitor: &mut V, body: &[Located<Statement>]) {
+    for statement in body {
+        visitor.visit_statement(statement);
+    }
+}
+
+pub fn walk_statement<V: Visitor + ?Sized>(visitor: &mut V, statement: &Located<Statement>) {
+    match &statement.value {
+        Statement::VariableDeclaration(_, _, expr) => visitor.visit_expression(expr),
+        Statement::TupleDeclaration(_, _, expr) => visitor.visit_expression(expr),
+        Statement::Assignment(_, expr) => visitor.visit_expression(expr),
+        Statement::IndexAssignment(_, index, expr) => {
+            visitor.visit_expression(index);
+            visitor.visit_expression(expr);
+        },
+        Statement::Call(_, arguments) | Statement::Barrier(arguments) => {
+            for arg in arguments {
+                visitor.visit_expression(arg);
+            }
+        },
+        Statement::If { condition, true_body, false_body } => {
+            visitor.visit_expression(condition);
+            visitor.visit_body(true_body);
+            visitor.visit_body(false_body);
+        },
+        Statement::While { condition, body } => {
+            visitor.visit_expression(condition);
+            visitor.visit_body(body);
+        },
+        Statement::Return(Some(expr)) => visitor.visit_expression(expr),
+        Statement::Return(None) => (),
+    }
+}
+
+pub fn walk_expression<V: Visitor + ?Sized>(visitor: &mut V, expr: &Located<Expression>) {
+    match &expr.value {
+        Expression::Call(_, arguments) => {
+            for arg in arguments {
+                visitor.visit_expression(arg);
+            }
+        },
+        Expression::BinaryOp(lhs, _, rhs) => {
+            visitor.visit_expression(lhs);
+            visitor.visit_expression(rhs);
+        },
+        Expression::ChainedComparison(first, rest) => {
+            visitor.visit_expression(first);
+            for (_, operand) in rest {
+                visitor.visit_expression(operand);
+            }
+        },
+        Expression::Not(operand) => visitor.visit_expression(operand),
+        Expression::Tuple(elements) | Expression::Register(elements) => {
+            for element in elements {
+                visitor.visit_expression(element);
+            }
+        },
+        Expression::Measure(operand) => visitor.visit_expression(operand),
+        Expression::Index(_, index) => visitor.visit_expression(index),
+        Expression::Identifier(_) |
+        Expression::QubitLiteral(_) |
+        Expression::NumberLiteral(_) |
+        Expression::BitLiteral(_) |
+        Expression::StringLiteral(_) => (),
+    }
+}
+
+/// Walks over the AST for a program as with `Visitor`, but with mutable
+/// access to each node, so that a pass can rewrite nodes in place.
+pub trait VisitorMut {
+    fn visit_program_mut(&mut self, program: &mut Program) {
+        walk_program_mut(self, program)
+    }
+
+    fn visit_file_element_mut(&mut self, element: &mut Located<FileElement>) {
+        walk_file_element_mut(self, element)
+    }
+
+    fn visit_body_mut(&mut self, body: &mut [Located<Statement>]) {
+        walk_body_mut(self, body)
+    }
+
+    fn visit_statement_mut(&mut self, statement: &mut Located<Statement>) {
+        walk_statement_mut(self, statement)
+    }
+
+    fn visit_expression_mut(&mut self, expr: &mut Located<Expression>) {
+        walk_expression_mut(self, expr)
+    }
+}
+
+pub fn walk_program_mut<V: VisitorMut + ?Sized>(visitor: &mut V, program: &mut Program) {
+    for element in &mut program.0 {
+        visitor.visit_file_element_mut(element);
+    }
+}
+
+pub fn walk_file_element_mut<V: VisitorMut + ?Sized>(visitor: &mut V, element: &mut Located<FileElement>) {
+    match &mut element.value {
+        FileElement::Declaration(_) | FileElement::Include(_) => (),
+        FileElement::Definition { body, .. } => visitor.visit_body_mut(body),
+        FileElement::Constant { expr, .. } | FileElement::Global { expr, .. } => visitor.visit_expression_mut(expr),
+    }
+}
+
+/// Visits each statement in a body, in order.
+pub fn walk_body_mut<V: VisitorMut + ?Sized>(visitor: &mut V, body: &mut [Located<Statement>]) {
+    for statement in body {
+        visitor.visit_statement_mut(statement);
+    }
+}
+
+pub fn walk_statement_mut<V: VisitorMut + ?Sized>(visitor: &mut V, statement: &mut Located<Statement>) {
+    match &mut statement.value {
+        Statement::VariableDeclaration(_, _, expr) => visitor.visit_expression_mut(expr),
+        Statement::TupleDeclaration(_, _, expr) => visitor.visit_expression_mut(expr),
+        Statement::Assignment(_, expr) => visitor.visit_expression_mut(expr),
+        Statement::IndexAssignment(_, index, expr) => {
+            visitor.visit_expression_mut(index);
+            visitor.visit_expression_mut(expr);
+        },
+        Statement::Call(_, arguments) | Statement::Barrier(arguments) => {
+            for arg in arguments {
+                visitor.visit_expression_mut(arg);
+            }
+        },
+        Statement::If { condition, true_body, false_body } => {
+            visitor.visit_expression_mut(condition);
+            visitor.visit_body_mut(true_body);
+            visitor.visit_body_mut(false_body);
+        },
+        Statement::While { condition, body } => {
+            visitor.visit_expression_mut(condition);
+            visitor.visit_body_mut(body);
+        },
+        Statement::Return(Some(expr)) => visitor.visit_expression_mut(expr),
+        Statement::Return(None) => (),
+    }
+}
+
+pub fn walk_expression_mut<V: VisitorMut + ?Sized>(visitor: &mut V, expr: &mut Located<Expression>) {
+    match &mut expr.value {
+        Expression::Call(_, arguments) => {
+            for arg in arguments {
+                visitor.visit_expression_mut(arg);
+            }
+        },
+        Expression::BinaryOp(lhs, _, rhs) => {
+            visitor.visit_expression_mut(lhs);
+            visitor.visit_expression_mut(rhs);
+        },
+        Expression::ChainedComparison(first, rest) => {
+            visitor.visit_expression_mut(first);
+            for (_, operand) in rest {
+                visitor.visit_expression_mut(operand);
+            }
+        },
+        Expression::Not(operand) => visitor.visit_expression_mut(operand),
+        Expression::Tuple(elements) | Expression::Register(elements) => {
+            for element in elements {
+                visitor.visit_expression_mut(element);
+            }
+        },
+        Expression::Measure(operand) => visitor.visit_expression_mut(operand),
+        Expression::Index(_, index) => visitor.visit_expression_mut(index),
+        Expression::Identifier(_) |
+        Expression::QubitLiteral(_) |
+        Expression::NumberLiteral(_) |
+        Expression::BitLiteral(_) |
+        Expression::StringLiteral(_) => (),
+    }
 }
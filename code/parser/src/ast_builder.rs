@@ -1,40 +1,44 @@
 use crate::ast::{
-    ArgumentDeclaration, Expression, FileElement, Identifier, Located, Prototype,
-    Statement, Type, Program,
+    ArgumentDeclaration, BinaryOperator, Expression, FileElement, Identifier, Located,
+    Prototype, Statement, Type, Program,
 };
 use crate::error::{
-    rule_error_as_parse_error, wrong_rule_as_parse_error, QKaledioscopeError, Result,
+    combine_errors, rule_error_as_parse_error, wrong_rule_as_parse_error, QKaledioscopeError,
+    Result,
 };
 use crate::parser::{QKaledioscopeParser, Rule};
-use crate::util::ResultIter;
-use pest::iterators::Pair;
+use crate::graphviz;
+use crate::util::{read_source, PhaseTimer, ResultIter};
+use pest::iterators::{Pair, Pairs};
+use pest::prec_climber::{Assoc, Operator, PrecClimber};
 use pest::{Parser, Span};
-use std::path::PathBuf;
-use std::{vec, fs};
+use std::iter::Peekable;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::vec;
 use std::{fmt::Debug, str::FromStr};
 
 pub(crate) trait TryParse
 where
     Self: Sized + Debug,
 {
-    fn try_parse_raw(source: &str, pair: Pair<Rule>) -> Result<Self>;
-    fn try_parse(source: &str, pair: Pair<Rule>) -> Result<Located<Self>> {
+    fn try_parse_raw(source: &Arc<str>, pair: Pair<Rule>) -> Result<Self>;
+    fn try_parse(source: &Arc<str>, pair: Pair<Rule>) -> Result<Located<Self>> {
         let span = pair.as_span();
         let raw = Self::try_parse_raw(source, pair)?;
-        Ok(Located {
-            value: raw,
-            location: Some(
-                (span.start(), span.end())
-            ),
-        })
+        Ok(Located::new(raw, (span.start(), span.end())))
     }
 
     fn try_parse_many<'a, I: Iterator<Item = Pair<'a, Rule>>>(
-        source: &str,
+        source: &Arc<str>,
         span: Span,
         pairs: &mut I,
     ) -> Result<Vec<Located<Self>>> {
         match pairs
+            // Statements are followed by the semicolons that end them, which
+            // have nothing to add to the AST.
+            .filter(|pair| pair.as_rule() != Rule::Semicolon)
             .map(|pair| Self::try_parse(source, pair))
             .try_collect()
         {
@@ -50,25 +54,50 @@ where
 }
 
 impl TryParse for FileElement {
-    fn try_parse_raw(source: &str, pair: Pair<Rule>) -> Result<FileElement> {
+    fn try_parse_raw(source: &Arc<str>, pair: Pair<Rule>) -> Result<FileElement> {
         match pair.as_rule() {
             Rule::declaration => {
-                Prototype::try_parse(source, pair.into_inner().next().unwrap())
-                    .map(|ok| FileElement::Declaration(ok))
+                let mut inner = pair.into_inner().peekable();
+                let doc = parse_doc_comment(&mut inner);
+                Prototype::try_parse(source, inner.next().unwrap())
+                    .map(|mut ok| {
+                        ok.value.doc = doc;
+                        FileElement::Declaration(ok)
+                    })
             }
             Rule::definition => {
                 let span = pair.as_span();
-                let mut inner = pair.into_inner();
-                let proto = Prototype::try_parse(source, inner.next().unwrap())?;
+                let mut inner = pair.into_inner().peekable();
+                let doc = parse_doc_comment(&mut inner);
+                let mut proto = Prototype::try_parse(source, inner.next().unwrap())?;
+                proto.value.doc = doc;
                 let body = Statement::try_parse_many(source, span, &mut inner)?;
                 Ok(FileElement::Definition {
                     prototype: proto,
                     body,
                 })
             }
+            Rule::constant => {
+                let mut inner = pair.into_inner();
+                let name = Identifier::try_parse(source, inner.next().unwrap())?;
+                let type_sig = Type::try_parse(source, inner.next().unwrap())?;
+                let expr = Expression::try_parse(source, inner.next().unwrap())?;
+                Ok(FileElement::Constant { name, type_sig, expr })
+            }
+            Rule::global => {
+                let mut inner = pair.into_inner();
+                let name = Identifier::try_parse(source, inner.next().unwrap())?;
+                let type_sig = Type::try_parse(source, inner.next().unwrap())?;
+                let expr = Expression::try_parse(source, inner.next().unwrap())?;
+                Ok(FileElement::Global { name, type_sig, expr })
+            }
+            // Includes are only expanded when reading a program from a file,
+            // as otherwise there's nothing to resolve their paths against,
+            // so here they're kept as they are.
+            Rule::include => Ok(FileElement::Include(unescape_string(source, pair.into_inner().next().unwrap())?)),
             _ => Err(wrong_rule_as_parse_error(
                 source,
-                "Expected declaration or definition.",
+                "Expected declaration, definition, or constant.",
                 pair.as_span(),
                 vec![],
             )),
@@ -76,8 +105,51 @@ impl TryParse for FileElement {
     }
 }
 
+/// Decodes the escape sequences in a string literal, returning its contents
+/// without the surrounding quotes.
+fn unescape_string(source: &Arc<str>, pair: Pair<Rule>) -> Result<String> {
+    let literal = pair.as_str();
+    let mut contents = String::new();
+    let mut chars = literal[1..literal.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            contents.push(c);
+            continue;
+        }
+        // The grammar makes sure that every backslash is followed by
+        // another character.
+        contents.push(match chars.next().unwrap() {
+            'n' => '\n',
+            't' => '\t',
+            '"' => '"',
+            '\\' => '\\',
+            other => return Err(wrong_rule_as_parse_error(
+                source,
+                format!("Unknown escape sequence `\\{other}` in string literal").as_str(),
+                pair.as_span(),
+                vec![],
+            )),
+        });
+    }
+    Ok(contents)
+}
+
+/// Takes the doc comment from the start of a declaration or definition, if
+/// there is one, joining its lines together without their leading `##`.
+fn parse_doc_comment<'a, I: Iterator<Item = Pair<'a, Rule>>>(pairs: &mut Peekable<I>) -> Option<String> {
+    let doc_comment = pairs.next_if(|pair| pair.as_rule() == Rule::doc_comment)?;
+    Some(doc_comment
+        .into_inner()
+        .map(|line| {
+            let line = line.as_str().strip_prefix("##").unwrap();
+            line.strip_prefix(' ').unwrap_or(line).trim_end()
+        })
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
 impl TryParse for Prototype {
-    fn try_parse_raw(source: &str, pair: Pair<Rule>) -> Result<Prototype> {
+    fn try_parse_raw(source: &Arc<str>, pair: Pair<Rule>) -> Result<Prototype> {
         let span = pair.as_span();
         if !matches!(pair.as_rule(), Rule::prototype) {
             return Err(wrong_rule_as_parse_error(
@@ -115,6 +187,9 @@ impl TryParse for Prototype {
                     name: ident,
                     arguments,
                     return_type,
+                    // Doc comments come before the prototype itself, so
+                    // they're filled in by the enclosing file element.
+                    doc: None,
                 })
             }
         }
@@ -123,7 +198,7 @@ impl TryParse for Prototype {
 
 
 impl TryParse for ArgumentDeclaration {
-    fn try_parse_raw(source: &str, pair: Pair<Rule>) -> Result<Self> {
+    fn try_parse_raw(source: &Arc<str>, pair: Pair<Rule>) -> Result<Self> {
         match pair.as_rule() {
             Rule::arg_decl => {
                 let mut pairs = pair.into_inner();
@@ -142,11 +217,30 @@ impl TryParse for ArgumentDeclaration {
 }
 
 impl TryParse for Type {
-    fn try_parse_raw(source: &str, pair: Pair<Rule>) -> Result<Self> {
+    fn try_parse_raw(source: &Arc<str>, pair: Pair<Rule>) -> Result<Self> {
         match pair.as_rule() {
             Rule::qubit_type => Ok(Type::Qubit),
             Rule::number_type => Ok(Type::Number),
             Rule::bit_type => Ok(Type::Bit),
+            Rule::string_type => Ok(Type::String),
+            Rule::tuple_type => Ok(Type::Tuple(
+                pair.into_inner()
+                    .map(|pair| Type::try_parse_raw(source, pair))
+                    .collect::<Result<_>>()?
+            )),
+            Rule::register_type => {
+                let span = pair.as_span();
+                let size = pair.into_inner().next().unwrap().as_str();
+                let size = usize::from_str(size).map_err(|e| {
+                    wrong_rule_as_parse_error(
+                        source,
+                        format!("Could not convert `{}` to register size", size).as_str(),
+                        span,
+                        vec![QKaledioscopeError::ParseIntError(e)],
+                    )
+                })?;
+                Ok(Type::Register(size))
+            },
             _ => Err(wrong_rule_as_parse_error(
                 source,
                 "Expected a valid type",
@@ -158,7 +252,7 @@ impl TryParse for Type {
 }
 
 impl TryParse for Identifier {
-    fn try_parse_raw(source: &str, pair: Pair<Rule>) -> Result<Identifier> {
+    fn try_parse_raw(source: &Arc<str>, pair: Pair<Rule>) -> Result<Identifier> {
         match pair.as_rule() {
             Rule::Ident => Ok(Identifier(pair.as_str().to_string())),
             _ => Err(wrong_rule_as_parse_error(
@@ -172,14 +266,26 @@ impl TryParse for Identifier {
 }
 
 impl TryParse for Statement {
-    fn try_parse_raw(source: &str, pair: Pair<Rule>) -> Result<Self> {
+    fn try_parse_raw(source: &Arc<str>, pair: Pair<Rule>) -> Result<Self> {
         match pair.as_rule() {
             Rule::variable_declaration => {
                 let mut inner = pair.into_inner();
-                let ident = Identifier::try_parse(source, inner.next().unwrap())?;
-                let type_sig = Type::try_parse(source, inner.next().unwrap())?;
+                let target = inner.next().unwrap();
+                let type_sig = match inner.peek().map(|pair| pair.as_rule()) {
+                    Some(Rule::number_type | Rule::qubit_type | Rule::bit_type | Rule::register_type | Rule::string_type | Rule::tuple_type) =>
+                        Some(Type::try_parse(source, inner.next().unwrap())?),
+                    _ => None
+                };
                 let value = Expression::try_parse(source, inner.next().unwrap())?;
-                Ok(Statement::VariableDeclaration(ident, type_sig, value))
+                match target.as_rule() {
+                    Rule::tuple_pattern => {
+                        let idents = target.into_inner()
+                            .map(|pair| Identifier::try_parse(source, pair))
+                            .collect::<Result<_>>()?;
+                        Ok(Statement::TupleDeclaration(idents, type_sig, value))
+                    },
+                    _ => Ok(Statement::VariableDeclaration(Identifier::try_parse(source, target)?, type_sig, value))
+                }
             },
             Rule::assignment => {
                 let mut inner = pair.into_inner();
@@ -187,6 +293,13 @@ impl TryParse for Statement {
                 let value = Expression::try_parse(source, inner.next().unwrap())?;
                 Ok(Statement::Assignment(ident, value))
             },
+            Rule::index_assignment => {
+                let mut inner = pair.into_inner();
+                let ident = Identifier::try_parse(source, inner.next().unwrap())?;
+                let index = Expression::try_parse(source, inner.next().unwrap())?;
+                let value = Expression::try_parse(source, inner.next().unwrap())?;
+                Ok(Statement::IndexAssignment(ident, index, value))
+            },
             Rule::call_expr => {
                 let span = pair.as_span();
                 let mut inner = pair.into_inner();
@@ -229,8 +342,18 @@ impl TryParse for Statement {
             },
             Rule::return_stmt => {
                 let mut inner = pair.into_inner();
-                let value = Expression::try_parse(source, inner.next().unwrap())?;
+                inner.next(); // Skip over the return keyword itself.
+                let value = inner.next()
+                    .map(|pair| Expression::try_parse(source, pair))
+                    .transpose()?;
                 Ok(Statement::Return(value))
+            },
+            Rule::barrier_stmt => {
+                let span = pair.as_span();
+                let mut inner = pair.into_inner();
+                inner.next(); // Skip over the barrier keyword itself.
+                let qubits = Expression::try_parse_many(source, span, &mut inner)?;
+                Ok(Statement::Barrier(qubits))
             }
             _ => Err(wrong_rule_as_parse_error(
                 source,
@@ -242,8 +365,46 @@ impl TryParse for Statement {
     }
 }
 
+impl TryParse for BinaryOperator {
+    fn try_parse_raw(source: &Arc<str>, pair: Pair<Rule>) -> Result<Self> {
+        match pair.as_rule() {
+            Rule::Plus => Ok(BinaryOperator::Add),
+            Rule::Minus => Ok(BinaryOperator::Subtract),
+            Rule::Star => Ok(BinaryOperator::Multiply),
+            Rule::Slash => Ok(BinaryOperator::Divide),
+            Rule::DoubleEquals => Ok(BinaryOperator::Equal),
+            Rule::NotEquals => Ok(BinaryOperator::NotEqual),
+            Rule::Less => Ok(BinaryOperator::Less),
+            Rule::LessEquals => Ok(BinaryOperator::LessEqual),
+            Rule::Greater => Ok(BinaryOperator::Greater),
+            Rule::GreaterEquals => Ok(BinaryOperator::GreaterEqual),
+            _ => Err(wrong_rule_as_parse_error(
+                source,
+                "Expected a binary operator",
+                pair.as_span(),
+                vec![],
+            )),
+        }
+    }
+}
+
+/// Operators for binary expressions, listed from lowest to highest
+/// precedence.
+fn binary_op_climber() -> PrecClimber<Rule> {
+    PrecClimber::new(vec![
+        Operator::new(Rule::DoubleEquals, Assoc::Left)
+            | Operator::new(Rule::NotEquals, Assoc::Left)
+            | Operator::new(Rule::Less, Assoc::Left)
+            | Operator::new(Rule::LessEquals, Assoc::Left)
+            | Operator::new(Rule::Greater, Assoc::Left)
+            | Operator::new(Rule::GreaterEquals, Assoc::Left),
+        Operator::new(Rule::Plus, Assoc::Left) | Operator::new(Rule::Minus, Assoc::Left),
+        Operator::new(Rule::Star, Assoc::Left) | Operator::new(Rule::Slash, Assoc::Left),
+    ])
+}
+
 impl TryParse for Expression {
-    fn try_parse_raw(source: &str, pair: Pair<Rule>) -> Result<Self> {
+    fn try_parse_raw(source: &Arc<str>, pair: Pair<Rule>) -> Result<Self> {
         match pair.as_rule() {
             Rule::call_expr => {
                 let span = pair.as_span();
@@ -252,9 +413,69 @@ impl TryParse for Expression {
                 let arguments = Expression::try_parse_many(source, span, &mut inner)?;
                 Ok(Expression::Call(ident, arguments))
             },
+            Rule::binary_expr => {
+                // Each operand and operator comes through as a flat list of
+                // pairs, so we use pest's precedence climbing to nest them
+                // into a tree. Alongside each subtree, we keep track of
+                // whether it's an ordering comparison built by this climb
+                // (rather than one written in parentheses), as another
+                // ordering comparison after it extends it into a chain.
+                let (expr, _) = binary_op_climber().climb(
+                    pair.into_inner(),
+                    |pair| Ok((Expression::try_parse(source, pair)?, false)),
+                    |lhs, op, rhs| {
+                        let (lhs, lhs_is_ordering) = lhs?;
+                        let (rhs, _) = rhs?;
+                        let op = BinaryOperator::try_parse(source, op)?;
+                        let location = (lhs.location.0, rhs.location.1);
+                        let is_ordering = op.value.is_ordering();
+                        let expr = match lhs.value {
+                            Expression::BinaryOp(first, first_op, second) if lhs_is_ordering && is_ordering =>
+                                Expression::ChainedComparison(first, vec![(first_op, *second), (op, rhs)]),
+                            Expression::ChainedComparison(first, mut rest) if lhs_is_ordering && is_ordering => {
+                                rest.push((op, rhs));
+                                Expression::ChainedComparison(first, rest)
+                            },
+                            value => Expression::BinaryOp(Box::new(Located::new(value, lhs.location)), op, Box::new(rhs)),
+                        };
+                        Ok((Located::new(expr, location), is_ordering))
+                    },
+                )?;
+                Ok(expr.value)
+            },
             Rule::Ident => {
                 Ok(Expression::Identifier(Identifier::try_parse_raw(source, pair)?))
             },
+            Rule::not_expr => {
+                let mut inner = pair.into_inner();
+                // Skip past the operator itself to get to its operand.
+                inner.next();
+                let operand = Expression::try_parse(source, inner.next().unwrap())?;
+                Ok(Expression::Not(Box::new(operand)))
+            },
+            Rule::measure_expr => {
+                let mut inner = pair.into_inner();
+                // As with not, skip past the keyword to get to the operand.
+                inner.next();
+                let operand = Expression::try_parse(source, inner.next().unwrap())?;
+                Ok(Expression::Measure(Box::new(operand)))
+            },
+            Rule::tuple_expr => Ok(Expression::Tuple(
+                pair.into_inner()
+                    .map(|pair| Expression::try_parse(source, pair))
+                    .collect::<Result<_>>()?
+            )),
+            Rule::register_expr => Ok(Expression::Register(
+                pair.into_inner()
+                    .map(|pair| Expression::try_parse(source, pair))
+                    .collect::<Result<_>>()?
+            )),
+            Rule::index_expr => {
+                let mut inner = pair.into_inner();
+                let ident = Identifier::try_parse(source, inner.next().unwrap())?;
+                let index = Expression::try_parse(source, inner.next().unwrap())?;
+                Ok(Expression::Index(ident, Box::new(index)))
+            },
             Rule::TrueKeyword => Ok(Expression::BitLiteral(true)),
             Rule::FalseKeyword => Ok(Expression::BitLiteral(false)),
             Rule::number_literal => Ok({
@@ -270,10 +491,16 @@ impl TryParse for Expression {
                 })?;
                 Expression::NumberLiteral(val)
             }),
+            Rule::string_literal => Ok(Expression::StringLiteral(unescape_string(source, pair)?)),
             Rule::qubit_literal => Ok({
                 let span = pair.as_span();
                 let s = pair.as_str();
-                let idx = usize::from_str(&s[1..]).map_err(|e| {
+                let (digits, radix) = match &s[1..] {
+                    digits if digits.starts_with("0x") => (&digits[2..], 16),
+                    digits if digits.starts_with("0b") => (&digits[2..], 2),
+                    digits => (digits, 10),
+                };
+                let idx = usize::from_str_radix(digits, radix).map_err(|e| {
                     wrong_rule_as_parse_error(
                         source,
                         format!("Could not convert `{}` to qubit literal", s).as_str(),
@@ -293,36 +520,418 @@ impl TryParse for Expression {
     }
 }
 
-pub fn build_ast(source_file: PathBuf) -> Result<(Program, String)> {
-    // TODO: Refactor to use parse(). Can't do that quite yet since we still
-    //       need source as &str to reference the original program source code
-    //       for reporting as errors.
-    let fname = source_file.to_str().map(|s| s.to_string());
-    let source = fs::read_to_string(&source_file).map_err(|e| QKaledioscopeError::IOError {
-        cause: e,
-        subject: fname
-    })?;
-    let source_str = source.as_str();
-    let mut program = vec![];
-
-    let pairs = QKaledioscopeParser::parse(Rule::program, source_str)
-        .map_err(|e| rule_error_as_parse_error(source_str, e))?;
+/// Reads a program from a source file and builds its AST, returning both
+/// the AST and the source it was built from.
+///
+/// Any files that the program includes are read along with it. Everything
+/// written in the program itself keeps its place in the source, so that
+/// diagnostics point at the lines it was written on, while what's included
+/// comes first in the AST, just as the include itself does:
+///
+/// ```
+/// let (program, source) = parser::ast_builder::build_ast("examples/include.qk".into()).unwrap();
+/// let written = std::fs::read_to_string("examples/include.qk").unwrap();
+/// let qmain = program.0.last().unwrap();
+/// let (start, end) = qmain.location;
+/// assert!(written[start..end].starts_with("def qmain()"));
+/// assert_eq!(source[start..end], written[start..end]);
+/// assert_eq!(program.0[0].value.prototype().unwrap().value.name.value.0, "h");
+/// ```
+pub fn build_ast(source_file: PathBuf) -> Result<(Program, Arc<str>)> {
+    build_ast_timed(source_file, PhaseTimer::default())
+}
+
+/// Builds the AST for a program as with `build_ast`, timing how long
+/// parsing and building the AST each take.
+pub(crate) fn build_ast_timed(source_file: PathBuf, timer: PhaseTimer) -> Result<(Program, Arc<str>)> {
+    let source = read_source(&source_file)?;
+    // Programs read from stdin can still include other files, but can't be
+    // included themselves, so there's nothing to add to the stack for them.
+    let mut including = fs::canonicalize(&source_file).into_iter().collect();
+    let mut expanded = String::with_capacity(source.len());
+    let order = expand_includes(&source_file, &source, &mut including, &mut expanded)?;
+    let source: Arc<str> = expanded.into();
+    let mut program = parse_timed(&source, timer)?;
+
+    // Put the elements of each included file back where its include was, so
+    // that constants and globals are evaluated in the order they're written.
+    let position = |offset: usize| order.iter()
+        .position(|&(start, end)| (start..end).contains(&offset))
+        .unwrap_or(order.len());
+    program.0.sort_by_key(|element| (position(element.location.0), element.location.0));
+
+    Ok((program, source))
+}
+
+/// Reads a program without expanding its includes, such that it can be
+/// printed back out as it was written.
+pub(crate) fn build_unexpanded_ast(source_file: PathBuf) -> Result<(Program, Arc<str>)> {
+    let source: Arc<str> = read_source(&source_file)?.into();
+    let program = parse_shared(&source)?;
+    Ok((program, source))
+}
+
+/// Appends a program to `expanded` along with the contents of each file
+/// that it includes, resolved relative to the including file, so that the
+/// whole thing can then be parsed as if it had all been written in one
+/// file. Definitions that end up with the same name are reported as
+/// duplicates when the program is checked, as with any other program.
+///
+/// Each include is blanked out where it was written, and what it includes
+/// is added after the end of the including file, so that spans in the
+/// including file still line up with its own source. Returns the ranges of
+/// `expanded` in the order that they would have come in if each include had
+/// been replaced with the file it names instead.
+///
+/// `including` holds the canonical path of each file that's currently
+/// being expanded, so that a file which ends up including itself can be
+/// reported rather than expanded forever.
+fn expand_includes(source_file: &Path, source: &str, including: &mut Vec<PathBuf>, expanded: &mut String) -> Result<Vec<(usize, usize)>> {
+    let offset = expanded.len();
+    // If the program doesn't parse, leave it as is so that its syntax
+    // errors are reported when the expanded program is parsed.
+    let includes = match QKaledioscopeParser::parse(Rule::program, source) {
+        Ok(pairs) => pairs.filter(|pair| pair.as_rule() == Rule::include).collect::<Vec<_>>(),
+        Err(_) => vec![],
+    };
+
+    let mut last_end = 0;
+    for include in &includes {
+        let span = include.as_span();
+        expanded.push_str(&source[last_end..span.start()]);
+        expanded.push_str(&mask_outside(span.as_str(), 0, 0));
+        last_end = span.end();
+    }
+    expanded.push_str(&source[last_end..]);
+
+    let base = source_file.parent().unwrap_or_else(|| Path::new(""));
+    let mut order = vec![];
+    let mut last_end = 0;
+    for include in includes {
+        let span = include.as_span();
+        let path = unescape_string(&Arc::from(source), include.into_inner().next().unwrap())?;
+        let resolved = base.join(&path);
+        let canonical = fs::canonicalize(&resolved).map_err(|e| QKaledioscopeError::IOError {
+            cause: e,
+            subject: resolved.to_str().map(|s| s.to_string()),
+        })?;
+        if including.contains(&canonical) {
+            return Err(QKaledioscopeError::CyclicIncludeError {
+                path,
+                src: source.into(),
+                span: (span.start(), span.end() - span.start()).into(),
+            });
+        }
+
+        // Make sure that a comment on the last line of whatever came before
+        // doesn't swallow the start of the included file.
+        if !expanded.ends_with('\n') {
+            expanded.push('\n');
+        }
+        order.push((offset + last_end, offset + span.start()));
+        including.push(canonical);
+        order.extend(expand_includes(&resolved, &read_source(&resolved)?, including, expanded)?);
+        including.pop();
+        last_end = span.end();
+    }
+    order.push((offset + last_end, offset + source.len()));
+
+    Ok(order)
+}
+
+/// Parses a program from source and builds its AST, reporting all syntax
+/// errors found along the way. This is the main entry point for using this
+/// crate as a library, as it doesn't need to touch the filesystem.
+///
+/// ```
+/// let program = parser::parse_program("
+///     extern h(q : qubit);
+///     def qmain() {
+///         h(%0);
+///     }
+/// ").unwrap();
+/// assert_eq!(program.0.len(), 2);
+/// assert_eq!(program.0[1].value.prototype().unwrap().value.name.value.0, "qmain");
+/// ```
+///
+/// Qubit literals can be written in hexadecimal or binary, as well as in
+/// decimal:
+///
+/// ```
+/// use parser::ast::{Expression, FileElement, Statement};
+/// let program = parser::parse_program("def qmain() { h(%0xFF); }").unwrap();
+/// let body = match &program.0[0].value {
+///     FileElement::Definition { body, .. } => body,
+///     _ => unreachable!(),
+/// };
+/// let arguments = match &body[0].value {
+///     Statement::Call(_, arguments) => arguments,
+///     _ => unreachable!(),
+/// };
+/// assert!(matches!(arguments[0].value, Expression::QubitLiteral(255)));
+/// ```
+///
+/// Lines starting with `##` are only documentation when they come right
+/// before `def` or `extern`, and not before names that start the same way:
+///
+/// ```
+/// use parser::ast::FileElement;
+/// let program = parser::parse_program("
+///     def qmain() {
+///         ## Just a comment.
+///         default_state(%0);
+///         ## Also just a comment.
+///         externally_measured(%0);
+///     }
+/// ").unwrap();
+/// assert!(matches!(&program.0[0].value, FileElement::Definition { body, .. } if body.len() == 2));
+/// ```
+///
+/// Files with nothing in them but whitespace and comments aren't programs:
+///
+/// ```
+/// use parser::QKaledioscopeError;
+/// let result = parser::parse_program("# Nothing to see here.\n");
+/// assert!(matches!(result, Err(QKaledioscopeError::EmptyProgramError)));
+/// ```
+///
+/// Every error found in a program refers to the same copy of its source,
+/// rather than to a copy of its own:
+///
+/// ```
+/// use std::sync::Arc;
+/// use parser::QKaledioscopeError;
+/// let (src, causes) = match parser::parse_program("def f() { x( }\ndef g() { y( }\n") {
+///     Err(QKaledioscopeError::ParseError { src, causes, .. }) => (src, causes),
+///     _ => unreachable!(),
+/// };
+/// assert!(!causes.is_empty());
+/// for cause in causes {
+///     match cause {
+///         QKaledioscopeError::ParseError { src: cause_src, .. } => assert!(Arc::ptr_eq(&src, &cause_src)),
+///         _ => unreachable!(),
+///     }
+/// }
+/// ```
+pub fn parse_program(source: &str) -> Result<Program> {
+    parse_shared(&source.into())
+}
+
+/// Parses a program as with `parse_program`, but from source that's already
+/// shared, so that any errors can refer to it without making copies.
+pub(crate) fn parse_shared(source: &Arc<str>) -> Result<Program> {
+    parse_timed(source, PhaseTimer::default())
+}
+
+fn parse_timed(source: &Arc<str>, timer: PhaseTimer) -> Result<Program> {
+    let elements = try_parse_file_elements(source, timer)?;
+    if elements.is_empty() {
+        return Err(QKaledioscopeError::EmptyProgramError);
+    }
+    Ok(Program::new(elements))
+}
+
+/// Parses each file element in a program, continuing past any elements that
+/// fail to parse so that all of their errors can be reported together.
+fn try_parse_file_elements(source: &Arc<str>, timer: PhaseTimer) -> Result<Vec<Located<FileElement>>> {
+    let mut elements = vec![];
+    let mut errors = vec![];
+
+    match timer.time("parse", || QKaledioscopeParser::parse(Rule::program, source)) {
+        Ok(pairs) => timer.time("build AST", || collect_file_elements(source, pairs, &mut elements, &mut errors)),
+        Err(err) => {
+            // pest stops at the first syntax error, so to find any others we
+            // split the program up at each top-level declaration or
+            // definition, and parse each piece separately. Rather than
+            // slicing out each piece, we blank out everything else so that
+            // spans still line up with the original source.
+            for (start, end) in top_level_chunks(source) {
+                let masked = mask_outside(source, start, end);
+                match QKaledioscopeParser::parse(Rule::program, masked.as_str()) {
+                    Ok(pairs) => collect_file_elements(source, pairs, &mut elements, &mut errors),
+                    Err(err) => errors.push(rule_error_as_parse_error(source, err)),
+                }
+            }
+            // Splitting things up should always find at least the error that
+            // we started with, but just in case, make sure we report it.
+            if errors.is_empty() {
+                errors.push(rule_error_as_parse_error(source, err));
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(elements)
+    } else {
+        Err(combine_errors(errors))
+    }
+}
+
+fn collect_file_elements(
+    source: &Arc<str>,
+    pairs: Pairs<Rule>,
+    elements: &mut Vec<Located<FileElement>>,
+    errors: &mut Vec<QKaledioscopeError>,
+) {
     for pair in pairs {
         // Ignore the end of the file, but try to parse everything else.
         if !matches!(pair.as_rule(), Rule::EOI) {
-            // TODO: write util fn to try parse multiple.
-            let element = FileElement::try_parse(source_str, pair)?;
-            program.push(element);
+            match FileElement::try_parse(source, pair) {
+                Ok(element) => elements.push(element),
+                Err(err) => errors.push(err),
+            }
         }
     }
+}
+
+/// Finds the byte range of each top-level declaration or definition in a
+/// program, based on which lines start with `def` or `extern`.
+fn top_level_chunks(source: &str) -> Vec<(usize, usize)> {
+    let mut starts = vec![0];
+    let mut offset = 0;
+    for line in source.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if offset > 0 && (trimmed.starts_with("def ") || trimmed.starts_with("extern ")) {
+            starts.push(offset);
+        }
+        offset += line.len();
+    }
+    let ends = starts.iter().skip(1).copied().chain(std::iter::once(source.len()));
+    starts.iter().copied().zip(ends).collect()
+}
+
+/// Replaces everything outside of a given byte range with spaces, keeping
+/// newlines and the length in bytes of the source the same.
+pub(crate) fn mask_outside(source: &str, start: usize, end: usize) -> String {
+    let mut masked = String::with_capacity(source.len());
+    for (idx, c) in source.char_indices() {
+        if (start..end).contains(&idx) || c == '\n' {
+            masked.push(c);
+        } else {
+            masked.extend(std::iter::repeat(' ').take(c.len_utf8()));
+        }
+    }
+    masked
+}
+
+
+/// The formats that an abstract syntax tree can be printed in.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AstFormat {
+    /// Serializes the AST and its source as JSON, with the line and column
+    /// of each node.
+    Json,
+    /// Renders the AST as a Graphviz DOT graph.
+    Dot,
+}
+
+/// Finds the line and column of a byte offset into some source, counting
+/// both from one.
+fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+/// Walks a serialized AST, adding a `span` with the line and column of
+/// the start and end of each node next to its `location`, which only
+/// gives byte offsets.
+fn add_line_cols(source: &str, value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            let location = fields.get("location").and_then(|location| {
+                let start = location.get(0)?.as_u64()? as usize;
+                let end = location.get(1)?.as_u64()? as usize;
+                Some((start, end))
+            });
+            if let Some((start, end)) = location {
+                let position = |offset| {
+                    let (line, col) = line_col(source, offset);
+                    serde_json::json!({ "line": line, "col": col })
+                };
+                fields.insert("span".to_string(), serde_json::json!({
+                    "start": position(start),
+                    "end": position(end),
+                }));
+            }
+            for field in fields.values_mut() {
+                add_line_cols(source, field);
+            }
+        },
+        serde_json::Value::Array(items) => {
+            for item in items {
+                add_line_cols(source, item);
+            }
+        },
+        _ => ()
+    }
+}
 
-    Ok((Program(program), source))
+/// Serializes the AST for a program as JSON, giving each node the line and
+/// column it starts and ends at in the source the program was parsed from,
+/// in addition to its byte offsets.
+///
+/// ```
+/// let source = "extern h(q : qubit);\ndef qmain() { h(%0); }";
+/// let program = parser::parse_program(source).unwrap();
+/// let json = parser::ast_builder::ast_to_json(&program, source).unwrap();
+/// assert_eq!(json[1]["span"]["start"]["line"], 2);
+/// assert_eq!(json[1]["span"]["start"]["col"], 1);
+/// assert_eq!(json[1]["span"]["end"]["col"], 23);
+/// ```
+pub fn ast_to_json(program: &Program, source: &str) -> Result<serde_json::Value> {
+    let mut json = serde_json::to_value(program).map_err(QKaledioscopeError::JsonError)?;
+    add_line_cols(source, &mut json);
+    Ok(json)
 }
 
+/// Loads the AST for a program back in from JSON, as produced by
+/// [`ast_to_json`]. Spans with line and column numbers are ignored, since
+/// they can be worked out again from byte offsets.
+///
+/// ```
+/// use parser::ast_builder::{ast_from_json, ast_to_json};
+/// let source = "extern h(q : qubit);\ndef qmain() { h(%0); }";
+/// let program = parser::parse_program(source).unwrap();
+/// let json = ast_to_json(&program, source).unwrap();
+/// let loaded = ast_from_json(json.clone()).unwrap();
+/// assert_eq!(loaded, program);
+/// assert_eq!(ast_to_json(&loaded, source).unwrap(), json);
+/// ```
+///
+/// Every kind of node survives the round trip, along with where it came
+/// from in the source:
+///
+/// ```
+/// use parser::ast_builder::{ast_from_json, ast_to_json};
+/// let source = std::fs::read_to_string("examples/registers.qk").unwrap();
+/// let program = parser::parse_program(&source).unwrap();
+/// let loaded = ast_from_json(ast_to_json(&program, &source).unwrap()).unwrap();
+/// assert_eq!(loaded, program);
+/// ```
+pub fn ast_from_json(json: serde_json::Value) -> Result<Program> {
+    serde_json::from_value(json).map_err(QKaledioscopeError::JsonError)
+}
+
+/// Reads a program's AST from a file written by the `build-ast` command,
+/// returning both the AST and the source it was built from.
+pub fn load_ast(ast_file: PathBuf) -> Result<(Program, Arc<str>)> {
+    let contents = read_source(&ast_file)?;
+    let (json, source): (serde_json::Value, String) =
+        serde_json::from_str(&contents).map_err(QKaledioscopeError::JsonError)?;
+    Ok((ast_from_json(json)?, source.into()))
+}
 
-pub fn run_build_cmd(source_file: PathBuf) -> miette::Result<()> {
-    let program = build_ast(source_file)?;
-    println!("{}", serde_json::to_string(&program).map_err(|e| QKaledioscopeError::JsonError(e))?);
+pub fn run_build_cmd(source_file: PathBuf, format: AstFormat) -> miette::Result<()> {
+    let (program, source) = build_ast(source_file)?;
+    match format {
+        AstFormat::Json => {
+            let json = ast_to_json(&program, &source)?;
+            println!("{}", serde_json::json!([json, &*source]));
+        },
+        AstFormat::Dot => print!("{}", graphviz::program_to_dot(&program)),
+    };
 
     Ok(())
 }
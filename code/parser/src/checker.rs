@@ -0,0 +1,629 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use miette::SourceSpan;
+
+use crate::{ast::{Program, FileElement, Prototype, Statement, Expression, Located, Identifier, Type, BinaryOperator, Visitor, walk_file_element, walk_statement, walk_expression}, error::{QKaledioscopeError, Result, literal_help}, ast_builder::build_ast, interpreter::builtin_constant, scope};
+
+// NB: Unlike the errors raised while building the AST or running a program,
+//     the checks in this module are best-effort: they look for mistakes that
+//     are likely, but not certain, to be bugs, and report them as warnings.
+
+/// Runs each of the static checks that a program must pass before it can be
+/// interpreted or compiled, returning the first error found.
+pub fn check_program(source: &Arc<str>, program: &Program) -> Result<()> {
+    check_constants(source, program)?;
+    check_call_arities(source, program)?;
+    check_return_types(source, program)?;
+    check_aliased_qubits(source, program)?;
+    Ok(())
+}
+
+/// Looks through a program for likely mistakes, returning a warning for
+/// each one found.
+///
+/// Statements after a `return` in the same block are never run:
+///
+/// ```
+/// use std::sync::Arc;
+/// use parser::QKaledioscopeError;
+///
+/// let source: Arc<str> = "
+///     def qmain() -> number {
+///         return 1.0;
+///         print_n(2.0);
+///     }
+/// ".into();
+/// let program = parser::parse_program(&source).unwrap();
+/// let warnings = parser::checker::check_warnings(&source, &program);
+/// let unreachable_start = source.find("print_n").unwrap();
+/// assert!(matches!(
+///     warnings.as_slice(),
+///     [QKaledioscopeError::UnreachableCodeWarning { span, .. }] if span.offset() == unreachable_start
+/// ));
+/// ```
+///
+/// Qubits that `qmain` measures and then passes to a gate without resetting
+/// them first are most likely being reused by mistake:
+///
+/// ```
+/// use std::sync::Arc;
+/// use parser::QKaledioscopeError;
+///
+/// let source: Arc<str> = "
+///     def qmain() {
+///         h(%0);
+///         var b: bit = measure %0;
+///         h(%1);
+///         x(%0);
+///     }
+/// ".into();
+/// let program = parser::parse_program(&source).unwrap();
+/// let warnings = parser::checker::check_warnings(&source, &program);
+/// assert!(matches!(
+///     warnings.as_slice(),
+///     [QKaledioscopeError::UseAfterMeasurementWarning { index: 0, measurement_span, use_span, .. }]
+///         if measurement_span.offset() == source.find("measure").unwrap() && use_span.offset() == source.find("x(%0)").unwrap()
+/// ));
+/// ```
+///
+/// Naming an argument or variable after a built-in constant hides the
+/// built-in:
+///
+/// ```
+/// use std::sync::Arc;
+/// use parser::QKaledioscopeError;
+///
+/// let source: Arc<str> = "
+///     def qmain() -> number {
+///         var pi: number = 3.0;
+///         return pi;
+///     }
+/// ".into();
+/// let program = parser::parse_program(&source).unwrap();
+/// let warnings = parser::checker::check_warnings(&source, &program);
+/// assert!(matches!(
+///     warnings.as_slice(),
+///     [QKaledioscopeError::ShadowedConstantWarning { name, span, .. }] if name == "pi" && span.offset() == source.find("pi").unwrap()
+/// ));
+/// ```
+pub fn check_warnings(source: &Arc<str>, program: &Program) -> Vec<QKaledioscopeError> {
+    let mut warnings = vec![];
+    ShadowedConstants { source, warnings: &mut warnings }.visit_program(program);
+    for element in &program.0 {
+        if let FileElement::Definition { prototype, body } = &element.value {
+            if prototype.value.name.value.0 == "qmain" {
+                UseAfterMeasurement::new(source).check_body(body, &mut warnings);
+            }
+            check_unreachable_code(source, body, &mut warnings);
+        }
+    }
+    warnings
+}
+
+/// Warns about each constant, argument, or variable that has the same name
+/// as one of the built-in constants, such as `pi`.
+struct ShadowedConstants<'a> {
+    source: &'a Arc<str>,
+    warnings: &'a mut Vec<QKaledioscopeError>,
+}
+
+impl ShadowedConstants<'_> {
+    fn check_name(&mut self, name: &Located<Identifier>) {
+        if builtin_constant(&name.value.0).is_some() {
+            self.warnings.push(QKaledioscopeError::ShadowedConstantWarning {
+                name: name.value.0.clone(),
+                src: self.source.clone(),
+                span: name.as_sourcespan(),
+            });
+        }
+    }
+}
+
+impl Visitor for ShadowedConstants<'_> {
+    fn visit_file_element(&mut self, element: &Located<FileElement>) {
+        match &element.value {
+            FileElement::Constant { name, .. } | FileElement::Global { name, .. } => self.check_name(name),
+            FileElement::Definition { prototype, .. } => {
+                for argument in &prototype.value.arguments {
+                    self.check_name(&argument.value.0);
+                }
+            },
+            FileElement::Declaration(_) | FileElement::Include(_) => (),
+        }
+        walk_file_element(self, element)
+    }
+
+    fn visit_statement(&mut self, statement: &Located<Statement>) {
+        match &statement.value {
+            Statement::VariableDeclaration(ident, ..) => self.check_name(ident),
+            Statement::TupleDeclaration(idents, ..) => {
+                for ident in idents {
+                    self.check_name(ident);
+                }
+            },
+            _ => ()
+        }
+        walk_statement(self, statement)
+    }
+}
+
+/// Warns about any statements that follow a return in the same block, as
+/// well as in each block nested inside of it.
+fn check_unreachable_code(source: &Arc<str>, body: &[Located<Statement>], warnings: &mut Vec<QKaledioscopeError>) {
+    let returned = body.iter().position(|statement| matches!(statement.value, Statement::Return(_)));
+    if let Some(idx) = returned {
+        if let (Some(first), Some(last)) = (body.get(idx + 1), body.last()) {
+            let (start, end) = (first.location.0, last.location.1);
+            warnings.push(QKaledioscopeError::UnreachableCodeWarning {
+                src: source.clone(),
+                return_span: body[idx].as_sourcespan(),
+                span: (start, end - start).into(),
+            });
+        }
+    }
+    for statement in body {
+        match &statement.value {
+            Statement::If { true_body, false_body, .. } => {
+                check_unreachable_code(source, true_body, warnings);
+                check_unreachable_code(source, false_body, warnings);
+            },
+            Statement::While { body, .. } => check_unreachable_code(source, body, warnings),
+            _ => ()
+        }
+    }
+}
+
+/// Checks that every constant and global in a program can be evaluated
+/// before the program starts.
+pub fn check_constants(source: &Arc<str>, program: &Program) -> Result<()> {
+    for element in &program.0 {
+        match &element.value {
+            FileElement::Constant { name, expr, .. } => check_constant(source, &name.value, expr)?,
+            FileElement::Global { name, expr, .. } => check_global(source, &name.value, expr)?,
+            FileElement::Declaration(_) | FileElement::Definition { .. } | FileElement::Include(_) => (),
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the initial value of a global can be evaluated before the
+/// program starts, under the same rules as for constants.
+pub fn check_global(source: &Arc<str>, name: &Identifier, expr: &Located<Expression>) -> Result<()> {
+    check_constant(source, name, expr).map_err(|err| match err {
+        QKaledioscopeError::ImpureConstantError { name, src, span, kind } =>
+            QKaledioscopeError::ImpureGlobalError { name, src, span, kind },
+        err => err,
+    })
+}
+
+/// Checks that the expression for a constant can be evaluated before the
+/// program starts. Function calls and qubit literals would need a running
+/// simulator (and could have side effects, as with `m`), so neither is
+/// allowed anywhere in a constant's expression, and nor are measurements.
+pub fn check_constant(source: &Arc<str>, name: &Identifier, expr: &Located<Expression>) -> Result<()> {
+    let impure = |kind: &str| Err(QKaledioscopeError::ImpureConstantError {
+        name: name.0.clone(),
+        src: source.clone(),
+        span: expr.as_sourcespan(),
+        kind: kind.to_string(),
+    });
+    match &expr.value {
+        Expression::Call(..) => impure("function call"),
+        Expression::QubitLiteral(_) => impure("qubit"),
+        Expression::Measure(_) => impure("measurement"),
+        Expression::BinaryOp(lhs, _, rhs) => {
+            check_constant(source, name, lhs)?;
+            check_constant(source, name, rhs)
+        },
+        Expression::ChainedComparison(first, rest) => {
+            check_constant(source, name, first)?;
+            rest.iter().try_for_each(|(_, operand)| check_constant(source, name, operand))
+        },
+        Expression::Not(operand) | Expression::Index(_, operand) => check_constant(source, name, operand),
+        Expression::Tuple(elements) | Expression::Register(elements) => elements
+            .iter()
+            .try_for_each(|element| check_constant(source, name, element)),
+        Expression::Identifier(_) | Expression::NumberLiteral(_) |
+        Expression::BitLiteral(_) | Expression::StringLiteral(_) => Ok(())
+    }
+}
+
+/// Checks that every call to a function declared or defined in a program
+/// passes the number of arguments given in that function's prototype.
+///
+/// Calls to functions that aren't declared anywhere in the program (e.g.:
+/// built-in functions without an `extern`) are left to be checked when the
+/// program runs.
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// let check = |call: &str| {
+///     let source: Arc<str> = format!("
+///         def pair(a: number, b: number) {{}}
+///         def qmain() {{
+///             {call};
+///         }}
+///     ").into();
+///     let program = parser::parse_program(&source).unwrap();
+///     parser::checker::check_call_arities(&source, &program).unwrap_err().to_string()
+/// };
+/// assert_eq!(check("pair(1.0, 2.0, 3.0)"), "Function pair takes 2 argument(s), but was called with 3.");
+/// assert_eq!(check("pair(1.0)"), "Function pair takes 2 argument(s), but was called with 1.");
+/// ```
+pub fn check_call_arities(source: &Arc<str>, program: &Program) -> Result<()> {
+    let arities = program.0
+        .iter()
+        .filter_map(|element| {
+            let prototype = &element.value.prototype()?.value;
+            Some((prototype.name.value.clone(), prototype.arguments.len()))
+        })
+        .collect::<HashMap<_, _>>();
+    let check_call = |name: &Identifier, n_args: usize, span: SourceSpan| -> Result<()> {
+        match arities.get(name) {
+            Some(&expected) if expected != n_args => Err(QKaledioscopeError::ArityError {
+                name: name.0.clone(),
+                expected,
+                actual: n_args,
+                src: source.clone(),
+                span,
+            }),
+            _ => Ok(())
+        }
+    };
+    for element in &program.0 {
+        if let FileElement::Definition { body, .. } = &element.value {
+            check_calls_in_body(body, &check_call)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_calls_in_body(body: &[Located<Statement>], check_call: &impl Fn(&Identifier, usize, SourceSpan) -> Result<()>) -> Result<()> {
+    for statement in body {
+        match &statement.value {
+            Statement::VariableDeclaration(_, _, expr) |
+            Statement::TupleDeclaration(_, _, expr) |
+            Statement::Assignment(_, expr) |
+            Statement::Return(Some(expr)) => check_calls_in_expr(expr, check_call)?,
+            Statement::Return(None) => (),
+            Statement::IndexAssignment(_, index, expr) => {
+                check_calls_in_expr(index, check_call)?;
+                check_calls_in_expr(expr, check_call)?;
+            },
+            Statement::Barrier(qubits) => {
+                for qubit in qubits {
+                    check_calls_in_expr(qubit, check_call)?;
+                }
+            },
+            Statement::Call(ident, arguments) => {
+                check_call(&ident.value, arguments.len(), statement.as_sourcespan())?;
+                for argument in arguments {
+                    check_calls_in_expr(argument, check_call)?;
+                }
+            },
+            Statement::If { condition, true_body, false_body } => {
+                check_calls_in_expr(condition, check_call)?;
+                check_calls_in_body(true_body, check_call)?;
+                check_calls_in_body(false_body, check_call)?;
+            },
+            Statement::While { condition, body } => {
+                check_calls_in_expr(condition, check_call)?;
+                check_calls_in_body(body, check_call)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+fn check_calls_in_expr(expr: &Located<Expression>, check_call: &impl Fn(&Identifier, usize, SourceSpan) -> Result<()>) -> Result<()> {
+    match &expr.value {
+        Expression::Call(ident, arguments) => {
+            check_call(&ident.value, arguments.len(), expr.as_sourcespan())?;
+            for argument in arguments {
+                check_calls_in_expr(argument, check_call)?;
+            }
+            Ok(())
+        },
+        Expression::BinaryOp(lhs, _, rhs) => {
+            check_calls_in_expr(lhs, check_call)?;
+            check_calls_in_expr(rhs, check_call)
+        },
+        Expression::ChainedComparison(first, rest) => {
+            check_calls_in_expr(first, check_call)?;
+            rest.iter().try_for_each(|(_, operand)| check_calls_in_expr(operand, check_call))
+        },
+        Expression::Not(operand) | Expression::Measure(operand) |
+        Expression::Index(_, operand) => check_calls_in_expr(operand, check_call),
+        Expression::Tuple(elements) | Expression::Register(elements) => elements
+            .iter()
+            .try_for_each(|element| check_calls_in_expr(element, check_call)),
+        Expression::Identifier(_) | Expression::QubitLiteral(_) |
+        Expression::NumberLiteral(_) | Expression::BitLiteral(_) |
+        Expression::StringLiteral(_) => Ok(())
+    }
+}
+
+/// Checks that each function's return statements agree with its declared
+/// return type, and that functions with a return type can't finish without
+/// returning. Only returned values whose types are known without running
+/// the program (e.g.: literals and comparisons) are checked against the
+/// return type; the interpreter checks the rest as they're returned.
+///
+/// ```
+/// use std::sync::Arc;
+/// use parser::QKaledioscopeError;
+///
+/// let source: Arc<str> = "
+///     def f() -> number {
+///         return true;
+///     }
+/// ".into();
+/// let program = parser::parse_program(&source).unwrap();
+/// let result = parser::checker::check_return_types(&source, &program);
+/// assert!(matches!(result, Err(QKaledioscopeError::TypeError { expected, actual, .. }) if expected == "number" && actual == "bit"));
+/// ```
+pub fn check_return_types(source: &Arc<str>, program: &Program) -> Result<()> {
+    for element in &program.0 {
+        if let FileElement::Definition { prototype, body } = &element.value {
+            check_returns_in_body(source, prototype, body)?;
+            if let Some(return_type) = &prototype.value.return_type {
+                if !always_returns(body) {
+                    return Err(QKaledioscopeError::MissingReturnError {
+                        name: prototype.value.name.value.0.clone(),
+                        src: source.clone(),
+                        span: return_type.as_sourcespan(),
+                        return_type: return_type.value.to_string(),
+                    });
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_returns_in_body(source: &Arc<str>, prototype: &Located<Prototype>, body: &[Located<Statement>]) -> Result<()> {
+    for statement in body {
+        match &statement.value {
+            Statement::Return(expr) => match (expr, &prototype.value.return_type) {
+                (Some(expr), None) => return Err(QKaledioscopeError::TypeError {
+                    expected: Type::Unit.to_string(),
+                    actual: known_type(&expr.value).map_or("a value".to_string(), |ty| ty.to_string()),
+                    src: source.clone(),
+                    expr_span: expr.as_sourcespan(),
+                    type_span: Some(prototype.as_sourcespan()),
+                    help: None,
+                }),
+                (None, Some(return_type)) => return Err(QKaledioscopeError::TypeError {
+                    expected: return_type.value.to_string(),
+                    actual: Type::Unit.to_string(),
+                    src: source.clone(),
+                    expr_span: statement.as_sourcespan(),
+                    type_span: Some(return_type.as_sourcespan()),
+                    help: None,
+                }),
+                (Some(expr), Some(return_type)) => match known_type(&expr.value) {
+                    Some(actual) if actual != return_type.value => return Err(QKaledioscopeError::TypeError {
+                        expected: return_type.value.to_string(),
+                        actual: actual.to_string(),
+                        src: source.clone(),
+                        expr_span: expr.as_sourcespan(),
+                        type_span: Some(return_type.as_sourcespan()),
+                        help: literal_help(&return_type.value, &actual),
+                    }),
+                    _ => ()
+                },
+                (None, None) => ()
+            },
+            Statement::If { true_body, false_body, .. } => {
+                check_returns_in_body(source, prototype, true_body)?;
+                check_returns_in_body(source, prototype, false_body)?;
+            },
+            Statement::While { body, .. } => check_returns_in_body(source, prototype, body)?,
+            _ => ()
+        }
+    }
+    Ok(())
+}
+
+/// Whether every path through a body ends in a return. Loops aren't
+/// counted, since their bodies might never run.
+fn always_returns(body: &[Located<Statement>]) -> bool {
+    body.iter().any(|statement| match &statement.value {
+        Statement::Return(_) => true,
+        Statement::If { true_body, false_body, .. } => always_returns(true_body) && always_returns(false_body),
+        _ => false
+    })
+}
+
+/// Works out the type of an expression without running it, if that can be
+/// done without knowing the types of any variables or functions.
+fn known_type(expr: &Expression) -> Option<Type> {
+    match expr {
+        Expression::NumberLiteral(_) => Some(Type::Number),
+        Expression::BitLiteral(_) => Some(Type::Bit),
+        Expression::StringLiteral(_) => Some(Type::String),
+        Expression::QubitLiteral(_) => Some(Type::Qubit),
+        Expression::Not(_) | Expression::Measure(_) | Expression::ChainedComparison(..) |
+        Expression::Index(..) => Some(Type::Bit),
+        Expression::BinaryOp(_, op, _) => match op.value {
+            BinaryOperator::Add | BinaryOperator::Subtract |
+            BinaryOperator::Multiply | BinaryOperator::Divide => Some(Type::Number),
+            BinaryOperator::Equal | BinaryOperator::NotEqual |
+            BinaryOperator::Less | BinaryOperator::LessEqual |
+            BinaryOperator::Greater | BinaryOperator::GreaterEqual => Some(Type::Bit),
+        },
+        Expression::Tuple(elements) => elements
+            .iter()
+            .map(|element| known_type(&element.value))
+            .collect::<Option<_>>()
+            .map(Type::Tuple),
+        Expression::Register(elements) => Some(Type::Register(elements.len())),
+        Expression::Identifier(_) | Expression::Call(..) => None,
+    }
+}
+
+/// Checks that no call to `cnot` or `controlled` passes the same qubit
+/// literal as both its control and its target.
+///
+/// Qubits passed through variables or arguments can't be told apart until
+/// the program runs, so those are left for the interpreter to catch.
+pub fn check_aliased_qubits(source: &Arc<str>, program: &Program) -> Result<()> {
+    let mut checker = AliasedQubits { source, error: None };
+    checker.visit_program(program);
+    match checker.error {
+        Some(error) => Err(error),
+        None => Ok(())
+    }
+}
+
+/// Remembers the first call found whose control and target are the same
+/// qubit literal.
+struct AliasedQubits<'a> {
+    source: &'a Arc<str>,
+    error: Option<QKaledioscopeError>,
+}
+
+impl AliasedQubits<'_> {
+    fn check_call(&mut self, ident: &Located<Identifier>, arguments: &[Located<Expression>]) {
+        let (control, target) = match (ident.value.0.as_str(), arguments) {
+            ("cnot", [control, target]) | ("controlled", [_, control, target]) => (control, target),
+            _ => return
+        };
+        if let (Expression::QubitLiteral(c), Expression::QubitLiteral(t)) = (&control.value, &target.value) {
+            if c == t && self.error.is_none() {
+                self.error = Some(QKaledioscopeError::QubitAliasingError {
+                    name: ident.value.0.clone(),
+                    index: *c,
+                    src: self.source.clone(),
+                    control_span: control.as_sourcespan(),
+                    target_span: target.as_sourcespan(),
+                });
+            }
+        }
+    }
+}
+
+impl Visitor for AliasedQubits<'_> {
+    fn visit_statement(&mut self, statement: &Located<Statement>) {
+        if let Statement::Call(ident, arguments) = &statement.value {
+            self.check_call(ident, arguments);
+        }
+        walk_statement(self, statement)
+    }
+
+    fn visit_expression(&mut self, expr: &Located<Expression>) {
+        if let Expression::Call(ident, arguments) = &expr.value {
+            self.check_call(ident, arguments);
+        }
+        walk_expression(self, expr)
+    }
+}
+
+/// Prints each warning found by `check_warnings` to stderr.
+pub fn report_warnings(source: &Arc<str>, program: &Program) {
+    for warning in check_warnings(source, program) {
+        eprintln!("{:?}", miette::Report::new(warning));
+    }
+}
+
+/// Tracks which qubit literals have been measured (and not yet reset) while
+/// stepping through straight-line code, so that we can warn if one of them
+/// is passed to a gate afterwards.
+struct UseAfterMeasurement<'a> {
+    source: &'a Arc<str>,
+    measured: HashMap<usize, SourceSpan>,
+}
+
+impl<'a> UseAfterMeasurement<'a> {
+    fn new(source: &'a Arc<str>) -> Self {
+        UseAfterMeasurement { source, measured: HashMap::new() }
+    }
+
+    fn check_body(&mut self, body: &[Located<Statement>], warnings: &mut Vec<QKaledioscopeError>) {
+        for statement in body {
+            match &statement.value {
+                Statement::VariableDeclaration(_, _, expr) |
+                Statement::TupleDeclaration(_, _, expr) |
+                Statement::Assignment(_, expr) |
+                Statement::Return(Some(expr)) => self.check_expr(expr, warnings),
+                Statement::IndexAssignment(_, index, expr) => {
+                    self.check_expr(index, warnings);
+                    self.check_expr(expr, warnings);
+                },
+                Statement::Return(None) | Statement::Barrier(_) => (),
+                Statement::Call(ident, arguments) =>
+                    self.check_call(&ident.value.0, arguments, statement.as_sourcespan(), warnings),
+                // Whether a qubit has been measured by the time we get past
+                // a branch or loop depends on what happens at runtime, so we
+                // stop here rather than risk a false positive.
+                Statement::If { .. } | Statement::While { .. } => break,
+            }
+        }
+    }
+
+    fn check_expr(&mut self, expr: &Located<Expression>, warnings: &mut Vec<QKaledioscopeError>) {
+        match &expr.value {
+            Expression::Call(ident, arguments) =>
+                self.check_call(&ident.value.0, arguments, expr.as_sourcespan(), warnings),
+            Expression::BinaryOp(lhs, _, rhs) => {
+                self.check_expr(lhs, warnings);
+                self.check_expr(rhs, warnings);
+            },
+            Expression::ChainedComparison(first, rest) => {
+                self.check_expr(first, warnings);
+                for (_, operand) in rest {
+                    self.check_expr(operand, warnings);
+                }
+            },
+            Expression::Not(operand) | Expression::Index(_, operand) => self.check_expr(operand, warnings),
+            Expression::Tuple(elements) | Expression::Register(elements) => {
+                for element in elements {
+                    self.check_expr(element, warnings);
+                }
+            },
+            // Measuring is the same as calling m, as far as later uses of
+            // the qubit are concerned.
+            Expression::Measure(operand) =>
+                self.check_call("m", std::slice::from_ref(operand.as_ref()), expr.as_sourcespan(), warnings),
+            Expression::Identifier(_) | Expression::QubitLiteral(_) |
+            Expression::NumberLiteral(_) | Expression::BitLiteral(_) |
+            Expression::StringLiteral(_) => ()
+        }
+    }
+
+    fn check_call(&mut self, name: &str, arguments: &[Located<Expression>], call_span: SourceSpan, warnings: &mut Vec<QKaledioscopeError>) {
+        // Arguments are evaluated before the call itself happens.
+        for argument in arguments {
+            self.check_expr(argument, warnings);
+        }
+
+        let qubits = arguments.iter().filter_map(|argument| match &argument.value {
+            Expression::QubitLiteral(idx) => Some(*idx),
+            _ => None
+        });
+        for idx in qubits {
+            match name {
+                "m" => { self.measured.insert(idx, call_span); },
+                "reset" => { self.measured.remove(&idx); },
+                _ => if let Some(measurement_span) = self.measured.remove(&idx) {
+                    warnings.push(QKaledioscopeError::UseAfterMeasurementWarning {
+                        index: idx,
+                        src: self.source.clone(),
+                        measurement_span,
+                        use_span: call_span,
+                    });
+                }
+            }
+        }
+    }
+}
+
+pub fn run_check_cmd(source_file: PathBuf) -> miette::Result<()> {
+    let (program, source) = build_ast(source_file)?;
+    scope::scope_check(&source, &program)?;
+    check_program(&source, &program)?;
+    report_warnings(&source, &program);
+
+    Ok(())
+}
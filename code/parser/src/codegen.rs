@@ -1,10 +1,12 @@
-use std::{collections::HashMap, path::PathBuf, hash::Hash, fs::File};
+use std::{collections::HashMap, path::{Path, PathBuf}, hash::Hash, fs::File, sync::Arc};
 
 use either::Either;
-use inkwell::{context::Context, builder::Builder, passes::PassManager, values::{FunctionValue, PointerValue, BasicValue, IntValue, FloatValue, StructValue, BasicMetadataValueEnum, BasicValueEnum, InstructionOpcode, InstructionValue}, module::Module, types::{StructType, BasicTypeEnum, FunctionType, FloatType, VoidType, IntType, BasicMetadataTypeEnum, BasicType, PointerType}, basic_block::BasicBlock};
-use miette::IntoDiagnostic;
+use inkwell::{context::Context, builder::Builder, passes::PassManager, values::{FunctionValue, PointerValue, BasicValue, IntValue, FloatValue, StructValue, BasicMetadataValueEnum, BasicValueEnum, InstructionOpcode, InstructionValue}, module::Module, types::{AnyTypeEnum, ArrayType, StructType, BasicTypeEnum, FunctionType, FloatType, VoidType, IntType, BasicMetadataTypeEnum, BasicType, PointerType}, basic_block::BasicBlock};
+use inkwell::{FloatPredicate, IntPredicate, OptimizationLevel, module::Linkage};
+use inkwell::targets::{CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine};
+use miette::{IntoDiagnostic, SourceSpan};
 
-use crate::{ast::{FileElement, Program, Prototype, Located, Type, ArgumentDeclaration, Statement, Expression, Identifier}, error::{Result, QKaledioscopeError}, ast_builder::build_ast};
+use crate::{ast::{FileElement, Program, Prototype, Located, Type, ArgumentDeclaration, Statement, Expression, Identifier, BinaryOperator}, error::{Result, QKaledioscopeError, did_you_mean, literal_help}, ast_builder::{build_ast_timed, parse_shared}, checker, folding, scope, graphviz::DotWriter, interpreter::{builtin_constant, register_index, BUILTIN_CONSTANTS, FunctionTable, InterpreterValue, RunOptions}, util::PhaseTimer};
 
 // NB: We largely follow the inkwell::kaledioscope tutorial at
 //     https://github.com/TheDan64/inkwell/blob/master/examples/kaleidoscope/main.rs
@@ -38,6 +40,78 @@ impl<'ctx> ReturnType<'ctx> for StructType<'ctx> {
         self.fn_type(param_types, is_var_args)
     }
 }
+impl<'ctx> ReturnType<'ctx> for ArrayType<'ctx> {
+    fn func_type(&self, param_types: &[BasicMetadataTypeEnum<'ctx>], is_var_args: bool) -> FunctionType<'ctx> {
+        self.fn_type(param_types, is_var_args)
+    }
+}
+
+/// How qubit literals like `%0` are lowered into QIR.
+///
+/// ```
+/// use parser::codegen::{compile, CompileOptions, QubitAllocation};
+///
+/// let dir = std::env::temp_dir().join("qk_qubit_allocation");
+/// std::fs::create_dir_all(&dir).unwrap();
+/// let source_file = dir.join("flip.qk");
+/// std::fs::write(&source_file, "
+///     extern x(q : qubit);
+///     def qmain() {
+///         x(%0);
+///         x(%1);
+///     }
+/// ").unwrap();
+/// let compile_with = |qubit_allocation: QubitAllocation| {
+///     let output = dir.join(format!("{qubit_allocation:?}.ll"));
+///     compile(source_file.clone(), &CompileOptions {
+///         qubit_allocation,
+///         output: Some(output.clone()),
+///         ..CompileOptions::default()
+///     }).unwrap();
+///     std::fs::read_to_string(output).unwrap()
+/// };
+///
+/// // NB: Casting 0 folds into a null pointer, so only %1 shows up as a cast.
+/// let ir = compile_with(QubitAllocation::Static);
+/// assert!(ir.contains("inttoptr (i64 1 to %Qubit*)"));
+/// assert!(!ir.contains("__quantum__rt__qubit_allocate"));
+///
+/// let ir = compile_with(QubitAllocation::Dynamic);
+/// assert!(ir.contains("call %Qubit* @__quantum__rt__qubit_allocate()"));
+/// assert!(ir.contains("call void @__quantum__rt__qubit_release(%Qubit*"));
+/// assert!(!ir.contains("inttoptr"));
+/// ```
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QubitAllocation {
+    /// Casts each literal's index directly into a `%Qubit*`, following the
+    /// static qubit convention.
+    Static,
+    /// Allocates a qubit from the runtime for each literal at the start of
+    /// `qmain`, and releases them all before `qmain` returns.
+    Dynamic,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    pub qubit_allocation: QubitAllocation,
+    /// If set, prints the control-flow graph of each compiled function as
+    /// Graphviz DOT instead of printing QIR.
+    pub emit_cfg: bool,
+    /// Where to write compiled output, or `None` to print it to stdout. QIR
+    /// is written as text if the path ends in `.ll`, as LLVM bitcode if it
+    /// ends in `.bc`, and as an object file if it ends in `.o`.
+    pub output: Option<PathBuf>,
+    /// If set, folds constant expressions in the program before compiling
+    /// it.
+    pub fold_constants: bool,
+    /// If set, reports how long each phase of compilation takes to stderr.
+    pub time: bool,
+}
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions { qubit_allocation: QubitAllocation::Static, emit_cfg: false, output: None, fold_constants: false, time: false }
+    }
+}
 
 pub struct Compiler<'a, 'ctx> {
     pub context: &'ctx Context,
@@ -45,11 +119,21 @@ pub struct Compiler<'a, 'ctx> {
     pub fpm: &'a PassManager<FunctionValue<'ctx>>,
     pub module: &'a Module<'ctx>,
     pub program: &'a Program,
-    pub source: &'a str,
+    pub source: &'a Arc<str>,
+    pub options: &'a CompileOptions,
 
     prototypes: HashMap<String, Located<Prototype>>,
     variables: HashMap<String, PointerValue<'ctx>>,
-    fn_value_opt: Option<FunctionValue<'ctx>>
+    // Constants are evaluated before compiling, and their values inlined
+    // wherever they're used.
+    constants: HashMap<String, BasicValueEnum<'ctx>>,
+    // Globals are kept in LLVM globals, which every function loads from and
+    // stores to directly.
+    globals: HashMap<String, PointerValue<'ctx>>,
+    fn_value_opt: Option<FunctionValue<'ctx>>,
+    // Only used with dynamic qubit allocation, where this points to a global
+    // array holding the handle allocated for each qubit literal.
+    qubit_handles: Option<(PointerValue<'ctx>, usize)>,
 }
 
 impl<'a, 'ctx> Compiler<'a, 'ctx> {
@@ -59,12 +143,32 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
         self.module.get_function(name)
     }
 
+    /// Gets a pointer to the variable or global with the given name, as seen
+    /// from the function being compiled.
+    fn variable_ptr(&self, name: &str) -> Option<PointerValue<'ctx>> {
+        self.variables.get(name).or_else(|| self.globals.get(name)).copied()
+    }
+
+    /// Lists the names that `variable_ptr` can find.
+    fn variable_names(&self) -> impl Iterator<Item = &str> {
+        self.variables.keys().chain(self.globals.keys()).map(String::as_str)
+    }
+
     /// Returns the `FunctionValue` representing the function being compiled.
     #[inline]
     fn fn_value(&self) -> FunctionValue<'ctx> {
         self.fn_value_opt.unwrap()
     }
 
+    /// Branches unconditionally to `target` from the current block, unless
+    /// that block has already been terminated (e.g. by a `return`).
+    fn build_branch_if_unterminated(&self, target: BasicBlock<'ctx>) {
+        let current = self.builder.get_insert_block().unwrap();
+        if current.get_terminator().is_none() {
+            self.builder.build_unconditional_branch(target);
+        }
+    }
+
     fn get_or_define_struct(
         &self,
         name: &str,
@@ -80,6 +184,158 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
         self.get_or_define_struct("Qubit").ptr_type(inkwell::AddressSpace::Generic)
     }
 
+    /// Strings are lowered to pointers to null-terminated arrays of `i8`.
+    fn string_type(&self) -> PointerType<'ctx> {
+        self.context.i8_type().ptr_type(inkwell::AddressSpace::Generic)
+    }
+
+    /// Registers are lowered to arrays of `i1`, one for each bit.
+    fn register_type(&self, size: usize) -> ArrayType<'ctx> {
+        self.context.bool_type().array_type(size as u32)
+    }
+
+    /// Adds a string to the module as a private global constant, returning
+    /// a pointer to its first character.
+    fn build_string_constant(&self, s: &str) -> PointerValue<'ctx> {
+        let value = self.context.const_string(s.as_bytes(), true);
+        let global = self.module.add_global(value.get_type(), None, "str");
+        global.set_linkage(Linkage::Private);
+        global.set_constant(true);
+        global.set_initializer(&value);
+        global.as_pointer_value().const_cast(self.string_type())
+    }
+
+    fn get_or_declare_function(&self, name: &str, fn_type: FunctionType<'ctx>) -> FunctionValue<'ctx> {
+        self.get_function(name)
+            .unwrap_or_else(|| self.module.add_function(name, fn_type, None))
+    }
+
+    /// Measures a qubit with the QIS measurement intrinsic, then compares
+    /// the `%Result*` that comes back against `One` to get a bit.
+    fn build_measurement(&self, qubit: PointerValue<'ctx>) -> IntValue<'ctx> {
+        let result_type = self.get_or_define_struct("Result").ptr_type(inkwell::AddressSpace::Generic);
+        let measure = self.get_or_declare_function(
+            "__quantum__qis__m__body",
+            result_type.fn_type(&[self.qubit_type().into()], false)
+        );
+        let get_one = self.get_or_declare_function(
+            "__quantum__rt__result_get_one",
+            result_type.fn_type(&[], false)
+        );
+        let equal = self.get_or_declare_function(
+            "__quantum__rt__result_equal",
+            self.context.bool_type().fn_type(&[result_type.into(), result_type.into()], false)
+        );
+        let result = self.builder.build_call(measure, &[qubit.into()], "result")
+            .try_as_basic_value()
+            .left()
+            .unwrap();
+        let one = self.builder.build_call(get_one, &[], "one")
+            .try_as_basic_value()
+            .left()
+            .unwrap();
+        self.builder.build_call(equal, &[result.into(), one.into()], "measured")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_int_value()
+    }
+
+    /// Emits a call to the QIR runtime to record a value returned from
+    /// qmain as output, so that whatever runs the compiled program can
+    /// report it. Bits and numbers are recorded directly, while registers
+    /// are recorded as an array followed by each of their bits; other values
+    /// aren't recorded.
+    fn build_output_recording(&self, value: BasicValueEnum<'ctx>) {
+        let value = match value {
+            BasicValueEnum::ArrayValue(register) => {
+                let len = register.get_type().len();
+                self.build_record_call(
+                    "__quantum__rt__array_record_output",
+                    self.context.i64_type().const_int(len.into(), false).into()
+                );
+                for idx in 0..len {
+                    let bit = self.builder.build_extract_value(register, idx, "bit").unwrap();
+                    self.build_output_recording(bit);
+                }
+                return;
+            },
+            BasicValueEnum::IntValue(_) | BasicValueEnum::FloatValue(_) => value,
+            _ => return,
+        };
+        let name = match value {
+            BasicValueEnum::IntValue(_) => "__quantum__rt__bool_record_output",
+            _ => "__quantum__rt__double_record_output",
+        };
+        self.build_record_call(name, value);
+    }
+
+    fn build_record_call(&self, name: &str, value: BasicValueEnum<'ctx>) {
+        // Each recording function takes a label for the value as well,
+        // which we leave null.
+        let record = self.get_or_declare_function(
+            name,
+            self.context.void_type().fn_type(&[value.get_type().into(), self.string_type().into()], false)
+        );
+        self.builder.build_call(record, &[value.into(), self.string_type().const_null().into()], "");
+    }
+
+    /// Returns the prototype of the function being compiled.
+    fn current_prototype(&self) -> &Located<Prototype> {
+        let name = self.fn_value().get_name().to_str().unwrap().to_string();
+        &self.prototypes[&name]
+    }
+
+    fn is_compiling_qmain(&self) -> bool {
+        self.fn_value().get_name().to_bytes() == b"qmain"
+    }
+
+    /// Gets a pointer to the slot in the global qubit handle array that holds
+    /// the handle for a given qubit literal.
+    fn qubit_handle_ptr(&self, handles: PointerValue<'ctx>, idx: usize) -> PointerValue<'ctx> {
+        let i64_type = self.context.i64_type();
+        unsafe {
+            self.builder.build_in_bounds_gep(
+                handles,
+                &[i64_type.const_zero(), i64_type.const_int(idx as u64, false)],
+                "qubit_handle_ptr"
+            )
+        }
+    }
+
+    /// Emits a call to `__quantum__rt__qubit_allocate` for each qubit literal
+    /// used by the program, storing the handles in a global array.
+    fn build_qubit_allocations(&self) {
+        if let Some((handles, n_qubits)) = self.qubit_handles {
+            let allocate = self.get_or_declare_function(
+                "__quantum__rt__qubit_allocate",
+                self.qubit_type().fn_type(&[], false)
+            );
+            for idx in 0..n_qubits {
+                let handle = self.builder.build_call(allocate, &[], "qubit")
+                    .try_as_basic_value()
+                    .left()
+                    .unwrap();
+                self.builder.build_store(self.qubit_handle_ptr(handles, idx), handle);
+            }
+        }
+    }
+
+    /// Emits a call to `__quantum__rt__qubit_release` for each qubit handle
+    /// allocated by `build_qubit_allocations`.
+    fn build_qubit_releases(&self) {
+        if let Some((handles, n_qubits)) = self.qubit_handles {
+            let release = self.get_or_declare_function(
+                "__quantum__rt__qubit_release",
+                self.context.void_type().fn_type(&[self.qubit_type().into()], false)
+            );
+            for idx in 0..n_qubits {
+                let handle = self.builder.build_load(self.qubit_handle_ptr(handles, idx), "qubit");
+                self.builder.build_call(release, &[handle.into()], "");
+            }
+        }
+    }
+
     /// Creates a new stack allocation instruction in the entry block of the function.
     fn create_entry_block_alloca(&self, name: &str, ty: &Type) -> PointerValue<'ctx> {
         let builder = self.context.create_builder();
@@ -95,11 +351,13 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
             Type::Bit => builder.build_alloca(self.context.bool_type(), name),
             Type::Number => builder.build_alloca(self.context.f64_type(), name),
             Type::Qubit => builder.build_alloca(self.qubit_type(), name),
+            Type::String => builder.build_alloca(self.string_type(), name),
+            Type::Register(size) => builder.build_alloca(self.register_type(*size), name),
+            Type::Gate | Type::Unit => unreachable!("Gate and unit types can't be written in source."),
+            Type::Tuple(_) => unreachable!("Tuples are rejected before any variables are allocated."),
         }
     }
 
-    // TODO: Change Result to crate::error::Result by adding appropriate
-    //       cases to QKaledioscopeError.
     fn compile_prototype(&mut self, proto: &Located<Prototype>) -> Result<FunctionValue<'ctx>> {
         // Start by registering the prototype in our map for later error
         // handling.
@@ -113,10 +371,14 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
         // Proceed to build the LLVM definition.
         let ret_type: Box<dyn ReturnType> = match &proto.return_type {
             None => Box::new(self.context.void_type()),
-            Some(Located { value, location }) => match value {
+            Some(return_type) => match &return_type.value {
                 Type::Bit => Box::new(self.context.bool_type()),
                 Type::Number => Box::new(self.context.f64_type()),
                 Type::Qubit => Box::new(self.qubit_type()),
+                Type::String => Box::new(self.string_type()),
+                Type::Register(size) => Box::new(self.register_type(*size)),
+                Type::Gate | Type::Unit => unreachable!("Gate and unit types can't be written in source."),
+                Type::Tuple(_) => return Err(self.unsupported_tuple_error(return_type.as_sourcespan())),
             }
         };
 
@@ -125,15 +387,21 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
             .iter()
             .map(|arg| {
                 let ArgumentDeclaration(ident, type_sig) = &arg.value;
-                (
+                Ok((
                     ident.value.0.clone(),
-                    match type_sig.value {
+                    match &type_sig.value {
                         Type::Bit => BasicMetadataTypeEnum::IntType(self.context.bool_type()),
                         Type::Number => BasicMetadataTypeEnum::FloatType(self.context.f64_type()),
                         Type::Qubit => BasicMetadataTypeEnum::PointerType(self.qubit_type()),
+                        Type::String => BasicMetadataTypeEnum::PointerType(self.string_type()),
+                        Type::Register(size) => BasicMetadataTypeEnum::ArrayType(self.register_type(*size)),
+                        Type::Gate | Type::Unit => unreachable!("Gate and unit types can't be written in source."),
+                        Type::Tuple(_) => return Err(self.unsupported_tuple_error(type_sig.as_sourcespan())),
                     }
-                )
+                ))
             })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
             .unzip();
 
         let fn_type = ret_type.func_type(arg_types.as_slice(), false);
@@ -146,10 +414,17 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
         Ok(fn_val)
     }
 
+    fn unsupported_tuple_error(&self, span: SourceSpan) -> QKaledioscopeError {
+        QKaledioscopeError::UnsupportedTupleError {
+            src: self.source.clone(),
+            span,
+        }
+    }
+
     fn compile_call(&mut self, ident: &Located<Identifier>, arg_exprs: &[Located<Expression>]) -> Result<Either<BasicValueEnum<'ctx>, InstructionValue<'ctx>>> {
         let callee = self.get_function(&ident.value.0).ok_or(QKaledioscopeError::UndefinedFunctionError {
             name: ident.value.0.to_string(),
-            src: self.source.to_string(),
+            src: self.source.clone(),
             span: ident.as_sourcespan()
         })?;
         let args = arg_exprs.iter()
@@ -164,21 +439,32 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
     fn compile_expr(&mut self, expr: &Located<Expression>) -> Result<BasicValueEnum<'ctx>> {
         Ok(match &expr.value {
             Expression::BitLiteral(b) => self.context.bool_type().const_int(if *b { 1 } else { 0 }, false).into(),
+            Expression::StringLiteral(s) => self.build_string_constant(s).into(),
             Expression::NumberLiteral(n) => self.context.f64_type().const_float(*n).into(),
-            Expression::QubitLiteral(q) =>
-                self.builder.build_cast(
+            Expression::QubitLiteral(q) => match self.qubit_handles {
+                Some((handles, _)) =>
+                    self.builder.build_load(self.qubit_handle_ptr(handles, *q), "qubit"),
+                None => self.builder.build_cast(
                     InstructionOpcode::IntToPtr, 
                     self.context.i64_type().const_int((*q).try_into().unwrap(), false),
                     self.qubit_type(),
                     "" // TODO: Not clear from inkwel or llvm docs what this argument does.
                 ),
-            Expression::Identifier(ident) => {
-                let alloca = self.variables.get(&ident.0).ok_or(QKaledioscopeError::UndefinedVariableError {
-                    name: ident.0.clone(),
-                    src: self.source.to_string(),
-                    span: expr.as_sourcespan()
-                })?;
-                self.builder.build_load(*alloca, "")
+            },
+            Expression::Identifier(ident) => match (self.variable_ptr(&ident.0), self.constants.get(&ident.0)) {
+                (Some(ptr), _) => self.builder.build_load(ptr, ""),
+                (None, Some(constant)) => *constant,
+                (None, None) => match builtin_constant(&ident.0) {
+                    Some(value) => self.context.f64_type().const_float(value).into(),
+                    None => return Err(QKaledioscopeError::UndefinedVariableError {
+                        name: ident.0.clone(),
+                        src: self.source.clone(),
+                        span: expr.as_sourcespan(),
+                        help: did_you_mean(&ident.0, self.variable_names()
+                            .chain(self.constants.keys().map(String::as_str))
+                            .chain(BUILTIN_CONSTANTS.iter().map(|(name, _)| *name))),
+                    }),
+                },
             },
             Expression::Call(ident, arg_exprs) => {
                 let call = self.compile_call(&ident, arg_exprs);
@@ -192,12 +478,203 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
                         let prototype = self.prototypes.get(&ident.value.0.to_string()).unwrap();
                         QKaledioscopeError::VoidCallError {
                             name: ident.value.0.clone(),
-                            src: self.source.to_string(),
+                            src: self.source.clone(),
                             call_span: expr.as_sourcespan(),
-                            decl_span: prototype.as_sourcespan(),
+                            decl_span: Some(prototype.as_sourcespan()),
                         }
                     })?
-            }
+            },
+            Expression::BinaryOp(lhs, op, rhs) => self.compile_binary_op(lhs, op, rhs)?,
+            // As with the interpreter, every operand is evaluated once, and
+            // the comparisons between them are and-ed together.
+            Expression::ChainedComparison(first, rest) => {
+                let mut lhs = self.compile_number_operand(first)?;
+                let mut holds = self.context.bool_type().const_int(1, false);
+                for (op, operand) in rest {
+                    let rhs = self.compile_number_operand(operand)?;
+                    let predicate = match op.value {
+                        BinaryOperator::Less => FloatPredicate::OLT,
+                        BinaryOperator::LessEqual => FloatPredicate::OLE,
+                        BinaryOperator::Greater => FloatPredicate::OGT,
+                        BinaryOperator::GreaterEqual => FloatPredicate::OGE,
+                        _ => unreachable!("Only ordering comparisons are chained.")
+                    };
+                    let comparison = self.builder.build_float_compare(predicate, lhs, rhs, "cmptmp");
+                    holds = self.builder.build_and(holds, comparison, "andtmp");
+                    lhs = rhs;
+                }
+                holds.into()
+            },
+            Expression::Not(operand) => {
+                let operand = self.compile_condition(operand)?;
+                self.builder.build_not(operand, "nottmp").into()
+            },
+            Expression::Tuple(_) => return Err(self.unsupported_tuple_error(expr.as_sourcespan())),
+            Expression::Register(elements) => {
+                let mut register = self.register_type(elements.len()).get_undef();
+                for (idx, element) in elements.iter().enumerate() {
+                    let bit = self.compile_condition(element)?;
+                    register = self.builder.build_insert_value(register, bit, idx as u32, "register")
+                        .unwrap()
+                        .into_array_value();
+                }
+                register.into()
+            },
+            Expression::Index(ident, index) => {
+                let register = match self.variable_ptr(&ident.value.0) {
+                    Some(alloca) => alloca,
+                    // Constants are inlined as values rather than kept in
+                    // memory, so we copy them onto the stack to index them.
+                    None => {
+                        let value = self.compile_expr(&Located::new(Expression::Identifier(ident.value.clone()), ident.location))?;
+                        let alloca = self.create_entry_block_alloca(&ident.value.0, &Self::value_type(&value));
+                        self.builder.build_store(alloca, value);
+                        alloca
+                    }
+                };
+                let bit_ptr = self.build_register_bit_ptr(register, ident, index)?;
+                self.builder.build_load(bit_ptr, "bit")
+            },
+            Expression::Measure(operand) => match self.compile_expr(operand)? {
+                value @ BasicValueEnum::PointerValue(qubit) if Self::value_type(&value) == Type::Qubit =>
+                    self.build_measurement(qubit).into(),
+                value => return Err(QKaledioscopeError::TypeError {
+                    expected: Type::Qubit.to_string(),
+                    actual: Self::value_type(&value).to_string(),
+                    src: self.source.clone(),
+                    expr_span: operand.as_sourcespan(),
+                    type_span: None,
+                    help: None,
+                }),
+            },
+        })
+    }
+
+    /// Recovers the Quantum Kaledioscope type that a compiled value was
+    /// lowered from.
+    fn value_type(value: &BasicValueEnum<'ctx>) -> Type {
+        match value {
+            BasicValueEnum::IntValue(_) => Type::Bit,
+            BasicValueEnum::FloatValue(_) => Type::Number,
+            // Both qubits and strings lower to pointers, but only strings
+            // point to integers.
+            BasicValueEnum::PointerValue(p) if p.get_type().get_element_type().is_int_type() => Type::String,
+            BasicValueEnum::PointerValue(_) => Type::Qubit,
+            BasicValueEnum::ArrayValue(register) => Type::Register(register.get_type().len() as usize),
+            _ => unreachable!("No Quantum Kaledioscope type lowers to {value:?}.")
+        }
+    }
+
+    /// Gets a pointer to one bit of the register that `register` points to.
+    /// Indices written as literals are checked against the size of the
+    /// register here; any others are assumed to be in range, since compiled
+    /// programs don't check indices when they run.
+    fn build_register_bit_ptr(&mut self, register: PointerValue<'ctx>, ident: &Located<Identifier>, index: &Located<Expression>) -> Result<PointerValue<'ctx>> {
+        let size = match register.get_type().get_element_type() {
+            AnyTypeEnum::ArrayType(array) => array.len() as usize,
+            _ => return Err(QKaledioscopeError::TypeError {
+                expected: "a register".to_string(),
+                actual: Self::value_type(&self.builder.build_load(register, "")).to_string(),
+                src: self.source.clone(),
+                expr_span: ident.as_sourcespan(),
+                type_span: None,
+                help: None,
+            }),
+        };
+        if let Expression::NumberLiteral(idx) = index.value {
+            register_index(self.source, idx, size, index.as_sourcespan())?;
+        }
+        let i64_type = self.context.i64_type();
+        let idx = self.compile_number_operand(index)?;
+        let idx = self.builder.build_float_to_unsigned_int(idx, i64_type, "idx");
+        Ok(unsafe {
+            self.builder.build_in_bounds_gep(register, &[i64_type.const_zero(), idx], "bit_ptr")
+        })
+    }
+
+    fn compile_number_operand(&mut self, expr: &Located<Expression>) -> Result<FloatValue<'ctx>> {
+        match self.compile_expr(expr)? {
+            BasicValueEnum::FloatValue(value) => Ok(value),
+            value => Err(QKaledioscopeError::TypeError {
+                expected: Type::Number.to_string(),
+                actual: Self::value_type(&value).to_string(),
+                src: self.source.clone(),
+                expr_span: expr.as_sourcespan(),
+                type_span: None,
+                help: literal_help(&Type::Number, &Self::value_type(&value)),
+            })
+        }
+    }
+
+    /// Compiles the condition of an `if` or `while` statement (or the operand
+    /// to `not`), which must lower to an `i1`.
+    fn compile_condition(&mut self, condition: &Located<Expression>) -> Result<IntValue<'ctx>> {
+        match self.compile_expr(condition)? {
+            BasicValueEnum::IntValue(cond) => Ok(cond),
+            value => Err(QKaledioscopeError::TypeError {
+                expected: Type::Bit.to_string(),
+                actual: Self::value_type(&value).to_string(),
+                src: self.source.clone(),
+                expr_span: condition.as_sourcespan(),
+                type_span: None,
+                help: literal_help(&Type::Bit, &Self::value_type(&value)),
+            })
+        }
+    }
+
+    fn compile_binary_op(&mut self, lhs: &Located<Expression>, op: &Located<BinaryOperator>, rhs: &Located<Expression>) -> Result<BasicValueEnum<'ctx>> {
+        if let BinaryOperator::Equal | BinaryOperator::NotEqual = op.value {
+            // Equality is defined between two numbers or two bits; in either
+            // case, we get back an i1 that we can branch on.
+            let lhs_value = self.compile_expr(lhs)?;
+            let rhs_value = self.compile_expr(rhs)?;
+            let is_equal = op.value == BinaryOperator::Equal;
+            return match (lhs_value, rhs_value) {
+                (BasicValueEnum::IntValue(l), BasicValueEnum::IntValue(r)) => {
+                    let predicate = if is_equal { IntPredicate::EQ } else { IntPredicate::NE };
+                    Ok(self.builder.build_int_compare(predicate, l, r, "cmptmp").into())
+                },
+                (BasicValueEnum::FloatValue(l), BasicValueEnum::FloatValue(r)) => {
+                    // NaN isn't equal to anything, itself included, so `!=`
+                    // has to be unordered to hold for NaN as it does in the
+                    // interpreter.
+                    let predicate = if is_equal { FloatPredicate::OEQ } else { FloatPredicate::UNE };
+                    Ok(self.builder.build_float_compare(predicate, l, r, "cmptmp").into())
+                },
+                (BasicValueEnum::PointerValue(_), _) => Err(QKaledioscopeError::TypeError {
+                    expected: format!("{} or {}", Type::Number, Type::Bit),
+                    actual: Self::value_type(&lhs_value).to_string(),
+                    src: self.source.clone(),
+                    expr_span: lhs.as_sourcespan(),
+                    type_span: None,
+                    help: None,
+                }),
+                _ => Err(QKaledioscopeError::TypeError {
+                    expected: Self::value_type(&lhs_value).to_string(),
+                    actual: Self::value_type(&rhs_value).to_string(),
+                    src: self.source.clone(),
+                    expr_span: rhs.as_sourcespan(),
+                    type_span: None,
+                    help: literal_help(&Self::value_type(&lhs_value), &Self::value_type(&rhs_value)),
+                }),
+            };
+        }
+
+        // NB: Numbers are the only type with arithmetic or ordering, so both
+        //     sides lower to floats; integer arithmetic would go here as well
+        //     if we add an integer type.
+        let l = self.compile_number_operand(lhs)?;
+        let r = self.compile_number_operand(rhs)?;
+        Ok(match op.value {
+            BinaryOperator::Add => self.builder.build_float_add(l, r, "addtmp").into(),
+            BinaryOperator::Subtract => self.builder.build_float_sub(l, r, "subtmp").into(),
+            BinaryOperator::Multiply => self.builder.build_float_mul(l, r, "multmp").into(),
+            BinaryOperator::Divide => self.builder.build_float_div(l, r, "divtmp").into(),
+            BinaryOperator::Less => self.builder.build_float_compare(FloatPredicate::OLT, l, r, "cmptmp").into(),
+            BinaryOperator::LessEqual => self.builder.build_float_compare(FloatPredicate::OLE, l, r, "cmptmp").into(),
+            BinaryOperator::Greater => self.builder.build_float_compare(FloatPredicate::OGT, l, r, "cmptmp").into(),
+            BinaryOperator::GreaterEqual => self.builder.build_float_compare(FloatPredicate::OGE, l, r, "cmptmp").into(),
+            BinaryOperator::Equal | BinaryOperator::NotEqual => unreachable!()
         })
     }
 
@@ -207,46 +684,115 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
         for stmt in body.iter() {
             match &stmt.value {
                 Statement::VariableDeclaration(ident, ty, rhs) => {
-                    let alloca = self.create_entry_block_alloca(&ident.value.0, &ty.value);
-                    self.builder.build_store(alloca, self.compile_expr(&rhs)?);
+                    let value = self.compile_expr(rhs)?;
+                    let ty = match ty {
+                        Some(Located { value: Type::Tuple(_), .. }) =>
+                            return Err(self.unsupported_tuple_error(rhs.as_sourcespan())),
+                        Some(ty) => ty.value.clone(),
+                        None => Self::value_type(&value),
+                    };
+                    let alloca = self.create_entry_block_alloca(&ident.value.0, &ty);
+                    self.builder.build_store(alloca, value);
                     self.variables.insert(ident.value.0.to_string(), alloca);
                 },
+                Statement::TupleDeclaration(..) =>
+                    return Err(self.unsupported_tuple_error(stmt.as_sourcespan())),
                 Statement::Assignment(ident, rhs) => {
-                    // TODO: Don't unwrap here.
-                    let alloca = self.variables.get(&ident.value.0.to_string()).unwrap();
-                    self.builder.build_store(*alloca, self.compile_expr(&rhs)?);
+                    let ptr = self.variable_ptr(&ident.value.0).ok_or_else(|| QKaledioscopeError::UndefinedVariableError {
+                        name: ident.value.0.clone(),
+                        src: self.source.clone(),
+                        span: ident.as_sourcespan(),
+                        help: did_you_mean(&ident.value.0, self.variable_names()),
+                    })?;
+                    self.builder.build_store(ptr, self.compile_expr(rhs)?);
+                },
+                Statement::IndexAssignment(ident, index, rhs) => {
+                    let register = self.variable_ptr(&ident.value.0).ok_or_else(|| QKaledioscopeError::UndefinedVariableError {
+                        name: ident.value.0.clone(),
+                        src: self.source.clone(),
+                        span: ident.as_sourcespan(),
+                        help: did_you_mean(&ident.value.0, self.variable_names()),
+                    })?;
+                    let bit = self.compile_condition(rhs)?;
+                    let bit_ptr = self.build_register_bit_ptr(register, ident, index)?;
+                    self.builder.build_store(bit_ptr, bit);
                 },
                 Statement::Call(ident, args) => {
                     self.compile_call(ident, args)?;
                 },
+                // Nothing is reordered across statements when compiling, so
+                // barriers don't emit anything, but as in the interpreter, we
+                // still make sure that each one only refers to qubits.
+                Statement::Barrier(qubits) => {
+                    for qubit in qubits {
+                        let value = self.compile_expr(qubit)?;
+                        if Self::value_type(&value) != Type::Qubit {
+                            return Err(QKaledioscopeError::TypeError {
+                                expected: Type::Qubit.to_string(),
+                                actual: Self::value_type(&value).to_string(),
+                                src: self.source.clone(),
+                                expr_span: qubit.as_sourcespan(),
+                                type_span: None,
+                                help: None,
+                            });
+                        }
+                    }
+                },
                 Statement::Return(expr) => {
-                    let value = self.compile_expr(&expr)?;
-                    self.builder.build_return(Some(&value));
+                    let value = match expr {
+                        Some(expr) => Some((expr, self.compile_expr(expr)?)),
+                        None => None
+                    };
+                    let prototype = self.current_prototype();
+                    match (&value, &prototype.value.return_type) {
+                        (Some((expr, value)), None) => return Err(QKaledioscopeError::TypeError {
+                            expected: Type::Unit.to_string(),
+                            actual: Self::value_type(value).to_string(),
+                            src: self.source.clone(),
+                            expr_span: expr.as_sourcespan(),
+                            type_span: Some(prototype.as_sourcespan()),
+                            help: None,
+                        }),
+                        (None, Some(return_type)) => return Err(QKaledioscopeError::TypeError {
+                            expected: return_type.value.to_string(),
+                            actual: Type::Unit.to_string(),
+                            src: self.source.clone(),
+                            expr_span: stmt.as_sourcespan(),
+                            type_span: Some(return_type.as_sourcespan()),
+                            help: None,
+                        }),
+                        _ => ()
+                    };
+                    if self.is_compiling_qmain() {
+                        if let Some((_, value)) = &value {
+                            self.build_output_recording(*value);
+                        }
+                        self.build_qubit_releases();
+                    }
+                    match &value {
+                        Some((_, value)) => self.builder.build_return(Some(value)),
+                        None => self.builder.build_return(None)
+                    };
                 },
                 Statement::If { condition, true_body, false_body} => {
                     let parent = self.fn_value();
                     let then_bb = self.context.append_basic_block(parent, "then");
                     let else_bb = self.context.append_basic_block(parent, "else");
                     let cont_bb = self.context.append_basic_block(parent, "ifcont");
-                    let cond = self.compile_expr(&condition)?;
-                    let cond = match cond {
-                        BasicValueEnum::IntValue(cond) => cond,
-                        // TODO: Don't unwrap here.
-                        _ => panic!("Expected a boolean condition, but got {cond:?}")
-                    };
+                    let cond = self.compile_condition(&condition)?;
 
                     self.builder.build_conditional_branch(cond, then_bb, else_bb);
 
                     // Build then block.
                     self.builder.position_at_end(then_bb);
                     self.compile_body(true_body)?;
-                    self.builder.build_unconditional_branch(cont_bb);
+                    self.build_branch_if_unterminated(cont_bb);
                     let then_bb = self.builder.get_insert_block().unwrap();
 
                     // Built the else block.
                     self.builder.position_at_end(else_bb);
                     self.compile_body(false_body)?;
-                    self.builder.build_unconditional_branch(cont_bb);
+                    self.build_branch_if_unterminated(cont_bb);
                     let else_bb = self.builder.get_insert_block().unwrap();
 
                     // NB: We don't have to worry about phi nodes here, since we
@@ -256,31 +802,108 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
                     //     anything more practical.
                     self.builder.position_at_end(cont_bb);
                 },
-                _ => todo!("not yet implemented: {stmt:?}")
+                Statement::While { condition, body } => {
+                    let parent = self.fn_value();
+                    let cond_bb = self.context.append_basic_block(parent, "loopcond");
+                    let body_bb = self.context.append_basic_block(parent, "loop");
+                    let after_bb = self.context.append_basic_block(parent, "afterloop");
+                    self.build_branch_if_unterminated(cond_bb);
+
+                    // The condition gets its own block, so that the end of
+                    // each iteration can jump back and evaluate it again.
+                    self.builder.position_at_end(cond_bb);
+                    let cond = self.compile_condition(condition)?;
+                    self.builder.build_conditional_branch(cond, body_bb, after_bb);
+
+                    self.builder.position_at_end(body_bb);
+                    self.compile_body(body)?;
+                    self.build_branch_if_unterminated(cond_bb);
+
+                    self.builder.position_at_end(after_bb);
+                },
             }
         }
 
         Ok(())
     }
 
+    /// Lowers a value computed ahead of time by the interpreter into an LLVM
+    /// constant.
+    fn const_value(&self, value: InterpreterValue) -> BasicValueEnum<'ctx> {
+        match value {
+            InterpreterValue::Number(n) => self.context.f64_type().const_float(n).into(),
+            InterpreterValue::Bit(b) => self.context.bool_type().const_int(if b { 1 } else { 0 }, false).into(),
+            InterpreterValue::Str(s) => self.build_string_constant(&s).into(),
+            InterpreterValue::QubitRef(_) | InterpreterValue::Gate(_) =>
+                unreachable!("Constants and globals can't refer to qubits or gates."),
+            InterpreterValue::Unit => unreachable!("Constants and globals can't call functions."),
+            InterpreterValue::Tuple(_) => unreachable!("Tuple constants and globals are rejected before compiling."),
+            InterpreterValue::Register(bits) => {
+                let bits = bits.iter()
+                    .map(|bit| self.context.bool_type().const_int(if *bit { 1 } else { 0 }, false))
+                    .collect::<Vec<_>>();
+                self.context.bool_type().const_array(&bits).into()
+            },
+        }
+    }
+
     pub fn compile(&mut self) -> Result<()> {
         // We start by making prototypes for each file element in the source.
         // This allows us to make sure we can always emit call instructions
         // later on in the compilation process, as the function declaration
-        // will always exist.
+        // will always exist. In particular, this is what lets a function call
+        // another that's defined later in the file, or lets two functions be
+        // mutually recursive.
         for file_element in &self.program.0 {
             let proto = match &file_element.value {
                 FileElement::Declaration(proto) => proto,
-                FileElement::Definition { body, prototype } => prototype
+                FileElement::Definition { body, prototype } => prototype,
+                FileElement::Constant { type_sig: type_sig @ Located { value: Type::Tuple(_), .. }, .. } |
+                FileElement::Global { type_sig: type_sig @ Located { value: Type::Tuple(_), .. }, .. } =>
+                    return Err(self.unsupported_tuple_error(type_sig.as_sourcespan())),
+                FileElement::Constant { .. } | FileElement::Global { .. } | FileElement::Include(_) => continue,
             };
-            let compiled_proto = self.compile_prototype(proto).unwrap(); // TODO: don't unwrap!
+            let compiled_proto = self.compile_prototype(proto)?;
+            // Externs never get a body, and are left for whatever the
+            // compiled program is linked against (e.g.: a QIR runtime) to
+            // provide.
+            if let FileElement::Declaration(_) = &file_element.value {
+                compiled_proto.set_linkage(Linkage::External);
+            }
+        }
+
+        // Constants and the initial values of globals can only be made up of
+        // literals and operators, so we can evaluate them up front with the
+        // interpreter rather than generating code to compute them.
+        let table = FunctionTable::build(self.source, self.program, RunOptions::default())?;
+        for (name, value) in table.constants() {
+            let value = self.const_value(value);
+            self.constants.insert(name.0.clone(), value);
+        }
+        for (name, value) in table.globals() {
+            let value = self.const_value(value);
+            let global = self.module.add_global(value.get_type(), None, &name.0);
+            global.set_linkage(Linkage::Internal);
+            global.set_initializer(&value);
+            self.globals.insert(name.0, global.as_pointer_value());
+        }
+
+        // With dynamic allocation, qubit literals refer to handles that qmain
+        // gets from the runtime, so we need somewhere global to keep them.
+        if self.options.qubit_allocation == QubitAllocation::Dynamic {
+            let n_qubits = self.program.n_qubits_required();
+            let handles_type = self.qubit_type().array_type(n_qubits as u32);
+            let handles = self.module.add_global(handles_type, None, "qubit_handles");
+            handles.set_linkage(Linkage::Internal);
+            handles.set_initializer(&handles_type.const_zero());
+            self.qubit_handles = Some((handles.as_pointer_value(), n_qubits));
         }
 
         // Once we've made an initial pass to build prototypes, we can run a
         // second pass to add function bodies directly.
         for file_element in &self.program.0 {
             match &file_element.value {
-                FileElement::Declaration(_) => (),
+                FileElement::Declaration(_) | FileElement::Constant { .. } | FileElement::Global { .. } | FileElement::Include(_) => (),
                 FileElement::Definition { body, prototype } => {
                     // TODO: Move this this logic into a new method for compiling
                     //       function arg decls.
@@ -290,7 +913,10 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
                     let entry = self.context.append_basic_block(function, "entry");
                     self.builder.position_at_end(entry);
 
-                    // Load arguments in as variables.
+                    // Load arguments in as variables, starting from a clean
+                    // slate so that locals from previously compiled functions
+                    // aren't visible here.
+                    self.variables.clear();
                     self.variables.reserve(prototype.value.arguments.len());
 
 
@@ -303,9 +929,31 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
                         self.variables.insert(arg_name, alloca);
                     }
 
+                    if self.is_compiling_qmain() {
+                        self.build_qubit_allocations();
+                    }
+
                     // Now that we've loaded arguments, we can compile the
                     // body itself.
                     self.compile_body(&body)?;
+
+                    // Functions without a return type are allowed to run off
+                    // the end of their bodies, so we need to add the return
+                    // ourselves. Returns from qmain also release qubits on
+                    // their way out. The checker makes sure that every path
+                    // through a function with a return type returns, so any
+                    // block left at the end of one (e.g.: after an if whose
+                    // branches both return) can never be reached.
+                    if self.builder.get_insert_block().unwrap().get_terminator().is_none() {
+                        if prototype.value.return_type.is_some() {
+                            self.builder.build_unreachable();
+                        } else {
+                            if self.is_compiling_qmain() {
+                                self.build_qubit_releases();
+                            }
+                            self.builder.build_return(None);
+                        }
+                    }
                 }
             }
         }
@@ -314,46 +962,437 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
     }
 }
 
-pub fn compile(source_file: PathBuf) -> Result<()> {
-    // TODO: Need some way of getting source as String here so that we can
-    //       attach error messages.
-    let (program, source) = build_ast(source_file)?;
-
-    let context = Context::create();
+/// Compiles an already checked program into a new LLVM module, verifying
+/// that the resulting IR is valid.
+fn compile_module<'ctx>(context: &'ctx Context, source: &Arc<str>, program: &Program, options: &CompileOptions) -> Result<Module<'ctx>> {
     let module = context.create_module("qk");
-    let builder = context.create_builder();
-
-    // Initialize the pass manager.
-    let fpm = PassManager::create(&module);
-    fpm.add_instruction_combining_pass();
-    fpm.add_reassociate_pass();
-    fpm.add_gvn_pass();
-    fpm.add_cfg_simplification_pass();
-    fpm.add_basic_alias_analysis_pass();
-    fpm.add_promote_memory_to_register_pass();
-    fpm.add_instruction_combining_pass();
-    fpm.add_reassociate_pass();
-    fpm.initialize();
-
-    let mut compiler = Compiler {
-        builder: &builder,
-        context: &context,
-        fpm: &fpm,
-        module: &module,
-        program: &program,
-        source: &source,
-        fn_value_opt: None,
-        variables: HashMap::new(),
-        prototypes: HashMap::new(),
-    };
+    {
+        let builder = context.create_builder();
+
+        // Initialize the pass manager.
+        let fpm = PassManager::create(&module);
+        fpm.add_instruction_combining_pass();
+        fpm.add_reassociate_pass();
+        fpm.add_gvn_pass();
+        fpm.add_cfg_simplification_pass();
+        fpm.add_basic_alias_analysis_pass();
+        fpm.add_promote_memory_to_register_pass();
+        fpm.add_instruction_combining_pass();
+        fpm.add_reassociate_pass();
+        fpm.initialize();
+
+        let mut compiler = Compiler {
+            builder: &builder,
+            context,
+            fpm: &fpm,
+            module: &module,
+            program,
+            source,
+            options,
+            fn_value_opt: None,
+            variables: HashMap::new(),
+            constants: HashMap::new(),
+            globals: HashMap::new(),
+            prototypes: HashMap::new(),
+            qubit_handles: None,
+        };
+
+        compiler.compile()?;
+    }
+    // Make sure we actually produced valid IR before handing it off to
+    // anything downstream.
+    verify_module(&module)?;
+
+    Ok(module)
+}
+
+/// Checks that a module is valid LLVM IR, turning whatever LLVM finds wrong
+/// with it into a `CodegenError`. Blocks without a terminator are caught
+/// here, for instance:
+///
+/// ```
+/// use inkwell::context::Context;
+/// use parser::{codegen::verify_module, QKaledioscopeError};
+///
+/// let context = Context::create();
+/// let module = context.create_module("broken");
+/// let function = module.add_function("f", context.void_type().fn_type(&[], false), None);
+/// context.append_basic_block(function, "entry");
+/// assert!(matches!(
+///     verify_module(&module),
+///     Err(QKaledioscopeError::CodegenError { message }) if message.contains("does not have terminator")
+/// ));
+///
+/// // Ending a function with an if whose branches both return used to leave
+/// // an empty block like the one above.
+/// let module = parser::codegen::compile_str_in(&context, "
+///     def f(x: number) -> number {
+///         if x < 1.0 { return 1.0; } else { return 2.0; }
+///     }
+///     def qmain() {}
+/// ").unwrap();
+/// assert!(verify_module(&module).is_ok());
+/// ```
+///
+/// The diagnostic quotes LLVM's own message, along with a hint at what's
+/// usually behind it:
+///
+/// ```
+/// use inkwell::context::Context;
+/// use miette::Diagnostic;
+///
+/// let context = Context::create();
+/// let module = context.create_module("broken");
+/// let function = module.add_function("f", context.void_type().fn_type(&[], false), None);
+/// context.append_basic_block(function, "entry");
+/// let err = parser::codegen::verify_module(&module).unwrap_err();
+/// assert!(err.to_string().starts_with("Compiled program was not valid LLVM IR:\n"));
+/// assert!(err.to_string().contains("in function 'f'"));
+/// assert!(err.help().unwrap().to_string().contains("doesn't return on every path"));
+/// ```
+pub fn verify_module(module: &Module) -> Result<()> {
+    module.verify().map_err(|message| QKaledioscopeError::CodegenError {
+        message: message.to_string()
+    })
+}
 
-    compiler.compile()?;
+/// Compiles a program given as source into QIR, returning the IR as text
+/// without touching the filesystem. Qubits are allocated statically, as
+/// with the default options for the `compile` command.
+///
+/// ```
+/// let ir = parser::compile_str("
+///     extern h(q : qubit);
+///     def qmain() {
+///         h(%0);
+///     }
+/// ").unwrap();
+/// assert!(ir.contains("qmain"));
+/// ```
+///
+/// Extern declarations are compiled into declarations without a body, to be
+/// resolved when linking:
+///
+/// ```
+/// let ir = parser::compile_str("
+///     extern reticulate(angle : number);
+///     def qmain() {
+///         reticulate(1.0);
+///     }
+/// ").unwrap();
+/// assert!(ir.contains("declare void @reticulate(double"));
+/// assert!(ir.contains("call void @reticulate(double"));
+/// ```
+///
+/// Whatever `qmain` returns is recorded as output with the QIR runtime, so
+/// that a measurement result can be reported back:
+///
+/// ```
+/// let ir = parser::compile_str("
+///     extern h(q : qubit);
+///     def qmain() -> bit {
+///         h(%0);
+///         return measure %0;
+///     }
+/// ").unwrap();
+/// assert_eq!(ir.matches("call void @__quantum__rt__bool_record_output(i1").count(), 1);
+/// assert!(ir.contains("declare void @__quantum__rt__bool_record_output(i1, i8*)"));
+/// ```
+///
+/// Registers are recorded as an array, followed by each of their bits:
+///
+/// ```
+/// let ir = parser::compile_str("
+///     def qmain() -> bit[2] {
+///         var r: bit[2] = [false, false];
+///         r[0] = measure %0;
+///         r[1] = measure %1;
+///         return r;
+///     }
+/// ").unwrap();
+/// assert_eq!(ir.matches("call void @__quantum__rt__array_record_output(i64 2").count(), 1);
+/// assert_eq!(ir.matches("call void @__quantum__rt__bool_record_output(i1").count(), 2);
+/// ```
+///
+/// Functions with a return type can end with an if whose branches both
+/// return, leaving nothing to run after it:
+///
+/// ```
+/// let ir = parser::compile_str("
+///     def f(x: number) -> number {
+///         if x < 1.0 {
+///             return 1.0;
+///         } else {
+///             return 2.0;
+///         }
+///     }
+///     def qmain() -> number {
+///         return f(0.0);
+///     }
+/// ").unwrap();
+/// assert!(ir.contains("define double @f(double"));
+/// ```
+///
+/// Since every function is declared before any are compiled, functions can
+/// call each other no matter which order they're defined in:
+///
+/// ```
+/// let ir = parser::compile_str("
+///     def qmain() -> bit {
+///         return is_even(4.0);
+///     }
+///     def is_even(n: number) -> bit {
+///         if n == 0.0 {
+///             return true;
+///         }
+///         return is_odd(n - 1.0);
+///     }
+///     def is_odd(n: number) -> bit {
+///         if n == 0.0 {
+///             return false;
+///         }
+///         return is_even(n - 1.0);
+///     }
+/// ").unwrap();
+/// let definition = |name: &str| {
+///     let start = ir.find(&format!("define i1 @{name}(double")).unwrap();
+///     &ir[start..start + ir[start..].find("\n}").unwrap()]
+/// };
+/// assert!(definition("is_even").contains("call i1 @is_odd(double"));
+/// assert!(definition("is_odd").contains("call i1 @is_even(double"));
+/// ```
+///
+/// Multiplication binds tighter than addition, so its result feeds into the
+/// sum:
+///
+/// ```
+/// let ir = parser::compile_str("
+///     def f(a: number, b: number, c: number) -> number {
+///         var x: number = a * b + c;
+///         return x;
+///     }
+///     def qmain() {}
+/// ").unwrap();
+/// let fmul = ir.find("%multmp = fmul double").unwrap();
+/// let fadd = ir.find("%addtmp = fadd double %multmp,").unwrap();
+/// assert!(fmul < fadd);
+/// ```
+///
+/// Even an empty `qmain` compiles to a function that returns right away:
+///
+/// ```
+/// let ir = parser::compile_str("def qmain() {}").unwrap();
+/// assert!(ir.contains("ret void"));
+/// ```
+pub fn compile_str(source: &str) -> Result<String> {
+    let context = Context::create();
+    let module = compile_str_in(&context, source)?;
     let ir = module.print_to_string().to_string();
-    println!("Compiled IR:\n{ir}");
+    Ok(ir)
+}
+
+/// Compiles a program given as source into a new module owned by an
+/// existing LLVM context, so that several programs can share one context
+/// rather than each creating their own.
+///
+/// ```
+/// use inkwell::context::Context;
+///
+/// let context = Context::create();
+/// let bell = parser::codegen::compile_str_in(&context, "
+///     extern h(q : qubit);
+///     extern cnot(control : qubit, target : qubit);
+///     def qmain() {
+///         h(%0);
+///         cnot(%0, %1);
+///     }
+/// ").unwrap();
+/// let empty = parser::codegen::compile_str_in(&context, "def qmain() {}").unwrap();
+/// assert!(bell.get_function("cnot").is_some());
+/// assert!(empty.get_function("cnot").is_none());
+/// ```
+pub fn compile_str_in<'ctx>(context: &'ctx Context, source: &str) -> Result<Module<'ctx>> {
+    let source: Arc<str> = source.into();
+    let program = parse_shared(&source)?;
+    scope::scope_check(&source, &program)?;
+    checker::check_program(&source, &program)?;
+
+    compile_module(context, &source, &program, &CompileOptions::default())
+}
+
+/// Compiles a program read from a file, printing the result or writing it
+/// to `options.output`. With `emit_cfg` set, the control-flow graph of each
+/// function is given as a Graphviz DOT graph instead of the compiled IR,
+/// with each conditional branch labeled by which way it goes:
+///
+/// ```
+/// use parser::codegen::{compile, CompileOptions};
+///
+/// let dir = std::env::temp_dir().join("qk_compile_cfg");
+/// std::fs::create_dir_all(&dir).unwrap();
+/// let source_file = dir.join("branches.qk");
+/// std::fs::write(&source_file, "
+///     extern x(q : qubit);
+///     def qmain() {
+///         if measure %0 {
+///             x(%0);
+///         } else {
+///             x(%1);
+///         }
+///         var n: number = 0.0;
+///         while n < 2.0 {
+///             n = n + 1.0;
+///         }
+///     }
+/// ").unwrap();
+/// compile(source_file, &CompileOptions {
+///     emit_cfg: true,
+///     output: Some(dir.join("branches.dot")),
+///     ..CompileOptions::default()
+/// }).unwrap();
+/// let dot = std::fs::read_to_string(dir.join("branches.dot")).unwrap();
+///
+/// let node = |name: &str| {
+///     let line = dot.lines().find(|line| line.ends_with(&format!("[label=\"qmain: {name}\"];"))).unwrap();
+///     line.trim().split(' ').next().unwrap().to_string()
+/// };
+/// let has_edge = |from: &str, to: &str, label: &str| {
+///     dot.contains(&format!("{} -> {} [label=\"{label}\"];", node(from), node(to)))
+/// };
+/// assert!(has_edge("entry", "then", "true"));
+/// assert!(has_edge("entry", "else", "false"));
+/// assert!(dot.contains(&format!("{} -> {};", node("then"), node("ifcont"))));
+/// assert!(dot.contains(&format!("{} -> {};", node("else"), node("ifcont"))));
+/// assert!(has_edge("loopcond", "loop", "true"));
+/// assert!(has_edge("loopcond", "afterloop", "false"));
+/// assert!(dot.contains(&format!("{} -> {};", node("loop"), node("loopcond"))));
+/// ```
+pub fn compile(source_file: PathBuf, options: &CompileOptions) -> Result<()> {
+    let timer = PhaseTimer { enabled: options.time };
+    let (mut program, source) = build_ast_timed(source_file, timer)?;
+    timer.time("check", || -> Result<()> {
+        scope::scope_check(&source, &program)?;
+        checker::check_program(&source, &program)?;
+        checker::report_warnings(&source, &program);
+        Ok(())
+    })?;
+    if options.fold_constants {
+        timer.time("fold constants", || folding::fold_constants(&source, &mut program));
+    }
+
+    let context = Context::create();
+    let module = timer.time("codegen", || compile_module(&context, &source, &program, options))?;
+    match &options.output {
+        None if options.emit_cfg => print!("{}", cfg_to_dot(&module)),
+        None => {
+            let ir = module.print_to_string().to_string();
+            println!("Compiled IR:\n{ir}");
+        },
+        Some(path) if options.emit_cfg => write_output(path, cfg_to_dot(&module))?,
+        Some(path) => write_module(&module, path)?,
+    };
 
     Ok(())
 }
 
-pub fn run_compile_cmd(source_file: PathBuf) -> miette::Result<()> {
-    Ok(compile(source_file)?)
+/// Writes a compiled module to a file, in a format picked from the file's
+/// extension: `.ll` for textual IR, `.bc` for bitcode, or `.o` for an
+/// object file for the machine we're running on.
+///
+/// ```
+/// use parser::codegen::{compile, CompileOptions};
+///
+/// let dir = std::env::temp_dir().join("qk_write_module");
+/// std::fs::create_dir_all(&dir).unwrap();
+/// let source_file = dir.join("bell.qk");
+/// std::fs::write(&source_file, "
+///     extern h(q : qubit);
+///     extern cnot(c : qubit, t : qubit);
+///     def qmain() {
+///         h(%0);
+///         cnot(%0, %1);
+///     }
+/// ").unwrap();
+/// let compile_to = |name: &str| compile(source_file.clone(), &CompileOptions {
+///     output: Some(dir.join(name)),
+///     ..CompileOptions::default()
+/// });
+///
+/// compile_to("bell.ll").unwrap();
+/// assert!(std::fs::read_to_string(dir.join("bell.ll")).unwrap().contains("define void @qmain()"));
+/// compile_to("bell.bc").unwrap();
+/// assert!(std::fs::read(dir.join("bell.bc")).unwrap().starts_with(b"BC\xC0\xDE"));
+/// compile_to("bell.o").unwrap();
+/// assert!(!std::fs::read(dir.join("bell.o")).unwrap().is_empty());
+/// assert!(matches!(compile_to("bell.txt"), Err(parser::QKaledioscopeError::UnsupportedOutputFormatError { .. })));
+/// ```
+fn write_module(module: &Module, path: &Path) -> Result<()> {
+    let write_error = |message: String| QKaledioscopeError::IOError {
+        cause: std::io::Error::new(std::io::ErrorKind::Other, message),
+        subject: path.to_str().map(|s| s.to_string()),
+    };
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("ll") => module.print_to_file(path).map_err(|message| write_error(message.to_string())),
+        Some("bc") => match module.write_bitcode_to_path(path) {
+            true => Ok(()),
+            false => Err(write_error("LLVM could not write bitcode".to_string())),
+        },
+        Some("o") => {
+            Target::initialize_native(&InitializationConfig::default()).map_err(write_error)?;
+            let triple = TargetMachine::get_default_triple();
+            let machine = Target::from_triple(&triple)
+                .map_err(|message| write_error(message.to_string()))?
+                .create_target_machine(&triple, "generic", "", OptimizationLevel::Default, RelocMode::Default, CodeModel::Default)
+                .ok_or_else(|| write_error("LLVM could not target this machine".to_string()))?;
+            machine.write_to_file(module, FileType::Object, path).map_err(|message| write_error(message.to_string()))
+        },
+        _ => Err(QKaledioscopeError::UnsupportedOutputFormatError {
+            path: path.to_string_lossy().to_string(),
+        }),
+    }
+}
+
+fn write_output(path: &Path, contents: String) -> Result<()> {
+    std::fs::write(path, contents).map_err(|e| QKaledioscopeError::IOError {
+        cause: e,
+        subject: path.to_str().map(|s| s.to_string()),
+    })
+}
+
+/// Renders the control-flow graph of each function defined in a module as
+/// a Graphviz DOT graph, with a node for each basic block and an edge from
+/// each block to the successors of its terminator.
+fn cfg_to_dot(module: &Module) -> String {
+    let mut writer = DotWriter::default();
+    for function in module.get_functions() {
+        let fn_name = function.get_name().to_string_lossy().to_string();
+        let blocks = function.get_basic_blocks();
+        let ids = blocks
+            .iter()
+            .map(|block| writer.node(&format!("{fn_name}: {}", block.get_name().to_string_lossy())))
+            .collect::<Vec<_>>();
+        for (block, id) in blocks.iter().zip(&ids) {
+            let terminator = match block.get_terminator() {
+                Some(terminator) => terminator,
+                None => continue
+            };
+            let successors = (0..terminator.get_num_operands())
+                .filter_map(|idx| terminator.get_operand(idx))
+                .filter_map(|operand| operand.right())
+                .collect::<Vec<_>>();
+            // NB: LLVM lists the false case of a conditional branch before
+            //     the true case.
+            let is_conditional = terminator.get_opcode() == InstructionOpcode::Br && successors.len() == 2;
+            for (idx, successor) in successors.iter().enumerate() {
+                let target = ids[blocks.iter().position(|block| block == successor).unwrap()];
+                let label = is_conditional.then(|| if idx == 0 { "false" } else { "true" });
+                writer.edge(*id, target, label);
+            }
+        }
+    }
+    writer.finish("cfg")
+}
+
+pub fn run_compile_cmd(source_file: PathBuf, options: CompileOptions) -> miette::Result<()> {
+    Ok(compile(source_file, &options)?)
 }
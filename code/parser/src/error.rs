@@ -1,7 +1,11 @@
-use miette::{Diagnostic, SourceSpan, SourceCode, SourceOffset};
+use std::sync::Arc;
+
+use miette::{Diagnostic, SourceSpan, SourceOffset};
 use pest::{error::LineColLocation, Span};
 use thiserror::Error;
 
+use crate::{ast::Type, parser::Rule};
+
 #[derive(Debug, Diagnostic, Error)]
 pub enum QKaledioscopeError {
     #[error("I/O error reading {subject:?}: {cause}")]
@@ -26,47 +30,129 @@ pub enum QKaledioscopeError {
     ParseError {
         description: String,
         #[source_code]
-        src: String,
+        src: Arc<str>,
         #[label("{description}")]
         err_span: SourceSpan,
         #[related]
         causes: Vec<QKaledioscopeError>
     },
 
-    #[error("Duplicate name error")]
+    #[error("Duplicate name error{}", builtin_note(.old_is_builtin))]
     #[diagnostic()]
     DuplicateNameError {
         name: String,
 
         #[source_code]
-        src: String,
+        src: Arc<str>,
 
+        // NB: Built-in functions aren't defined in the source, so there's no
+        //     old span to point to if that's where the collision came from.
         #[label("...but {name} was already defined here.")]
-        // TODO: Change to sourcespan
-        old_span: (usize, usize),
+        old_span: Option<SourceSpan>,
+        old_is_builtin: bool,
 
         #[label("Attempted to define {name} here...")]
-        // TODO: Change to sourcespan
-        new_span: (usize, usize),
+        new_span: SourceSpan,
+    },
+
+    #[error("{path} includes itself.")]
+    #[diagnostic(
+        help("Each file can only be included once while it's already being included; move whatever both files need into a third file that each of them includes instead.")
+    )]
+    CyclicIncludeError {
+        path: String,
+
+        #[source_code]
+        src: Arc<str>,
+
+        #[label("This include leads back to a file that's already being included.")]
+        span: SourceSpan,
+    },
+
+    #[error("Couldn't include {path}, as this program wasn't read from a file.")]
+    #[diagnostic(
+        help("Includes are resolved relative to the file that they're in, so only programs read from a file can use them.")
+    )]
+    UnresolvedIncludeError {
+        path: String,
+
+        #[source_code]
+        src: Arc<str>,
+
+        #[label("Nothing to resolve this include against.")]
+        span: SourceSpan,
     },
 
+    #[error("Program is empty.")]
+    #[diagnostic(
+        help("A program needs at least a `def qmain() {{ ... }}` to run. Comments on their own don't count.")
+    )]
+    EmptyProgramError,
+
     #[error("No qmain function defined.")]
     #[diagnostic(
         help("Try adding `def qmain() {{ ... }}` to your program.")
     )]
     NoQMainError,
 
-    #[error("No definition for extern function found.")]
-    #[diagnostic()]
+    #[error("Could not pass argument to qmain: {message}")]
+    #[diagnostic(
+        help("Pass one --arg for each argument of qmain, written as a number, as true or false, or as text for a string.")
+    )]
+    QMainArgumentError {
+        message: String,
+
+        #[source_code]
+        src: Arc<str>,
+
+        #[label("qmain is declared here.")]
+        span: SourceSpan,
+    },
+
+    #[error("Function {name} was declared extern, but no implementation for it was provided.")]
+    #[diagnostic(
+        help("Extern functions have no body in Quantum Kaledioscope, so they need to be implemented by whatever runs the program, such as one of the interpreter's built-in functions.")
+    )]
     LinkingError {
         name: String,
 
         #[source_code]
-        src: String,
+        src: Arc<str>,
+
+        #[label("No implementation was found for this extern declaration.")]
+        span: SourceSpan
+    },
+
+    #[error("Constant {name} can't be computed without running the program.")]
+    #[diagnostic(
+        help("Constants are evaluated once before qmain runs, so they can only use literals, operators, and other constants. Try using a variable in qmain instead.")
+    )]
+    ImpureConstantError {
+        name: String,
+
+        #[source_code]
+        src: Arc<str>,
+
+        #[label("This {kind} isn't allowed in a constant.")]
+        span: SourceSpan,
+
+        kind: String,
+    },
+
+    #[error("Global {name} can't be initialized without running the program.")]
+    #[diagnostic(
+        help("Globals are initialized once before qmain runs, so their initial values can only use literals, operators, and constants. Try assigning to the global from qmain instead.")
+    )]
+    ImpureGlobalError {
+        name: String,
+
+        #[source_code]
+        src: Arc<str>,
 
-        #[label("No definition found for this extern declaration.")]
-        // TODO: Change to sourcespan
-        span: (usize, usize)
+        #[label("This {kind} isn't allowed in a global's initial value.")]
+        span: SourceSpan,
+
+        kind: String,
     },
 
     #[error("Mismatched types: expected {expected}, but got {actual}.")]
@@ -76,13 +162,16 @@ pub enum QKaledioscopeError {
         actual: String,
 
         #[source_code]
-        src: String,
+        src: Arc<str>,
 
         #[label("Expected this expression to evaluate to {expected}...")]
         expr_span: SourceSpan,
 
         #[label("...because of this type declaration.")]
-        type_span: SourceSpan,
+        type_span: Option<SourceSpan>,
+
+        #[help]
+        help: Option<String>,
     },
 
     #[error("No variable {name} has been defined.")]
@@ -91,10 +180,13 @@ pub enum QKaledioscopeError {
         name: String,
 
         #[source_code]
-        src: String,
+        src: Arc<str>,
 
         #[label("Referenced from here.")]
         span: SourceSpan,
+
+        #[help]
+        help: Option<String>,
     },
 
     #[error("No function {name} has been defined.")]
@@ -103,27 +195,228 @@ pub enum QKaledioscopeError {
         name: String,
 
         #[source_code]
-        src: String,
+        src: Arc<str>,
 
         #[label("Called from here.")]
         span: SourceSpan,
     },
 
+    #[error("Function {name} takes {expected} argument(s), but was called with {actual}.")]
+    #[diagnostic()]
+    ArityError {
+        name: String,
+        expected: usize,
+        actual: usize,
+
+        #[source_code]
+        src: Arc<str>,
+
+        #[label("Called with {actual} argument(s) here.")]
+        span: SourceSpan,
+    },
+
+    #[error("Function {name} finished without returning a value.")]
+    #[diagnostic(
+        help("Functions declared with a return type need to return a value on every path through their bodies.")
+    )]
+    MissingReturnError {
+        name: String,
+
+        #[source_code]
+        src: Arc<str>,
+
+        #[label("Declared to return {return_type} here.")]
+        span: SourceSpan,
+
+        return_type: String,
+    },
+
     #[error("Called function {name} as an expression, but {name} was declared without a return value.")]
     #[diagnostic()]
     VoidCallError {
         name: String,
 
         #[source_code]
-        src: String,
+        src: Arc<str>,
 
         #[label("Called from here...")]
         call_span: SourceSpan,
 
         #[label("...but no return type was defined here.")]
-        decl_span: SourceSpan,
+        decl_span: Option<SourceSpan>,
+    },
+
+    #[error("Compiled program was not valid LLVM IR:\n{message}")]
+    #[diagnostic(
+        help("This may be a function with a return type that doesn't return on every path, or a bug in the compiler.")
+    )]
+    CodegenError {
+        message: String,
+    },
+
+    #[error("Don't know what format to write {path} in.")]
+    #[diagnostic(
+        help("Compiled output can be written as textual IR (.ll), LLVM bitcode (.bc), or an object file (.o).")
+    )]
+    UnsupportedOutputFormatError {
+        path: String,
     },
 
+    #[error("Tuples can't be compiled yet.")]
+    #[diagnostic(
+        help("Programs that use tuples can still be run with the interpreter.")
+    )]
+    UnsupportedTupleError {
+        #[source_code]
+        src: Arc<str>,
+
+        #[label("This tuple can't be compiled.")]
+        span: SourceSpan,
+    },
+
+    #[error("Program needs {n_qubits} qubits, but at most {max_qubits} can be simulated.")]
+    #[diagnostic(
+        help("Simulating more qubits takes exponentially more memory. If you're sure, pass a larger --max-qubits.")
+    )]
+    TooManyQubitsError {
+        n_qubits: usize,
+        max_qubits: usize,
+
+        #[source_code]
+        src: Arc<str>,
+
+        #[label("This qubit is out of range.")]
+        span: SourceSpan,
+    },
+
+    #[error("Qubit %{index} is out of range, as only {n_qubits} qubits were allocated.")]
+    #[diagnostic()]
+    QubitIndexError {
+        index: usize,
+        n_qubits: usize,
+
+        #[source_code]
+        src: Arc<str>,
+
+        #[label("This qubit was never allocated.")]
+        span: SourceSpan,
+    },
+
+    #[error("Qubit %{index} was passed to {name} as both its control and its target.")]
+    #[diagnostic(
+        help("Controlled gates act on two different qubits, using one to decide what happens to the other.")
+    )]
+    QubitAliasingError {
+        name: String,
+        index: usize,
+
+        #[source_code]
+        src: Arc<str>,
+
+        #[label("Used as the control here...")]
+        control_span: SourceSpan,
+
+        #[label("...and as the target here.")]
+        target_span: SourceSpan,
+    },
+
+    #[error("Index {index} is out of range for a register of {size} bit(s).")]
+    #[diagnostic(
+        help("Registers are indexed by whole numbers, starting from 0.")
+    )]
+    RegisterIndexError {
+        index: f64,
+        size: usize,
+
+        #[source_code]
+        src: Arc<str>,
+
+        #[label("This index is out of range.")]
+        span: SourceSpan,
+    },
+
+    #[error("Function {name} isn't defined for {value}.")]
+    #[diagnostic(
+        help("{name} only accepts numbers that are zero or more.")
+    )]
+    DomainError {
+        name: String,
+        value: f64,
+
+        #[source_code]
+        src: Arc<str>,
+
+        #[label("This evaluated to {value}.")]
+        span: SourceSpan,
+    },
+
+    #[error("Assertion failed.")]
+    #[diagnostic(
+        help("The program asserted that this would be true when it ran, but it wasn't.")
+    )]
+    AssertionError {
+        #[source_code]
+        src: Arc<str>,
+
+        #[label("This evaluated to false.")]
+        span: SourceSpan,
+    },
+
+    #[error("Qubit %{index} is used after being measured.")]
+    #[diagnostic(
+        severity(Warning),
+        help("Measuring a qubit collapses its state, so it won't be in superposition anymore. If you meant to reuse the qubit, try resetting it first.")
+    )]
+    UseAfterMeasurementWarning {
+        index: usize,
+
+        #[source_code]
+        src: Arc<str>,
+
+        #[label("%{index} is measured here...")]
+        measurement_span: SourceSpan,
+
+        #[label("...and then used again here.")]
+        use_span: SourceSpan,
+    },
+
+    #[error("Unreachable code.")]
+    #[diagnostic(
+        severity(Warning),
+        help("Nothing after a return statement in the same block is ever run. If this code is still needed, try moving it before the return.")
+    )]
+    UnreachableCodeWarning {
+        #[source_code]
+        src: Arc<str>,
+
+        #[label("The function returns here...")]
+        return_span: SourceSpan,
+
+        #[label("...so this is never run.")]
+        span: SourceSpan,
+    },
+
+    #[error("{name} shadows the built-in constant of the same name.")]
+    #[diagnostic(
+        severity(Warning),
+        help("Everywhere that this declaration is visible, {name} will refer to it rather than to the built-in. If that's not what you meant, try a different name.")
+    )]
+    ShadowedConstantWarning {
+        name: String,
+
+        #[source_code]
+        src: Arc<str>,
+
+        #[label("{name} is declared here.")]
+        span: SourceSpan,
+    },
+
+    #[error("Source file is not formatted.")]
+    #[diagnostic(
+        help("Run fmt without --check to print the formatted source.")
+    )]
+    UnformattedSourceError,
+
     #[error(transparent)]
     #[diagnostic()]
     JsonError(#[from] serde_json::Error),
@@ -131,13 +424,60 @@ pub enum QKaledioscopeError {
 
 pub type Result<T> = std::result::Result<T, QKaledioscopeError>;
 
-pub(crate) fn wrong_rule_as_parse_error<S>(source: S, description: &str, span: Span, causes: Vec<QKaledioscopeError>) -> QKaledioscopeError
-where S: SourceCode + AsRef<str> + ToString
-{
+fn builtin_note(old_is_builtin: &bool) -> &'static str {
+    if *old_is_builtin {
+        ": a built-in function with that name already exists"
+    } else {
+        ""
+    }
+}
+
+/// Suggests which of `candidates` a misspelled name was meant to be, if any
+/// is only a few edits away from it.
+pub(crate) fn did_you_mean<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    // Allow about one typo for every three characters, so that short names
+    // don't match everything else that's short.
+    let max_distance = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| format!("Did you mean `{candidate}`?"))
+}
+
+/// The number of single-character insertions, deletions, and substitutions
+/// needed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Suggests how to write a literal of the expected type when a bit is used
+/// where a number is expected, or the other way around.
+pub(crate) fn literal_help(expected: &Type, actual: &Type) -> Option<String> {
+    match (expected, actual) {
+        (Type::Bit, Type::Number) => Some("Bits are written as `true` or `false`. To get a bit from a number, compare it, as in `x != 0.0`.".to_string()),
+        (Type::Number, Type::Bit) => Some("Numbers are written with digits, as in `1.0` or `0.0`.".to_string()),
+        _ => None
+    }
+}
+
+pub(crate) fn wrong_rule_as_parse_error(source: &Arc<str>, description: &str, span: Span, causes: Vec<QKaledioscopeError>) -> QKaledioscopeError {
     QKaledioscopeError::ParseError {
         description: description.to_string(),
         causes,
-        src: source.to_string(),
+        src: source.clone(),
         err_span: SourceSpan::new(
             SourceOffset::from(span.start()),
             SourceOffset::from(span.end() - span.start())
@@ -145,20 +485,126 @@ where S: SourceCode + AsRef<str> + ToString
     }
 }
 
-pub(crate) fn rule_error_as_parse_error<S, R>(source: S, error: pest::error::Error<R>) -> QKaledioscopeError
-where S: SourceCode + AsRef<str> + ToString,
-      R: std::fmt::Debug
-{
+/// Folds several errors into one so that they can all be reported at once,
+/// using the first error's `related` causes to hold the rest.
+pub(crate) fn combine_errors(mut errors: Vec<QKaledioscopeError>) -> QKaledioscopeError {
+    let first = errors.remove(0);
+    match first {
+        QKaledioscopeError::ParseError { description, src, err_span, mut causes } => {
+            causes.extend(errors);
+            QKaledioscopeError::ParseError { description, src, err_span, causes }
+        },
+        // Other kinds of errors don't have anywhere to keep related errors,
+        // so they get reported on their own.
+        other => other
+    }
+}
+
+/// Describes what a grammar rule matches in words, for use in syntax errors.
+fn describe_rule(rule: &Rule) -> &'static str {
+    match rule {
+        Rule::Ident => "an identifier",
+        Rule::number_literal | Rule::Number => "a number",
+        Rule::Integer => "a whole number",
+        Rule::qubit_literal => "a qubit, such as `%0`",
+        Rule::string_literal => "a string",
+        Rule::TrueKeyword => "`true`",
+        Rule::FalseKeyword => "`false`",
+        Rule::Semicolon => "a semicolon",
+        Rule::EOI => "the end of the file",
+        Rule::DoubleEquals => "`==`",
+        Rule::NotEquals => "`!=`",
+        Rule::LessEquals => "`<=`",
+        Rule::Less => "`<`",
+        Rule::GreaterEquals => "`>=`",
+        Rule::Greater => "`>`",
+        Rule::Plus => "`+`",
+        Rule::Minus => "`-`",
+        Rule::Star => "`*`",
+        Rule::Slash => "`/`",
+        Rule::NotOperator => "`not`",
+        Rule::MeasureKeyword => "`measure`",
+        Rule::BarrierKeyword => "`barrier`",
+        Rule::ReturnKeyword => "`return`",
+        Rule::include => "an include",
+        Rule::declaration => "an extern declaration",
+        Rule::definition => "a function definition",
+        Rule::constant => "a constant",
+        Rule::global => "a global variable",
+        Rule::doc_comment | Rule::doc_line => "a doc comment",
+        Rule::prototype => "a function signature",
+        Rule::arg_list => "a list of arguments",
+        Rule::arg_decl => "an argument",
+        Rule::return_decl => "a return type",
+        Rule::number_type | Rule::qubit_type | Rule::bit_type | Rule::register_type |
+            Rule::string_type | Rule::tuple_type => "a type",
+        Rule::repl_statement => "a statement",
+        Rule::return_stmt => "a return statement",
+        Rule::barrier_stmt => "a barrier",
+        Rule::if_stmt | Rule::if_block => "an if statement",
+        Rule::else_block => "an else block",
+        Rule::while_stmt => "a while loop",
+        Rule::variable_declaration => "a variable declaration",
+        Rule::tuple_pattern => "a tuple of names",
+        Rule::assignment | Rule::index_assignment => "an assignment",
+        Rule::repl_expression | Rule::binary_expr => "an expression",
+        Rule::not_expr => "a `not` expression",
+        Rule::measure_expr => "a measurement",
+        Rule::call_expr => "a function call",
+        Rule::tuple_expr => "a tuple",
+        Rule::register_expr => "a register",
+        Rule::index_expr => "an index into a register",
+        // Silent rules never show up in errors, as pest only reports the
+        // rules that make up the parse tree.
+        _ => "valid syntax",
+    }
+}
+
+/// Lists what each of several rules matches, as in "a, b, or c".
+fn describe_rules(rules: &[Rule]) -> String {
+    let mut descriptions: Vec<&str> = vec![];
+    for description in rules.iter().map(describe_rule) {
+        // Several rules can have the same description (e.g.: each kind of
+        // type), which only needs to be given once.
+        if !descriptions.contains(&description) {
+            descriptions.push(description);
+        }
+    }
+    match descriptions.as_slice() {
+        [] => String::new(),
+        [only] => only.to_string(),
+        [first, second] => format!("{first} or {second}"),
+        [rest @ .., last] => format!("{}, or {last}", rest.join(", ")),
+    }
+}
+
+/// Turns an error from pest into a syntax error that says, in words, what
+/// was expected where parsing failed.
+///
+/// ```
+/// use parser::QKaledioscopeError;
+///
+/// let result = parser::parse_program("
+///     extern print(s : string);
+///     def qmain() {
+///         print(\"hi\")
+///     }
+/// ");
+/// assert!(matches!(
+///     result,
+///     Err(QKaledioscopeError::ParseError { description, .. }) if description == "expected a semicolon"
+/// ));
+/// ```
+pub(crate) fn rule_error_as_parse_error(source: &Arc<str>, error: pest::error::Error<Rule>) -> QKaledioscopeError {
     let description = match error.variant {
         pest::error::ErrorVariant::ParsingError { negatives, positives } => {
-            // TODO: make this prettier
             match (negatives.is_empty(), positives.is_empty()) {
                 (false, false) => format!(
-                    "unexpected {:?}; expected {:?}",
-                    negatives, positives
+                    "unexpected {}; expected {}",
+                    describe_rules(&negatives), describe_rules(&positives)
                 ),
-                (false, true) => format!("unexpected {:?}", negatives),
-                (true, false) => format!("expected {:?}", positives),
+                (false, true) => format!("unexpected {}", describe_rules(&negatives)),
+                (true, false) => format!("expected {}", describe_rules(&positives)),
                 (true, true) => "unknown parsing error".to_owned(),
             }
         },
@@ -168,17 +614,17 @@ where S: SourceCode + AsRef<str> + ToString,
     let loc = error.line_col.clone();
     let span = match loc {
         LineColLocation::Pos(pos) =>
-            SourceSpan::new(SourceOffset::from_location(&source, pos.0, 1 + pos.1), SourceOffset::from(1)),
+            SourceSpan::new(SourceOffset::from_location(&**source, pos.0, 1 + pos.1), SourceOffset::from(1)),
         LineColLocation::Span(start, end) => {
-            let start = SourceOffset::from_location(&source, start.0, start.1);
-            let end = SourceOffset::from_location(&source, end.0, end.1);
+            let start = SourceOffset::from_location(&**source, start.0, start.1);
+            let end = SourceOffset::from_location(&**source, end.0, end.1);
             SourceSpan::new(start, SourceOffset::from(end.offset() - start.offset()))
         }
     };
     let err = QKaledioscopeError::ParseError {
         causes: vec![],
         description,
-        src: source.to_string(),
+        src: source.clone(),
         err_span: span
     };
     err
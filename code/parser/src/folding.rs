@@ -0,0 +1,70 @@
+use std::sync::Arc;
+
+use crate::{ast::{Program, Expression, Located, VisitorMut, walk_expression_mut}, interpreter::{FunctionTable, InterpreterValue, LocalSymbolTable, RunOptions}};
+
+// NB: Folding reuses the interpreter to evaluate expressions, so that folded
+//     programs behave exactly as they would have otherwise. Expressions that
+//     would fail to evaluate (e.g.: adding a bit to a number) are left alone,
+//     so that the error is still raised with the right span when the program
+//     is run or compiled.
+
+/// Replaces each operator in a program whose operands are all literals with
+/// the literal it evaluates to. Expressions that use variables, constants,
+/// or function calls are left as they are, though any parts of them that
+/// only use literals are still folded.
+///
+/// ```
+/// use std::sync::Arc;
+/// let source: Arc<str> = "
+///     extern f() -> number;
+///     def qmain() {
+///         var x: number = 1.0 + 2.0 * 3.0;
+///         var y: bit = not (x < 2.0 - 1.0);
+///         var z: number = f() + 4.0 / 2.0;
+///     }
+/// ".into();
+/// let mut program = parser::parse_program(&source).unwrap();
+/// parser::folding::fold_constants(&source, &mut program);
+/// let folded = program.to_string();
+/// assert!(folded.contains("var x: number = 7.0;"));
+/// assert!(folded.contains("var y: bit = not (x < 1.0);"));
+/// assert!(folded.contains("var z: number = f() + 2.0;"));
+/// ```
+pub fn fold_constants(source: &Arc<str>, program: &mut Program) {
+    ConstantFolder { source }.visit_program_mut(program);
+}
+
+struct ConstantFolder<'a> {
+    source: &'a Arc<str>,
+}
+
+fn is_literal(expr: &Located<Expression>) -> bool {
+    matches!(expr.value, Expression::NumberLiteral(_) | Expression::BitLiteral(_))
+}
+
+impl VisitorMut for ConstantFolder<'_> {
+    fn visit_expression_mut(&mut self, expr: &mut Located<Expression>) {
+        // Fold from the bottom up, so that each operator only needs to check
+        // whether its own operands have become literals. Anything else, such
+        // as a tuple, can't be written as a single literal, but any parts
+        // of it can still be folded.
+        walk_expression_mut(self, expr);
+        let foldable = match &expr.value {
+            Expression::BinaryOp(lhs, _, rhs) => is_literal(lhs) && is_literal(rhs),
+            Expression::ChainedComparison(first, rest) =>
+                is_literal(first) && rest.iter().all(|(_, operand)| is_literal(operand)),
+            Expression::Not(operand) => is_literal(operand),
+            _ => false
+        };
+        if !foldable {
+            return;
+        }
+
+        let table = FunctionTable::new(RunOptions::default());
+        match expr.eval_in(self.source, &table, &mut LocalSymbolTable::new()) {
+            Ok(InterpreterValue::Number(num)) => expr.value = Expression::NumberLiteral(num),
+            Ok(InterpreterValue::Bit(bit)) => expr.value = Expression::BitLiteral(bit),
+            _ => ()
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use std::path::PathBuf;
+
+use crate::{ast_builder::build_unexpanded_ast, error::QKaledioscopeError};
+
+// NB: Formatting works by building an AST and printing it back out using the
+//     Display impls in the ast module, so anything that isn't part of the AST
+//     is lost along the way.
+//     Includes are kept as they're written, rather than being replaced with
+//     what they include, so that each file is formatted on its own.
+// TODO: Comments aren't part of the AST yet, and so aren't preserved.
+
+pub fn run_format_cmd(source_file: PathBuf, check: bool) -> miette::Result<()> {
+    let (program, source) = build_unexpanded_ast(source_file)?;
+    let formatted = program.to_string();
+
+    if check {
+        if formatted != *source {
+            return Err(QKaledioscopeError::UnformattedSourceError.into());
+        }
+    } else {
+        print!("{formatted}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,250 @@
+use crate::ast::{Program, FileElement, Statement, Expression, Located};
+
+/// Escapes a string for use inside a double-quoted Graphviz DOT label.
+pub fn escape_label(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Builds up the nodes and edges of a DOT graph, giving each node a unique
+/// ID as it's added.
+#[derive(Default)]
+pub struct DotWriter {
+    lines: Vec<String>,
+    n_nodes: usize,
+}
+
+impl DotWriter {
+    pub fn node(&mut self, label: &str) -> usize {
+        let id = self.n_nodes;
+        self.n_nodes += 1;
+        self.lines.push(format!("    n{id} [label=\"{}\"];", escape_label(label)));
+        id
+    }
+
+    pub fn edge(&mut self, from: usize, to: usize, label: Option<&str>) {
+        self.lines.push(match label {
+            Some(label) => format!("    n{from} -> n{to} [label=\"{}\"];", escape_label(label)),
+            None => format!("    n{from} -> n{to};"),
+        });
+    }
+
+    pub fn finish(self, name: &str) -> String {
+        let mut dot = format!("digraph {name} {{\n    node [shape=box];\n");
+        for line in self.lines {
+            dot.push_str(&line);
+            dot.push('\n');
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Renders the AST for a program as a Graphviz DOT graph, with a node for
+/// each file element, statement, and expression, labeled with its kind and
+/// span. Since the AST is a tree, there's one fewer edge than there are
+/// nodes:
+///
+/// ```
+/// let program = parser::parse_program("
+///     extern h(q : qubit);
+///     def qmain() -> bit {
+///         h(%0);
+///         return measure %0;
+///     }
+/// ").unwrap();
+/// let dot = parser::graphviz::program_to_dot(&program);
+/// let edges = dot.lines().filter(|line| line.contains(" -> ")).count();
+/// let nodes = dot.lines().filter(|line| line.contains("[label=") && !line.contains(" -> ")).count();
+/// // Program, the extern, qmain, the call to h and its argument, and the
+/// // return with its measurement of %0.
+/// assert_eq!(nodes, 8);
+/// assert_eq!(edges, 7);
+/// assert!(dot.contains("label=\"Call h\\n"));
+/// ```
+pub fn program_to_dot(program: &Program) -> String {
+    let mut writer = DotWriter::default();
+    let root = writer.node("Program");
+    for element in &program.0 {
+        let kind = match &element.value {
+            FileElement::Declaration(prototype) => format!("Declaration {}", prototype.value),
+            FileElement::Definition { prototype, .. } => format!("Definition {}", prototype.value),
+            FileElement::Constant { name, type_sig, .. } => format!("Constant {}: {}", name.value, type_sig.value),
+            FileElement::Global { name, type_sig, .. } => format!("Global {}: {}", name.value, type_sig.value),
+            FileElement::Include(path) => format!("Include {}", Expression::StringLiteral(path.clone())),
+        };
+        let id = located_node(&mut writer, &kind, element);
+        writer.edge(root, id, None);
+        match &element.value {
+            FileElement::Definition { body, .. } => body_to_dot(&mut writer, id, body, None),
+            FileElement::Constant { expr, .. } | FileElement::Global { expr, .. } => {
+                let expr_id = expression_to_dot(&mut writer, expr);
+                writer.edge(id, expr_id, None);
+            },
+            FileElement::Declaration(_) | FileElement::Include(_) => (),
+        }
+    }
+    writer.finish("ast")
+}
+
+fn located_node<T: std::fmt::Debug>(writer: &mut DotWriter, kind: &str, node: &Located<T>) -> usize {
+    let (start, end) = node.location;
+    writer.node(&format!("{kind}\n{start}..{end}"))
+}
+
+fn body_to_dot(writer: &mut DotWriter, parent: usize, body: &[Located<Statement>], label: Option<&str>) {
+    for statement in body {
+        let id = statement_to_dot(writer, statement);
+        writer.edge(parent, id, label);
+    }
+}
+
+fn statement_to_dot(writer: &mut DotWriter, statement: &Located<Statement>) -> usize {
+    match &statement.value {
+        Statement::VariableDeclaration(ident, type_sig, expr) => {
+            let label = match type_sig {
+                Some(type_sig) => format!("VariableDeclaration {}: {}", ident.value, type_sig.value),
+                None => format!("VariableDeclaration {}", ident.value),
+            };
+            let id = located_node(writer, &label, statement);
+            let expr_id = expression_to_dot(writer, expr);
+            writer.edge(id, expr_id, None);
+            id
+        },
+        Statement::TupleDeclaration(idents, type_sig, expr) => {
+            let idents = idents.iter().map(|ident| ident.value.to_string()).collect::<Vec<_>>().join(", ");
+            let label = match type_sig {
+                Some(type_sig) => format!("TupleDeclaration ({idents}): {}", type_sig.value),
+                None => format!("TupleDeclaration ({idents})"),
+            };
+            let id = located_node(writer, &label, statement);
+            let expr_id = expression_to_dot(writer, expr);
+            writer.edge(id, expr_id, None);
+            id
+        },
+        Statement::Assignment(ident, expr) => {
+            let id = located_node(writer, &format!("Assignment {}", ident.value), statement);
+            let expr_id = expression_to_dot(writer, expr);
+            writer.edge(id, expr_id, None);
+            id
+        },
+        Statement::IndexAssignment(ident, index, expr) => {
+            let id = located_node(writer, &format!("IndexAssignment {}", ident.value), statement);
+            let index_id = expression_to_dot(writer, index);
+            writer.edge(id, index_id, Some("index"));
+            let expr_id = expression_to_dot(writer, expr);
+            writer.edge(id, expr_id, Some("value"));
+            id
+        },
+        Statement::Call(ident, arguments) => {
+            let id = located_node(writer, &format!("Call {}", ident.value), statement);
+            for argument in arguments {
+                let arg_id = expression_to_dot(writer, argument);
+                writer.edge(id, arg_id, None);
+            }
+            id
+        },
+        Statement::If { condition, true_body, false_body } => {
+            let id = located_node(writer, "If", statement);
+            let condition_id = expression_to_dot(writer, condition);
+            writer.edge(id, condition_id, Some("condition"));
+            body_to_dot(writer, id, true_body, Some("then"));
+            body_to_dot(writer, id, false_body, Some("else"));
+            id
+        },
+        Statement::While { condition, body } => {
+            let id = located_node(writer, "While", statement);
+            let condition_id = expression_to_dot(writer, condition);
+            writer.edge(id, condition_id, Some("condition"));
+            body_to_dot(writer, id, body, Some("body"));
+            id
+        },
+        Statement::Barrier(qubits) => {
+            let id = located_node(writer, "Barrier", statement);
+            for qubit in qubits {
+                let qubit_id = expression_to_dot(writer, qubit);
+                writer.edge(id, qubit_id, None);
+            }
+            id
+        },
+        Statement::Return(expr) => {
+            let id = located_node(writer, "Return", statement);
+            if let Some(expr) = expr {
+                let expr_id = expression_to_dot(writer, expr);
+                writer.edge(id, expr_id, None);
+            }
+            id
+        },
+    }
+}
+
+fn expression_to_dot(writer: &mut DotWriter, expr: &Located<Expression>) -> usize {
+    match &expr.value {
+        Expression::Call(ident, arguments) => {
+            let id = located_node(writer, &format!("Call {}", ident.value), expr);
+            for argument in arguments {
+                let arg_id = expression_to_dot(writer, argument);
+                writer.edge(id, arg_id, None);
+            }
+            id
+        },
+        Expression::BinaryOp(lhs, op, rhs) => {
+            let id = located_node(writer, &format!("BinaryOp {}", op.value), expr);
+            let lhs_id = expression_to_dot(writer, lhs);
+            writer.edge(id, lhs_id, Some("lhs"));
+            let rhs_id = expression_to_dot(writer, rhs);
+            writer.edge(id, rhs_id, Some("rhs"));
+            id
+        },
+        Expression::ChainedComparison(first, rest) => {
+            let ops = rest.iter().map(|(op, _)| op.value.to_string()).collect::<Vec<_>>().join(" ");
+            let id = located_node(writer, &format!("ChainedComparison {ops}"), expr);
+            let first_id = expression_to_dot(writer, first);
+            writer.edge(id, first_id, None);
+            for (_, operand) in rest {
+                let operand_id = expression_to_dot(writer, operand);
+                writer.edge(id, operand_id, None);
+            }
+            id
+        },
+        Expression::Not(operand) => {
+            let id = located_node(writer, "Not", expr);
+            let operand_id = expression_to_dot(writer, operand);
+            writer.edge(id, operand_id, None);
+            id
+        },
+        Expression::Tuple(elements) => {
+            let id = located_node(writer, "Tuple", expr);
+            for element in elements {
+                let element_id = expression_to_dot(writer, element);
+                writer.edge(id, element_id, None);
+            }
+            id
+        },
+        Expression::Register(elements) => {
+            let id = located_node(writer, "Register", expr);
+            for element in elements {
+                let element_id = expression_to_dot(writer, element);
+                writer.edge(id, element_id, None);
+            }
+            id
+        },
+        Expression::Index(ident, index) => {
+            let id = located_node(writer, &format!("Index {}", ident.value), expr);
+            let index_id = expression_to_dot(writer, index);
+            writer.edge(id, index_id, None);
+            id
+        },
+        Expression::Measure(operand) => {
+            let id = located_node(writer, "Measure", expr);
+            let operand_id = expression_to_dot(writer, operand);
+            writer.edge(id, operand_id, None);
+            id
+        },
+        // Leaves are labeled with how they'd be written in source.
+        Expression::Identifier(_) => located_node(writer, &format!("Identifier {}", expr.value), expr),
+        Expression::QubitLiteral(_) => located_node(writer, &format!("QubitLiteral {}", expr.value), expr),
+        Expression::NumberLiteral(_) => located_node(writer, &format!("NumberLiteral {}", expr.value), expr),
+        Expression::BitLiteral(_) => located_node(writer, &format!("BitLiteral {}", expr.value), expr),
+        Expression::StringLiteral(_) => located_node(writer, &format!("StringLiteral {}", expr.value), expr),
+    }
+}
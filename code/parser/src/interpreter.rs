@@ -1,308 +1,2571 @@
-use std::{collections::HashMap, cell::RefCell, path::PathBuf, fs};
+use std::{collections::{BTreeMap, HashMap}, cell::{Cell, RefCell}, path::PathBuf, sync::Arc};
 
-use pest::Parser;
+use miette::SourceSpan;
+use serde::Serialize;
 use qqs::{QuantumSim, sparsestate::SparseState, common_matrices};
 
-use crate::{ast::{Program, FileElement, Statement, Expression, Identifier, Located, Type}, error::{QKaledioscopeError, Result, rule_error_as_parse_error}, parser::{QKaledioscopeParser, Rule}, ast_builder::TryParse};
+use crate::{ast::{Program, FileElement, Prototype, ArgumentDeclaration, Statement, Expression, Identifier, Located, Type, BinaryOperator, Visitor, walk_body, walk_expression}, error::{QKaledioscopeError, Result, did_you_mean, literal_help}, ast_builder::{build_ast_timed, load_ast, parse_shared}, checker, folding, scope, util::PhaseTimer};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Serialize)]
 pub enum InterpreterValue {
     QubitRef(usize),
     Number(f64),
     Bit(bool),
+    /// Refers to one of the built-in single-qubit gates.
+    Gate(Gate),
+    Str(String),
+    Tuple(Vec<InterpreterValue>),
+    /// The bits held by a classical register, starting from index 0.
+    Register(Vec<bool>),
+    /// What calls to functions without a return type evaluate to. Calls
+    /// made as statements can discard any value, but only calls that
+    /// evaluate to something else can be used as expressions.
+    ///
+    /// ```
+    /// use parser::interpreter::{run_program, InterpreterValue, RunOptions};
+    /// use parser::QKaledioscopeError;
+    ///
+    /// let results = run_program("
+    ///     def answer() -> number {
+    ///         return 42.0;
+    ///     }
+    ///     def qmain() -> number {
+    ///         answer();
+    ///         return answer();
+    ///     }
+    /// ", RunOptions::default()).unwrap();
+    /// assert!(matches!(results.return_value, Some(InterpreterValue::Number(n)) if n == 42.0));
+    ///
+    /// let result = run_program("
+    ///     def nothing() {}
+    ///     def qmain() -> number {
+    ///         var x: number = nothing();
+    ///         return x;
+    ///     }
+    /// ", RunOptions::default());
+    /// assert!(matches!(result, Err(QKaledioscopeError::VoidCallError { name, .. }) if name == "nothing"));
+    ///
+    /// // Such calls are rejected without calling the function at all.
+    /// use std::sync::Arc;
+    /// use parser::ast::Located;
+    /// use parser::interpreter::{Builtin, BuiltinFn};
+    /// let source: Arc<str> = "
+    ///     extern launch();
+    ///     def qmain() -> number {
+    ///         var x: number = launch();
+    ///         return x;
+    ///     }
+    /// ".into();
+    /// let program = parser::parse_program(&source).unwrap();
+    /// let launch = Builtin { name: "launch", arguments: vec![], return_type: None };
+    /// let launch_fn: Box<BuiltinFn> = Box::new(|_: &Arc<str>, _: &[Located<InterpreterValue>]| {
+    ///     panic!("launch() shouldn't be called for its value.")
+    /// });
+    /// let result = program.run_with(&source, RunOptions::default(), &[], vec![(launch, launch_fn)]);
+    /// assert!(matches!(result, Err(QKaledioscopeError::VoidCallError { name, .. }) if name == "launch"));
+    /// ```
+    Unit,
 }
 
-pub type LocalSymbolTable = HashMap<Identifier, InterpreterValue>;
+impl InterpreterValue {
+    pub fn get_type(&self) -> Type {
+        match self {
+            InterpreterValue::Bit(_) => Type::Bit,
+            InterpreterValue::Number(_) => Type::Number,
+            InterpreterValue::QubitRef(_) => Type::Qubit,
+            InterpreterValue::Gate(_) => Type::Gate,
+            InterpreterValue::Str(_) => Type::String,
+            InterpreterValue::Tuple(values) => Type::Tuple(values.iter().map(InterpreterValue::get_type).collect()),
+            InterpreterValue::Register(bits) => Type::Register(bits.len()),
+            InterpreterValue::Unit => Type::Unit,
+        }
+    }
+}
 
-pub enum FunctionTableEntry<'a> {
-    Interpreted(&'a Located<FileElement>),
-    Builtin(&'a dyn Fn(&[InterpreterValue]) -> Result<Option<InterpreterValue>>),
+/// The variables visible to the statement being run, kept as a stack of
+/// scopes. Each body (of an `if`, a `while`, or a function) gets its own
+/// scope, so that variables declared in it are forgotten once it finishes.
+///
+/// The scope checker already rejects programs that use a variable outside
+/// of the block it was declared in, but the interpreter enforces the same
+/// rule when running programs that haven't been checked:
+///
+/// ```
+/// use std::sync::Arc;
+/// use parser::{interpreter::RunOptions, QKaledioscopeError};
+///
+/// let source: Arc<str> = "
+///     def qmain() -> number {
+///         if true {
+///             var x: number = 1.0;
+///         }
+///         return x;
+///     }
+/// ".into();
+/// let program = parser::parse_program(&source).unwrap();
+/// let result = program.run(&source, RunOptions::default(), &[]);
+/// assert!(matches!(result, Err(QKaledioscopeError::UndefinedVariableError { name, .. }) if name == "x"));
+/// ```
+#[derive(Debug)]
+pub struct LocalSymbolTable {
+    scopes: Vec<HashMap<Identifier, InterpreterValue>>,
 }
 
-pub struct FunctionTable<'a> {
-    // TODO: Use a better type than FileElement here.
-    fns: HashMap<Identifier, FunctionTableEntry<'a>>,
+impl LocalSymbolTable {
+    pub fn new() -> Self {
+        LocalSymbolTable { scopes: vec![HashMap::new()] }
+    }
+
+    /// Looks up a variable, starting from the innermost scope and working
+    /// outward.
+    pub fn get(&self, ident: &Identifier) -> Option<&InterpreterValue> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(ident))
+    }
+
+    pub fn get_mut(&mut self, ident: &Identifier) -> Option<&mut InterpreterValue> {
+        self.scopes.iter_mut().rev().find_map(|scope| scope.get_mut(ident))
+    }
+
+    /// Declares a variable in the innermost scope, shadowing any variable of
+    /// the same name in an enclosing scope.
+    pub fn insert(&mut self, ident: Identifier, value: InterpreterValue) {
+        self.scopes
+            .last_mut()
+            .expect("There should always be at least one scope.")
+            .insert(ident, value);
+    }
+
+    /// Lists the name of every variable in scope.
+    fn names(&self) -> impl Iterator<Item = &Identifier> {
+        self.scopes.iter().flat_map(|scope| scope.keys())
+    }
+
+    fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn pop_scope(&mut self) {
+        self.scopes.pop();
+    }
 }
-impl<'a> FunctionTable<'a> {
-    pub fn register_builtin<F: 'a + Fn(&[InterpreterValue]) -> Result<Option<InterpreterValue>>>(&mut self, ident: &Identifier, f: &'a F) {
-        // TODO: Check if it's already registered, and throw.
-        self.fns.insert(ident.clone(), FunctionTableEntry::Builtin(f));
+
+impl Default for LocalSymbolTable {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    pub fn build(source: &str, value: &'a Program) -> Result<Self> {
-        let mut fns = HashMap::new();
-        for element in &value.0 {
-            let ident = &match &element.value {
-                FileElement::Declaration(prototype) => prototype,
-                FileElement::Definition { prototype, body: _ } => prototype
-            }.value.name;
-            let entry = FunctionTableEntry::Interpreted(element);
-            if let Some(existing) = fns.insert(ident.value.clone(), entry) {
-                // TODO: Move into util.
-                // TODO: Grab only the span for the prototype.
-                let (new_start, new_end) = element.location.unwrap();
-                let (old_start, old_end) = match existing {
-                    FunctionTableEntry::Interpreted(file_element) =>
-                        file_element.location.unwrap(),
-                    _ => todo!("Spans not yet implemented for built-in.")
-                };
-                return Err(QKaledioscopeError::DuplicateNameError {
-                    src: source.to_string(),
-                    name: ident.value.0.clone(),
-                    // FIXME: Don't unwrap here!
-                    new_span: (new_start, new_end - new_start),
-                    old_span: (old_start, old_end - old_start),
-                })
-            }
+/// The formats that the results of running a program can be printed in.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Prints output from the program as it runs, followed by a histogram
+    /// of measurement results when running more than once.
+    Text,
+    /// Prints a single JSON object with the measurement results from each
+    /// run once the program has finished. Output from the program itself
+    /// is sent to stderr instead, so that stdout only contains JSON.
+    Json,
+}
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Text
+    }
+}
+
+/// The most qubits that programs can use unless told otherwise, chosen so
+/// that the full state of every qubit fits comfortably in memory.
+pub const DEFAULT_MAX_QUBITS: usize = 24;
+
+/// Options controlling how the interpreter runs a program.
+#[derive(Debug, Clone, Copy)]
+pub struct RunOptions {
+    /// How much to trace to stderr while running: at 1, each built-in call
+    /// and qubit allocation is traced, and at 2, so is each change to a
+    /// variable.
+    pub verbosity: u64,
+    pub output_format: OutputFormat,
+    pub simulator: SimulatorKind,
+    /// Whether to print output from the program as it runs. Output is
+    /// always captured in the `RunResults` for each run either way.
+    pub print_output: bool,
+    /// The most qubits that a program may use before running it is refused.
+    pub max_qubits: usize,
+    /// Whether the interpret command folds constant expressions in the
+    /// program before running it.
+    pub fold_constants: bool,
+    /// Whether the interpret command reports how long each phase takes, and
+    /// how many gates and measurements the simulator carried out.
+    pub time: bool,
+    /// Which end of printed strings of measurement results the first
+    /// result goes at.
+    pub bit_order: BitOrder,
+    /// Whether the interpret command reports how many times each gate was
+    /// applied, along with the depth of the circuit that was run.
+    pub stats: bool,
+}
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            verbosity: 0,
+            output_format: OutputFormat::default(),
+            simulator: SimulatorKind::default(),
+            print_output: false,
+            max_qubits: DEFAULT_MAX_QUBITS,
+            fold_constants: false,
+            time: false,
+            bit_order: BitOrder::default(),
+            stats: false,
         }
-        Ok(FunctionTable { fns })
     }
 }
+impl RunOptions {
+    /// Prints a trace message to stderr if running at `level` or higher.
+    pub fn trace(&self, level: u64, message: std::fmt::Arguments) {
+        if self.verbosity >= level {
+            eprintln!("{message}");
+        }
+    }
 
-// TODO: n_qubits_required currently takes the max over all qubit literals, but
-//       could use a map onto IDs instead.
-impl Expression {
-    fn n_qubits_required(&self) -> usize {
-        match self {
-            Expression::BitLiteral(_) => 0,
-            Expression::NumberLiteral(_) => 0,
-            Expression::Identifier(_) => 0,
-            Expression::QubitLiteral(idx) => *idx,
-            Expression::Call(_, arguments) =>
-                arguments.iter().fold(0, |acc, expr| {
-                    std::cmp::max(acc, expr.value.n_qubits_required())
-                })
+    /// Prints output from the program being run if asked to, keeping it
+    /// out of stdout if stdout is reserved for JSON.
+    pub fn output(&self, message: std::fmt::Arguments) {
+        match (self.print_output, self.output_format) {
+            (false, _) => (),
+            (true, OutputFormat::Text) => println!("{message}"),
+            (true, OutputFormat::Json) => eprintln!("{message}"),
         }
     }
 }
 
-impl Program {
-    fn n_qubits_required(&self) -> usize {
-        self.0.iter().fold(0, |acc, element| {
-            std::cmp::max(acc, match &element.value {
-                FileElement::Declaration(_) => 0,
-                FileElement::Definition { prototype: _, body } => {
-                    body.iter().fold(0, |acc, stmt| {
-                        std::cmp::max(acc, match &stmt.value {
-                            Statement::Assignment(_, expr) => expr.value.n_qubits_required(),
-                            Statement::Call(_, arguments) => 
-                                arguments.iter().fold(0, |acc, expr| {
-                                    std::cmp::max(acc, expr.value.n_qubits_required())
-                                }),
-                            // TODO: Scan in other statement types as well!
-                            _ => 0
-                        })
-                    })
-                }
-            })
+/// What a program did over the course of a single run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunResults {
+    /// The result of each measurement, in the order they were made.
+    pub measurements: Vec<bool>,
+    /// Each line of output from the program's print built-ins.
+    pub output: Vec<String>,
+    /// The value returned by `qmain`, if it returns anything.
+    pub return_value: Option<InterpreterValue>,
+    /// How many gates the simulator applied, including global phases.
+    pub n_gates: usize,
+    /// How many measurements the simulator made, including those made to
+    /// reset qubits.
+    pub n_measurements: usize,
+    /// How many times each gate was applied, keyed by the gate's name with a
+    /// `c` in front for each control (e.g.: `cnot` is counted as `cx`).
+    pub gate_counts: BTreeMap<String, usize>,
+    /// How many layers the gates that were applied would take up as a
+    /// circuit, if each gate went in the first layer after every earlier
+    /// gate on any of the same qubits. Measurements and global phases
+    /// don't take up a layer.
+    ///
+    /// ```
+    /// use parser::interpreter::{run_program, RunOptions};
+    ///
+    /// let results = run_program("
+    ///     def qmain() {
+    ///         h(%0);
+    ///         cnot(%0, %1);
+    ///         x(%1);
+    ///     }
+    /// ", RunOptions::default()).unwrap();
+    /// assert_eq!(results.depth, 3);
+    /// assert_eq!(results.gate_counts["h"], 1);
+    /// assert_eq!(results.gate_counts["cx"], 1);
+    /// assert_eq!(results.gate_counts["x"], 1);
+    /// ```
+    pub depth: usize,
+}
+impl RunResults {
+    /// Records a line of output from the program, printing it as well if
+    /// `options` ask for that.
+    fn output(&mut self, options: RunOptions, line: String) {
+        options.output(format_args!("{line}"));
+        self.output.push(line);
+    }
+}
+
+/// Describes the signature of a function provided by the interpreter itself,
+/// rather than defined in Quantum Kaledioscope source.
+#[derive(Debug, Clone)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub arguments: Vec<Type>,
+    pub return_type: Option<Type>,
+}
+impl std::fmt::Display for Builtin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let arguments = self.arguments.iter().map(|ty| ty.to_string()).collect::<Vec<_>>();
+        write!(f, "{}({})", self.name, arguments.join(", "))?;
+        if let Some(return_type) = &self.return_type {
+            write!(f, " -> {return_type}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the signature of each function provided by the interpreter.
+///
+/// Besides gates and output, these include a few math functions on numbers,
+/// which are handy when working out rotation angles:
+///
+/// ```
+/// parser::interpret_str("
+///     extern abs(x : number) -> number;
+///     extern cos(x : number) -> number;
+///     extern assert(b : bit);
+///     def qmain() {
+///         assert(abs(cos(0.0) - 1.0) < 0.000001);
+///     }
+/// ").unwrap();
+/// ```
+///
+/// `measure_all` measures every qubit the program uses, in order, and
+/// returns the results as a register with a bit for each of them:
+///
+/// ```
+/// use parser::interpreter::{run_program, InterpreterValue, RunOptions};
+///
+/// let results = run_program("
+///     def qmain() -> bit[3] {
+///         x(%2);
+///         return measure_all();
+///     }
+/// ", RunOptions::default()).unwrap();
+/// assert!(matches!(
+///     results.return_value,
+///     Some(InterpreterValue::Register(bits)) if bits == vec![false, false, true]
+/// ));
+/// ```
+pub fn builtins() -> Vec<Builtin> {
+    let builtin = |name, arguments, return_type| Builtin { name, arguments, return_type };
+    vec![
+        builtin("print_n", vec![Type::Number], None),
+        builtin("print_b", vec![Type::Bit], None),
+        builtin("print_q", vec![Type::Qubit], None),
+        builtin("print", vec![Type::String], None),
+        builtin("h", vec![Type::Qubit], None),
+        builtin("x", vec![Type::Qubit], None),
+        builtin("y", vec![Type::Qubit], None),
+        builtin("z", vec![Type::Qubit], None),
+        builtin("s", vec![Type::Qubit], None),
+        builtin("sdg", vec![Type::Qubit], None),
+        builtin("t", vec![Type::Qubit], None),
+        builtin("tdg", vec![Type::Qubit], None),
+        builtin("cnot", vec![Type::Qubit, Type::Qubit], None),
+        builtin("controlled", vec![Type::Gate, Type::Qubit, Type::Qubit], None),
+        builtin("m", vec![Type::Qubit], Some(Type::Bit)),
+        builtin("gphase", vec![Type::Number], None),
+        // measure_all returns a bit for each qubit the program uses, which
+        // register_builtins fills in once it's known.
+        builtin("measure_all", vec![], Some(Type::Register(0))),
+        builtin("assert", vec![Type::Bit], None),
+        builtin("abs", vec![Type::Number], Some(Type::Number)),
+        builtin("sqrt", vec![Type::Number], Some(Type::Number)),
+        builtin("sin", vec![Type::Number], Some(Type::Number)),
+        builtin("cos", vec![Type::Number], Some(Type::Number)),
+        builtin("exp", vec![Type::Number], Some(Type::Number)),
+    ]
+}
+
+/// Numbers that can be referred to by name without being declared first.
+/// Variables and constants with the same name take precedence.
+///
+/// ```
+/// parser::interpret_str("
+///     extern abs(x : number) -> number;
+///     extern cos(x : number) -> number;
+///     extern assert(b : bit);
+///     def qmain() {
+///         assert(abs(cos(pi) + 1.0) < 0.000001);
+///     }
+/// ").unwrap();
+/// ```
+pub const BUILTIN_CONSTANTS: &[(&str, f64)] = &[("pi", std::f64::consts::PI)];
+
+/// Looks up the value of one of the `BUILTIN_CONSTANTS` by name.
+pub(crate) fn builtin_constant(name: &str) -> Option<f64> {
+    BUILTIN_CONSTANTS
+        .iter()
+        .find(|(constant, _)| *constant == name)
+        .map(|(_, value)| *value)
+}
+
+/// The built-in gates that act on a single qubit, and which can be referred
+/// to by name as values (e.g.: to pass to `controlled`).
+pub const SINGLE_QUBIT_GATES: &[&str] = &["h", "x", "y", "z", "s", "sdg", "t", "tdg"];
+
+/// One of the built-in gates that act on a single qubit, as applied by a
+/// `Simulator`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Gate {
+    H,
+    X,
+    Y,
+    Z,
+    S,
+    Sdg,
+    T,
+    Tdg,
+}
+impl Gate {
+    /// Looks up a gate by the name that programs call it by, which is one
+    /// of those in `SINGLE_QUBIT_GATES`.
+    pub fn from_name(name: &str) -> Option<Gate> {
+        Some(match name {
+            "h" => Gate::H,
+            "x" => Gate::X,
+            "y" => Gate::Y,
+            "z" => Gate::Z,
+            "s" => Gate::S,
+            "sdg" => Gate::Sdg,
+            "t" => Gate::T,
+            "tdg" => Gate::Tdg,
+            _ => return None,
         })
     }
 
-    // TODO: Generalize over simulators with a new trait.
-    pub fn run(&self, source: &str) -> Result<()> {
-        let sim = RefCell::new(QuantumSim::<SparseState>::new());
-        let n_qubits = self.n_qubits_required();
-        let n_qubits = 6usize; // FIXME: Don't hard code this.
-        println!("Using {n_qubits} qubits...");
-        let qubit_ids = (0..n_qubits).map(|_| sim.borrow_mut().allocate()).collect::<Vec<_>>();
-        println!("qubit_ids = {qubit_ids:?}");
-        let mut table = FunctionTable::build(source, self)?;
+    /// The name that programs call this gate by.
+    pub fn name(self) -> &'static str {
+        match self {
+            Gate::H => "h",
+            Gate::X => "x",
+            Gate::Y => "y",
+            Gate::Z => "z",
+            Gate::S => "s",
+            Gate::Sdg => "sdg",
+            Gate::T => "t",
+            Gate::Tdg => "tdg",
+        }
+    }
 
-        let mk_print = || |args: &[InterpreterValue]| {
-            // TODO: Check types and arity here.
-            println!("→ {:?}", args[0]);
-            Ok(None)
-        };
-        let print_n = mk_print();
-        table.register_builtin(&Identifier("print_n".to_string()), &print_n);
-        let print_b = mk_print();
-        table.register_builtin(&Identifier("print_b".to_string()), &print_b);
-        let print_q = mk_print();
-        table.register_builtin(&Identifier("print_q".to_string()), &print_q);
-
-        let h = |args: &[InterpreterValue]| {
-            // TODO: Check types and arity here instead of just unpacking...
-            match args[0] {
+    /// The gate's matrix, shared by every simulator so that they all agree
+    /// on what each gate does.
+    fn matrix(self) -> [[Complex; 2]; 2] {
+        let half = std::f64::consts::FRAC_1_SQRT_2;
+        match self {
+            Gate::H => [[(half, 0.0), (half, 0.0)], [(half, 0.0), (-half, 0.0)]],
+            Gate::X => [[(0.0, 0.0), (1.0, 0.0)], [(1.0, 0.0), (0.0, 0.0)]],
+            Gate::Y => [[(0.0, 0.0), (0.0, -1.0)], [(0.0, 1.0), (0.0, 0.0)]],
+            Gate::Z => [[(1.0, 0.0), (0.0, 0.0)], [(0.0, 0.0), (-1.0, 0.0)]],
+            Gate::S => [[(1.0, 0.0), (0.0, 0.0)], [(0.0, 0.0), (0.0, 1.0)]],
+            Gate::Sdg => [[(1.0, 0.0), (0.0, 0.0)], [(0.0, 0.0), (0.0, -1.0)]],
+            Gate::T => [[(1.0, 0.0), (0.0, 0.0)], [(0.0, 0.0), (half, half)]],
+            Gate::Tdg => [[(1.0, 0.0), (0.0, 0.0)], [(0.0, 0.0), (half, -half)]],
+        }
+    }
+}
+impl std::fmt::Display for Gate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The operations that the interpreter needs from a quantum simulator, so
+/// that programs can be run on backends other than the sparse-state
+/// simulator from `qqs`.
+///
+/// Simulators don't have to simulate anything, so long as they answer each
+/// measurement. For instance, one that only records which gates the
+/// built-ins ask for can be used to check those built-ins:
+///
+/// ```
+/// use std::{cell::RefCell, rc::Rc, sync::Arc};
+/// use parser::interpreter::{Gate, RunOptions, Simulator};
+///
+/// struct Recorder {
+///     n_qubits: usize,
+///     applied: Rc<RefCell<Vec<(Gate, usize, Vec<usize>)>>>,
+/// }
+/// impl Simulator for Recorder {
+///     fn allocate(&mut self) -> usize {
+///         self.n_qubits += 1;
+///         self.n_qubits - 1
+///     }
+///     fn apply(&mut self, gate: Gate, target: usize, controls: &[usize]) {
+///         self.applied.borrow_mut().push((gate, target, controls.to_vec()));
+///     }
+///     fn measure(&mut self, _: usize) -> bool {
+///         true
+///     }
+///     fn global_phase(&mut self, _: f64) {}
+/// }
+///
+/// let source: Arc<str> = "
+///     def qmain() {
+///         h(%0);
+///         cnot(%0, %1);
+///         controlled(sdg, %1, %0);
+///         if m(%1) {
+///             x(%1);
+///         }
+///     }
+/// ".into();
+/// let program = parser::parse_program(&source).unwrap();
+/// let applied = Rc::new(RefCell::new(vec![]));
+/// let recorder = Recorder { n_qubits: 0, applied: applied.clone() };
+/// program.run_on(recorder, &source, RunOptions::default(), &[], vec![]).unwrap();
+/// assert_eq!(*applied.borrow(), vec![
+///     (Gate::H, 0, vec![]),
+///     (Gate::X, 1, vec![0]),
+///     (Gate::Sdg, 0, vec![1]),
+///     (Gate::X, 1, vec![]),
+/// ]);
+/// ```
+pub trait Simulator {
+    /// Adds a new qubit in the |0⟩ state, returning the id used to refer to
+    /// it.
+    fn allocate(&mut self) -> usize;
+
+    /// Applies a gate to `target`, controlled on each qubit in `controls`.
+    fn apply(&mut self, gate: Gate, target: usize, controls: &[usize]);
+
+    /// Measures a qubit in the computational basis, returning `true` if it
+    /// was found to be in the |1⟩ state.
+    fn measure(&mut self, qubit: usize) -> bool;
+
+    /// Multiplies the whole state by e^{iθ}. At least one qubit must have
+    /// been allocated first.
+    fn global_phase(&mut self, theta: f64);
+
+    /// Returns a qubit to the |0⟩ state.
+    fn reset(&mut self, qubit: usize) {
+        if self.measure(qubit) {
+            self.apply(Gate::X, qubit, &[]);
+        }
+    }
+}
+
+/// Wraps another simulator, counting each gate and measurement that it
+/// carries out, and keeping track of how deep a circuit the gates so far
+/// would make.
+struct CountingSimulator<S> {
+    inner: S,
+    n_gates: usize,
+    n_measurements: usize,
+    gate_counts: BTreeMap<String, usize>,
+    /// The layer of the last gate applied to each qubit.
+    layers: HashMap<usize, usize>,
+    depth: usize,
+}
+
+impl<S> CountingSimulator<S> {
+    fn new(inner: S) -> Self {
+        CountingSimulator { inner, n_gates: 0, n_measurements: 0, gate_counts: BTreeMap::new(), layers: HashMap::new(), depth: 0 }
+    }
+}
+
+impl<S: Simulator> Simulator for CountingSimulator<S> {
+    fn allocate(&mut self) -> usize {
+        self.inner.allocate()
+    }
+
+    fn apply(&mut self, gate: Gate, target: usize, controls: &[usize]) {
+        self.n_gates += 1;
+        *self.gate_counts.entry(format!("{}{gate}", "c".repeat(controls.len()))).or_default() += 1;
+        let qubits = controls.iter().chain([&target]);
+        let layer = qubits.clone().map(|q| self.layers.get(q).copied().unwrap_or(0)).max().unwrap() + 1;
+        for q in qubits {
+            self.layers.insert(*q, layer);
+        }
+        self.depth = self.depth.max(layer);
+        self.inner.apply(gate, target, controls)
+    }
+
+    fn measure(&mut self, qubit: usize) -> bool {
+        self.n_measurements += 1;
+        self.inner.measure(qubit)
+    }
+
+    fn global_phase(&mut self, theta: f64) {
+        self.n_gates += 1;
+        *self.gate_counts.entry("gphase".to_string()).or_default() += 1;
+        self.inner.global_phase(theta)
+    }
+}
+
+impl Simulator for QuantumSim<SparseState> {
+    fn allocate(&mut self) -> usize {
+        QuantumSim::allocate(self)
+    }
+
+    fn apply(&mut self, gate: Gate, target: usize, controls: &[usize]) {
+        // Start from the identity, as qqs has no way to build a matrix from
+        // scratch, then fill in the gate's own entries.
+        let mut matrix = common_matrices::x().dot(&common_matrices::x());
+        let entries = gate.matrix();
+        for ((row, col), entry) in matrix.indexed_iter_mut() {
+            let (re, im) = entries[row][col];
+            entry.re = re;
+            entry.im = im;
+        }
+        let controls = if controls.is_empty() { None } else { Some(controls) };
+        QuantumSim::apply(self, &matrix, &[target], controls);
+    }
+
+    fn measure(&mut self, qubit: usize) -> bool {
+        QuantumSim::measure(self, qubit)
+    }
+
+    fn global_phase(&mut self, theta: f64) {
+        // Applying e^{iθ} times the identity to any one qubit multiplies
+        // every amplitude by e^{iθ}, so we pick the first qubit.
+        let (sin, cos) = theta.sin_cos();
+        let identity = common_matrices::x().dot(&common_matrices::x());
+        let matrix = identity.mapv(|c| {
+            let mut phased = c;
+            phased.re = c.re * cos - c.im * sin;
+            phased.im = c.re * sin + c.im * cos;
+            phased
+        });
+        QuantumSim::apply(self, &matrix, &[0], None);
+    }
+}
+
+/// Which simulator to run programs on.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimulatorKind {
+    /// The sparse-state simulator from `qqs`, which only keeps track of
+    /// basis states with nonzero amplitudes.
+    Sparse,
+    /// A simple state-vector simulator that keeps track of every amplitude,
+    /// which can be faster for programs with only a few qubits.
+    Dense,
+}
+impl Default for SimulatorKind {
+    fn default() -> Self {
+        SimulatorKind::Sparse
+    }
+}
+
+/// Which end of a printed string of measurement results the first result
+/// goes at. This only changes how results are printed, never what's
+/// measured.
+///
+/// ```
+/// use parser::interpreter::{run_program, BitOrder, RunOptions};
+///
+/// let source = "
+///     extern x(q : qubit);
+///     extern measure_all();
+///     def qmain() {
+///         x(%1);
+///         measure_all();
+///     }
+/// ";
+/// let big = run_program(source, RunOptions { bit_order: BitOrder::Big, ..RunOptions::default() }).unwrap();
+/// assert_eq!(big.output, vec!["measure_all() -> 01"]);
+/// let little = run_program(source, RunOptions { bit_order: BitOrder::Little, ..RunOptions::default() }).unwrap();
+/// assert_eq!(little.output, vec!["measure_all() -> 10"]);
+/// ```
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOrder {
+    /// The first result (for `measure_all`, that of qubit `%0`) is printed
+    /// on the left.
+    Big,
+    /// The first result is printed on the right, so that the string reads
+    /// as a binary number with qubit `%0` as its least significant bit.
+    Little,
+}
+impl Default for BitOrder {
+    fn default() -> Self {
+        BitOrder::Big
+    }
+}
+
+/// A complex number, written as its real and imaginary parts.
+type Complex = (f64, f64);
+
+fn complex_add((a, b): Complex, (c, d): Complex) -> Complex {
+    (a + c, b + d)
+}
+
+fn complex_mul((a, b): Complex, (c, d): Complex) -> Complex {
+    (a * c - b * d, a * d + b * c)
+}
+
+/// Simulates qubits by keeping track of the amplitude of every basis state,
+/// such that the `i`th amplitude is that of the basis state whose bits are
+/// given by the binary expansion of `i`.
+pub struct DenseSimulator {
+    amplitudes: Vec<Complex>,
+    n_qubits: usize,
+}
+impl DenseSimulator {
+    pub fn new() -> Self {
+        DenseSimulator { amplitudes: vec![(1.0, 0.0)], n_qubits: 0 }
+    }
+}
+impl Default for DenseSimulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Simulator for DenseSimulator {
+    fn allocate(&mut self) -> usize {
+        // The new qubit is the most significant bit of each index, so
+        // existing amplitudes are left where they are, with the new qubit in
+        // |0⟩.
+        self.amplitudes.resize(2 * self.amplitudes.len(), (0.0, 0.0));
+        self.n_qubits += 1;
+        self.n_qubits - 1
+    }
+
+    fn apply(&mut self, gate: Gate, target: usize, controls: &[usize]) {
+        let [[m00, m01], [m10, m11]] = gate.matrix();
+        let target_mask = 1 << target;
+        let control_mask = controls.iter().fold(0, |mask, control| mask | (1 << control));
+        // Each pair of basis states that differ only in the target qubit
+        // gets mixed by the gate, so long as every control qubit is set.
+        for i in 0..self.amplitudes.len() {
+            if i & target_mask != 0 || i & control_mask != control_mask {
+                continue;
+            }
+            let j = i | target_mask;
+            let (a, b) = (self.amplitudes[i], self.amplitudes[j]);
+            self.amplitudes[i] = complex_add(complex_mul(m00, a), complex_mul(m01, b));
+            self.amplitudes[j] = complex_add(complex_mul(m10, a), complex_mul(m11, b));
+        }
+    }
+
+    fn measure(&mut self, qubit: usize) -> bool {
+        let mask = 1 << qubit;
+        let p_one = self.amplitudes
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i & mask != 0)
+            .map(|(_, (re, im))| re * re + im * im)
+            .sum::<f64>();
+        let result = rand::random::<f64>() < p_one;
+        // Collapse onto the observed result, then renormalize what's left.
+        let norm = (if result { p_one } else { 1.0 - p_one }).sqrt();
+        for (i, amplitude) in self.amplitudes.iter_mut().enumerate() {
+            *amplitude = if (i & mask != 0) == result {
+                (amplitude.0 / norm, amplitude.1 / norm)
+            } else {
+                (0.0, 0.0)
+            };
+        }
+        result
+    }
+
+    fn global_phase(&mut self, theta: f64) {
+        let phase = (theta.cos(), theta.sin());
+        for amplitude in self.amplitudes.iter_mut() {
+            *amplitude = complex_mul(phase, *amplitude);
+        }
+    }
+}
+
+/// Checks that the control and target of a two-qubit gate are different
+/// qubits, since the simulator can't apply a gate controlled on its own
+/// target.
+fn check_distinct_qubits(source: &Arc<str>, name: &str, control: &Located<InterpreterValue>, c: usize, target: &Located<InterpreterValue>, t: usize) -> Result<()> {
+    if c == t {
+        return Err(QKaledioscopeError::QubitAliasingError {
+            name: name.to_string(),
+            index: c,
+            src: source.clone(),
+            control_span: control.as_sourcespan(),
+            target_span: target.as_sourcespan(),
+        });
+    }
+    Ok(())
+}
+
+/// The implementation of a built-in function. Built-ins are given the source
+/// being run along with each argument's value and where that value came
+/// from, so that they can report errors pointing at their arguments.
+pub type BuiltinFn<'a> = dyn Fn(&Arc<str>, &[Located<InterpreterValue>]) -> Result<InterpreterValue> + 'a;
+
+/// Creates the implementation of a built-in function, acting on the given
+/// simulator. Functions that act on every qubit (e.g.: `measure_all`) act on
+/// the first `n_qubits_used` qubits. Each measurement result is appended to
+/// `results`, in the order that measurements happen, as is any output.
+fn builtin_fn<'a, S: Simulator>(name: &'static str, sim: &'a RefCell<S>, n_qubits_used: &'a Cell<usize>, results: &'a RefCell<RunResults>, options: RunOptions) -> Box<BuiltinFn<'a>> {
+    // Single-qubit gates differ only in which gate they ask the simulator to
+    // apply, so we share their implementation here.
+    let single_qubit_gate = move |gate: Gate| -> Box<BuiltinFn<'a>> {
+        Box::new(move |_: &Arc<str>, args: &[Located<InterpreterValue>]| -> Result<InterpreterValue> {
+            match args[0].value {
                 InterpreterValue::QubitRef(q) => {
-                    sim.borrow_mut().apply(&common_matrices::h(), &[q], None);
+                    sim.borrow_mut().apply(gate, q, &[]);
                 },
                 _ => panic!("Wrong type for args[0]")
             };
-            println!("h({:?})", args[0]);
-            Ok(None)
-        };
-        table.register_builtin(&Identifier("h".to_string()), &h);
+            options.trace(1, format_args!("{gate}({:?})", args[0].value));
+            Ok(InterpreterValue::Unit)
+        })
+    };
 
-        let cnot = |args: &[InterpreterValue]| {
-            // TODO: Check types and arity here instead of just unpacking...
-            let c = match args[0] {
+    // Likewise, math functions differ only in which function they apply to
+    // their argument.
+    let math_fn = move |name: &'static str, f: fn(f64) -> f64| -> Box<BuiltinFn<'a>> {
+        Box::new(move |_: &Arc<str>, args: &[Located<InterpreterValue>]| -> Result<InterpreterValue> {
+            let x = match args[0].value {
+                InterpreterValue::Number(x) => x,
+                _ => panic!("Wrong type for args[0]")
+            };
+            options.trace(1, format_args!("{name}({x})"));
+            Ok(InterpreterValue::Number(f(x)))
+        })
+    };
+
+    match name {
+        "print_n" | "print_b" | "print_q" => Box::new(move |_: &Arc<str>, args: &[Located<InterpreterValue>]| -> Result<InterpreterValue> {
+            results.borrow_mut().output(options, format!("→ {:?}", args[0].value));
+            Ok(InterpreterValue::Unit)
+        }),
+        "print" => Box::new(move |_: &Arc<str>, args: &[Located<InterpreterValue>]| -> Result<InterpreterValue> {
+            match &args[0].value {
+                InterpreterValue::Str(s) => results.borrow_mut().output(options, s.clone()),
+                _ => panic!("Wrong type for args[0]")
+            };
+            Ok(InterpreterValue::Unit)
+        }),
+        "cnot" => Box::new(move |source: &Arc<str>, args: &[Located<InterpreterValue>]| -> Result<InterpreterValue> {
+            let c = match args[0].value {
                 InterpreterValue::QubitRef(q) => q,
                 _ => panic!("Wrong type for args[0]")
             };
-            let t = match args[1] {
+            let t = match args[1].value {
                 InterpreterValue::QubitRef(q) => q,
                 _ => panic!("Wrong type for args[0]")
             };
-            sim.borrow_mut().apply(&common_matrices::x(), &[t], Some(&[c]));
-            println!("cnot({:?})", args[0]);
-            Ok(None)
-        };
-        table.register_builtin(&Identifier("cnot".to_string()), &cnot);
-
-        let m = |args: &[InterpreterValue]| {
-            // TODO: Check types and arity here instead of just unpacking...
-            let r = match args[0] {
+            check_distinct_qubits(source, "cnot", &args[0], c, &args[1], t)?;
+            sim.borrow_mut().apply(Gate::X, t, &[c]);
+            options.trace(1, format_args!("cnot({:?}, {:?})", args[0].value, args[1].value));
+            Ok(InterpreterValue::Unit)
+        }),
+        "controlled" => Box::new(move |source: &Arc<str>, args: &[Located<InterpreterValue>]| -> Result<InterpreterValue> {
+            let gate = match args[0].value {
+                InterpreterValue::Gate(gate) => gate,
+                _ => panic!("Wrong type for args[0]")
+            };
+            let c = match args[1].value {
+                InterpreterValue::QubitRef(q) => q,
+                _ => panic!("Wrong type for args[1]")
+            };
+            let t = match args[2].value {
+                InterpreterValue::QubitRef(q) => q,
+                _ => panic!("Wrong type for args[2]")
+            };
+            check_distinct_qubits(source, "controlled", &args[1], c, &args[2], t)?;
+            sim.borrow_mut().apply(gate, t, &[c]);
+            options.trace(1, format_args!("controlled({gate}, {:?}, {:?})", args[1].value, args[2].value));
+            Ok(InterpreterValue::Unit)
+        }),
+        "assert" => Box::new(|source: &Arc<str>, args: &[Located<InterpreterValue>]| -> Result<InterpreterValue> {
+            match args[0].value {
+                InterpreterValue::Bit(true) => Ok(InterpreterValue::Unit),
+                InterpreterValue::Bit(false) => Err(QKaledioscopeError::AssertionError {
+                    src: source.clone(),
+                    span: args[0].as_sourcespan(),
+                }),
+                _ => panic!("Wrong type for args[0]")
+            }
+        }),
+        "m" => Box::new(move |_: &Arc<str>, args: &[Located<InterpreterValue>]| -> Result<InterpreterValue> {
+            let r = match args[0].value {
                 InterpreterValue::QubitRef(q) => {
                     sim.borrow_mut().measure(q)
                 },
                 _ => panic!("Wrong type for args[0]")
             };
-            println!("m({:?}) -> {r}", args[0]);
-            Ok(Some(InterpreterValue::Bit(r)))
+            results.borrow_mut().measurements.push(r);
+            options.trace(1, format_args!("m({:?}) -> {r}", args[0].value));
+            Ok(InterpreterValue::Bit(r))
+        }),
+        "gphase" => Box::new(move |_: &Arc<str>, args: &[Located<InterpreterValue>]| -> Result<InterpreterValue> {
+            let theta = match args[0].value {
+                InterpreterValue::Number(theta) => theta,
+                _ => panic!("Wrong type for args[0]")
+            };
+            // A global phase can't be observed if there are no qubits to
+            // observe it on.
+            if n_qubits_used.get() > 0 {
+                sim.borrow_mut().global_phase(theta);
+            }
+            options.trace(1, format_args!("gphase({theta})"));
+            Ok(InterpreterValue::Unit)
+        }),
+        "abs" => math_fn("abs", f64::abs),
+        "sin" => math_fn("sin", f64::sin),
+        "cos" => math_fn("cos", f64::cos),
+        "exp" => math_fn("exp", f64::exp),
+        // Square roots of negative numbers would come out as NaN, which then
+        // compares as unequal to everything, so we stop right away instead.
+        "sqrt" => Box::new(move |source: &Arc<str>, args: &[Located<InterpreterValue>]| -> Result<InterpreterValue> {
+            let x = match args[0].value {
+                InterpreterValue::Number(x) => x,
+                _ => panic!("Wrong type for args[0]")
+            };
+            if x < 0.0 {
+                return Err(QKaledioscopeError::DomainError {
+                    name: "sqrt".to_string(),
+                    value: x,
+                    src: source.clone(),
+                    span: args[0].as_sourcespan(),
+                });
+            }
+            options.trace(1, format_args!("sqrt({x})"));
+            Ok(InterpreterValue::Number(x.sqrt()))
+        }),
+        // TODO: Take a qubit register argument once the language has them,
+        //       rather than measuring every qubit the program refers to.
+        //       Registers (bit[n]) only hold bits so far.
+        "measure_all" => Box::new(move |_: &Arc<str>, _: &[Located<InterpreterValue>]| -> Result<InterpreterValue> {
+            let measured = (0..n_qubits_used.get())
+                .map(|q| sim.borrow_mut().measure(q))
+                .collect::<Vec<_>>();
+            let mut results = results.borrow_mut();
+            results.output(options, format!("measure_all() -> {}", format_measurements(&measured, options.bit_order)));
+            results.measurements.extend(measured.iter().copied());
+            Ok(InterpreterValue::Register(measured))
+        }),
+        name => match Gate::from_name(name) {
+            Some(gate) => single_qubit_gate(gate),
+            None => unreachable!("No implementation for built-in function {name}."),
+        }
+    }
+}
+
+pub enum FunctionTableEntry<'a> {
+    Interpreted(&'a Located<FileElement>),
+    Builtin(Builtin, Box<BuiltinFn<'a>>),
+}
+
+pub struct FunctionTable<'a> {
+    // TODO: Use a better type than FileElement here.
+    fns: HashMap<Identifier, FunctionTableEntry<'a>>,
+    // NB: Constants are evaluated as soon as they're registered, so we keep
+    //     their values rather than their expressions, along with where they
+    //     were declared so that we can report duplicates.
+    constants: HashMap<Identifier, (InterpreterValue, SourceSpan)>,
+    // Globals are evaluated as they're registered too, but can then be
+    // assigned to by any function while the program runs. A new table is
+    // built for each run, so each run starts from their initial values.
+    globals: RefCell<HashMap<Identifier, (InterpreterValue, SourceSpan)>>,
+    // How many qubits have been allocated on the simulator that built-ins
+    // act on, or None if built-ins haven't been registered yet.
+    n_qubits: Option<&'a Cell<usize>>,
+    pub options: RunOptions,
+}
+impl<'a> FunctionTable<'a> {
+    pub fn new(options: RunOptions) -> Self {
+        FunctionTable { fns: HashMap::new(), constants: HashMap::new(), globals: RefCell::new(HashMap::new()), n_qubits: None, options }
+    }
+
+    /// Gets the value of a constant defined in source, if there is one with
+    /// the given name.
+    pub fn constant(&self, name: &Identifier) -> Option<InterpreterValue> {
+        self.constants.get(name).map(|(value, _)| value.clone())
+    }
+
+    /// Iterates over the value of each constant defined in source.
+    pub fn constants(&self) -> impl Iterator<Item = (&Identifier, InterpreterValue)> {
+        self.constants.iter().map(|(name, (value, _))| (name, value.clone()))
+    }
+
+    /// Gets the current value of a global defined in source, if there is one
+    /// with the given name.
+    pub fn global(&self, name: &Identifier) -> Option<InterpreterValue> {
+        self.globals.borrow().get(name).map(|(value, _)| value.clone())
+    }
+
+    /// Lists the current value of each global defined in source.
+    pub fn globals(&self) -> Vec<(Identifier, InterpreterValue)> {
+        self.globals.borrow().iter().map(|(name, (value, _))| (name.clone(), value.clone())).collect()
+    }
+
+    /// Looks up the built-in gate with the given name, unless the name is
+    /// used by a function declared or defined in source.
+    fn builtin_gate(&self, name: &Identifier) -> Option<Gate> {
+        match self.fns.get(name) {
+            Some(FunctionTableEntry::Builtin(builtin, _)) => Gate::from_name(builtin.name),
+            _ => None,
+        }
+    }
+
+    /// Looks up a name used as a value, checking local variables first, then
+    /// constants and globals.
+    fn lookup(&self, symbol_table: &LocalSymbolTable, name: &Identifier) -> Option<InterpreterValue> {
+        symbol_table.get(name).cloned()
+            .or_else(|| self.constant(name))
+            .or_else(|| self.global(name))
+    }
+
+    pub fn register_builtin(&mut self, source: &Arc<str>, builtin: Builtin, f: Box<BuiltinFn<'a>>) -> Result<()> {
+        let ident = Identifier(builtin.name.to_string());
+        match self.fns.get(&ident) {
+            // An extern declaration is how a program asks for a built-in, so
+            // it's fine for the built-in to take its place. Definitions, on
+            // the other hand, would be silently shadowed.
+            Some(FunctionTableEntry::Interpreted(element)) =>
+                if let FileElement::Definition { prototype, .. } = &element.value {
+                    return Err(builtin_redefinition_error(source, prototype));
+                },
+            // Built-ins given by the host don't come from any source, so
+            // there's nowhere better to point a clash between them than at
+            // the start of the program.
+            Some(FunctionTableEntry::Builtin(..)) =>
+                return Err(QKaledioscopeError::DuplicateNameError {
+                    src: source.clone(),
+                    name: builtin.name.to_string(),
+                    old_span: None,
+                    old_is_builtin: true,
+                    new_span: (0, 0).into(),
+                }),
+            None => ()
         };
-        table.register_builtin(&Identifier("m".to_string()), &m);
+        self.fns.insert(ident, FunctionTableEntry::Builtin(builtin, f));
+        Ok(())
+    }
+
+    /// Registers every built-in function, each acting on the given simulator.
+    /// Qubit literals are then checked against `n_qubits_used`, which should
+    /// be kept up to date with how many qubits have been allocated.
+    ///
+    /// `run` always allocates as many qubits as the program's literals need
+    /// before it starts, but a table built by hand can be given fewer, in
+    /// which case literals past the end are reported when they're evaluated:
+    ///
+    /// ```
+    /// use std::{cell::{Cell, RefCell}, sync::Arc};
+    /// use parser::ast::{Expression, Located};
+    /// use parser::interpreter::{DenseSimulator, FunctionTable, LocalSymbolTable, RunOptions, RunResults};
+    /// use parser::QKaledioscopeError;
+    ///
+    /// let source: Arc<str> = "%2".into();
+    /// let sim = RefCell::new(DenseSimulator::new());
+    /// let n_qubits_used = Cell::new(2);
+    /// let results = RefCell::new(RunResults::default());
+    /// let mut table = FunctionTable::new(RunOptions::default());
+    /// table.register_builtins(&source, &sim, &n_qubits_used, &results).unwrap();
+    ///
+    /// let literal = Located::new(Expression::QubitLiteral(2), (0, 2));
+    /// let result = literal.eval_in(&source, &table, &mut LocalSymbolTable::new());
+    /// assert!(matches!(result, Err(QKaledioscopeError::QubitIndexError { index: 2, n_qubits: 2, .. })));
+    /// ```
+    pub fn register_builtins<S: Simulator>(&mut self, source: &Arc<str>, sim: &'a RefCell<S>, n_qubits_used: &'a Cell<usize>, results: &'a RefCell<RunResults>) -> Result<()> {
+        self.n_qubits = Some(n_qubits_used);
+        for mut builtin in builtins() {
+            if builtin.name == "measure_all" {
+                builtin.return_type = Some(Type::Register(n_qubits_used.get()));
+            }
+            let f = builtin_fn(builtin.name, sim, n_qubits_used, results, self.options);
+            self.register_builtin(source, builtin, f)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a function declared or defined in source to the table, raising
+    /// an error if its name is already taken. Constants and globals are
+    /// evaluated and added as they're registered, such that each can refer
+    /// to those registered before it.
+    pub fn register(&mut self, source: &Arc<str>, element: &'a Located<FileElement>) -> Result<()> {
+        let prototype = match &element.value {
+            FileElement::Constant { name, type_sig, expr } =>
+                return self.register_value(source, name, type_sig, expr, false),
+            FileElement::Global { name, type_sig, expr } =>
+                return self.register_value(source, name, type_sig, expr, true),
+            FileElement::Include(path) => return Err(QKaledioscopeError::UnresolvedIncludeError {
+                path: path.clone(),
+                src: source.clone(),
+                span: element.as_sourcespan(),
+            }),
+            _ => function_prototype(element),
+        };
+        let ident = &prototype.value.name;
+        match (&element.value, self.fns.get(&ident.value)) {
+            (FileElement::Definition { .. }, _) if builtins().iter().any(|builtin| builtin.name == ident.value.0) =>
+                return Err(builtin_redefinition_error(source, prototype)),
+            // As with register_builtin, an extern declaration of a function
+            // that's already built in just asks for that built-in.
+            (FileElement::Declaration(_), Some(FunctionTableEntry::Builtin(..))) =>
+                return Ok(()),
+            (_, Some(existing)) => {
+                let (old_span, old_is_builtin) = match existing {
+                    FunctionTableEntry::Interpreted(file_element) =>
+                        (Some(function_prototype(file_element).as_sourcespan()), false),
+                    FunctionTableEntry::Builtin(..) => (None, true)
+                };
+                return Err(QKaledioscopeError::DuplicateNameError {
+                    src: source.clone(),
+                    name: ident.value.0.clone(),
+                    new_span: prototype.as_sourcespan(),
+                    old_span,
+                    old_is_builtin,
+                });
+            },
+            (_, None) => ()
+        };
+        self.fns.insert(ident.value.clone(), FunctionTableEntry::Interpreted(element));
+        Ok(())
+    }
+
+    /// Evaluates the value of a constant or global, and adds it to the table.
+    /// Constants and globals share a namespace, so neither can have the same
+    /// name as any other.
+    fn register_value(&mut self, source: &Arc<str>, name: &Located<Identifier>, type_sig: &Located<Type>, expr: &Located<Expression>, is_global: bool) -> Result<()> {
+        let old_span = match self.constants.get(&name.value) {
+            Some((_, old_span)) => Some(*old_span),
+            None => self.globals.borrow().get(&name.value).map(|(_, old_span)| *old_span),
+        };
+        if let Some(old_span) = old_span {
+            return Err(QKaledioscopeError::DuplicateNameError {
+                src: source.clone(),
+                name: name.value.0.clone(),
+                new_span: name.as_sourcespan(),
+                old_span: Some(old_span),
+                old_is_builtin: false,
+            });
+        }
+        // The checker has already made sure that constants and globals don't
+        // call any functions or use any qubits, so there's nothing here that
+        // the expression could need besides the values before it.
+        let value = expr.eval_in(source, self, &mut LocalSymbolTable::new())?;
+        if value.get_type() != type_sig.value {
+            return Err(QKaledioscopeError::TypeError {
+                expected: type_sig.value.to_string(),
+                actual: value.get_type().to_string(),
+                expr_span: expr.as_sourcespan(),
+                type_span: Some(type_sig.as_sourcespan()),
+                help: literal_help(&type_sig.value, &value.get_type()),
+                src: source.clone()
+            });
+        }
+        if is_global {
+            self.options.trace(2, format_args!("var {} = {value:?}", name.value));
+            self.globals.get_mut().insert(name.value.clone(), (value, name.as_sourcespan()));
+        } else {
+            self.options.trace(2, format_args!("const {} = {value:?}", name.value));
+            self.constants.insert(name.value.clone(), (value, name.as_sourcespan()));
+        }
+        Ok(())
+    }
+
+    pub fn build(source: &Arc<str>, value: &'a Program, options: RunOptions) -> Result<Self> {
+        let mut table = FunctionTable::new(options);
+        for element in &value.0 {
+            table.register(source, element)?;
+        }
+        Ok(table)
+    }
+}
+
+/// Gets the prototype of a file element registered as a function. Only
+/// declarations and definitions are ever registered as functions, so this
+/// always succeeds for elements in the function table.
+fn function_prototype(element: &Located<FileElement>) -> &Located<Prototype> {
+    element.value.prototype().expect("Only functions should be registered in the function table.")
+}
+
+fn builtin_redefinition_error(source: &Arc<str>, prototype: &Located<Prototype>) -> QKaledioscopeError {
+    QKaledioscopeError::DuplicateNameError {
+        src: source.clone(),
+        name: prototype.value.name.value.0.clone(),
+        new_span: prototype.as_sourcespan(),
+        old_span: None,
+        old_is_builtin: true,
+    }
+}
+
+// TODO: n_qubits_required currently takes the max over all qubit literals, but
+//       could use a map onto IDs instead.
+#[derive(Default)]
+struct QubitCounter {
+    n_qubits: usize,
+    /// Where the qubit literal with the largest index was found.
+    largest: Option<SourceSpan>,
+}
+impl Visitor for QubitCounter {
+    fn visit_expression(&mut self, expr: &Located<Expression>) {
+        // Qubit literals are zero-indexed, so referring to %n means that we
+        // need at least n + 1 qubits. For the largest possible literal, that
+        // saturates rather than overflowing, which is still far too many.
+        if let Expression::QubitLiteral(idx) = expr.value {
+            if idx.saturating_add(1) > self.n_qubits {
+                self.n_qubits = idx.saturating_add(1);
+                self.largest = Some(expr.as_sourcespan());
+            }
+        }
+        walk_expression(self, expr);
+    }
+}
+
+impl QubitCounter {
+    /// Gives back how many qubits were counted, failing if that's more
+    /// than `max_qubits`.
+    fn finish(self, source: &Arc<str>, max_qubits: usize) -> Result<usize> {
+        match self.largest {
+            Some(span) if self.n_qubits > max_qubits => Err(QKaledioscopeError::TooManyQubitsError {
+                n_qubits: self.n_qubits,
+                max_qubits,
+                src: source.clone(),
+                span,
+            }),
+            _ => Ok(self.n_qubits),
+        }
+    }
+}
+
+impl Located<Expression> {
+    /// Finds how many qubits the expression needs, failing if that's more
+    /// than `max_qubits`.
+    pub(crate) fn count_qubits(&self, source: &Arc<str>, max_qubits: usize) -> Result<usize> {
+        let mut counter = QubitCounter::default();
+        counter.visit_expression(self);
+        counter.finish(source, max_qubits)
+    }
+}
+
+impl Located<Statement> {
+    /// Finds how many qubits the statement needs, failing if that's more
+    /// than `max_qubits`.
+    pub(crate) fn count_qubits(&self, source: &Arc<str>, max_qubits: usize) -> Result<usize> {
+        let mut counter = QubitCounter::default();
+        counter.visit_statement(self);
+        counter.finish(source, max_qubits)
+    }
+}
+
+/// Finds how many qubits a function body needs, failing if that's more
+/// than `max_qubits`.
+pub(crate) fn count_qubits_in(source: &Arc<str>, body: &[Located<Statement>], max_qubits: usize) -> Result<usize> {
+    let mut counter = QubitCounter::default();
+    walk_body(&mut counter, body);
+    counter.finish(source, max_qubits)
+}
+
+impl Program {
+    pub(crate) fn n_qubits_required(&self) -> usize {
+        let mut counter = QubitCounter::default();
+        counter.visit_program(self);
+        counter.n_qubits
+    }
+
+    /// Finds how many qubits the program needs, failing if that's more
+    /// than `max_qubits`. This walks the whole program, so when it's run
+    /// several times, the count is found once and given to each run (see
+    /// `run_shots`).
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use parser::QKaledioscopeError;
+    ///
+    /// let source: Arc<str> = "def qmain() { h(%0xFFFFFFFFFFFFFFFF); }".into();
+    /// let program = parser::parse_program(&source).unwrap();
+    /// let result = program.count_qubits(&source, 24);
+    /// assert!(matches!(result, Err(QKaledioscopeError::TooManyQubitsError { n_qubits: usize::MAX, .. })));
+    /// ```
+    pub fn count_qubits(&self, source: &Arc<str>, max_qubits: usize) -> Result<usize> {
+        let mut counter = QubitCounter::default();
+        counter.visit_program(self);
+        counter.finish(source, max_qubits)
+    }
+
+    /// Runs the program's `qmain` function on a fresh instance of the
+    /// simulator given by `options`, passing in `args` as parsed according
+    /// to its prototype. Returns the measurement results and output from the
+    /// run.
+    pub fn run(&self, source: &Arc<str>, options: RunOptions, args: &[String]) -> Result<RunResults> {
+        self.run_with(source, options, args, vec![])
+    }
+
+    /// Runs the program as with `run`, but with each of `externs` provided
+    /// alongside the interpreter's own built-in functions. This lets extern
+    /// functions declared by the program be implemented by the host, such
+    /// that any which aren't still raise a `LinkingError` when called.
+    /// Externs can't share a name with any of the interpreter's built-ins.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use parser::ast::{Located, Type};
+    /// use parser::interpreter::{Builtin, BuiltinFn, InterpreterValue, RunOptions};
+    /// let source: Arc<str> = "
+    ///     extern answer() -> number;
+    ///     extern print_n(n : number);
+    ///     def qmain() {
+    ///         print_n(answer());
+    ///     }
+    /// ".into();
+    /// let program = parser::parse_program(&source).unwrap();
+    /// let answer = Builtin { name: "answer", arguments: vec![], return_type: Some(Type::Number) };
+    /// let answer_fn: Box<BuiltinFn> = Box::new(|_: &Arc<str>, _: &[Located<InterpreterValue>]| {
+    ///     Ok(InterpreterValue::Number(42.0))
+    /// });
+    /// let results = program.run_with(&source, RunOptions::default(), &[], vec![(answer, answer_fn)]).unwrap();
+    /// assert_eq!(results.output, vec!["→ Number(42.0)"]);
+    /// ```
+    ///
+    /// An extern that clashes with a built-in, or with another extern, is
+    /// reported as a duplicate name:
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use parser::ast::{Located, Type};
+    /// use parser::interpreter::{Builtin, BuiltinFn, InterpreterValue, RunOptions};
+    /// use parser::QKaledioscopeError;
+    /// let source: Arc<str> = "def qmain() { }".into();
+    /// let program = parser::parse_program(&source).unwrap();
+    /// fn extern_named(name: &'static str) -> (Builtin, Box<BuiltinFn<'static>>) {
+    ///     let builtin = Builtin { name, arguments: vec![Type::Qubit], return_type: None };
+    ///     (builtin, Box::new(|_: &Arc<str>, _: &[Located<InterpreterValue>]| Ok(InterpreterValue::Unit)))
+    /// }
+    ///
+    /// let result = program.run_with(&source, RunOptions::default(), &[], vec![extern_named("h")]);
+    /// assert!(matches!(result, Err(QKaledioscopeError::DuplicateNameError { name, old_is_builtin: true, .. }) if name == "h"));
+    ///
+    /// let result = program.run_with(&source, RunOptions::default(), &[], vec![extern_named("f"), extern_named("f")]);
+    /// assert!(matches!(result, Err(QKaledioscopeError::DuplicateNameError { name, old_is_builtin: true, .. }) if name == "f"));
+    /// ```
+    pub fn run_with(&self, source: &Arc<str>, options: RunOptions, args: &[String], externs: Vec<(Builtin, Box<BuiltinFn>)>) -> Result<RunResults> {
+        match options.simulator {
+            SimulatorKind::Sparse => self.run_on(QuantumSim::<SparseState>::new(), source, options, args, externs),
+            SimulatorKind::Dense => self.run_on(DenseSimulator::new(), source, options, args, externs),
+        }
+    }
+
+    /// Runs the program's `qmain` function as with `run_with`, but on the
+    /// given simulator.
+    pub fn run_on<S: Simulator>(&self, sim: S, source: &Arc<str>, options: RunOptions, args: &[String], externs: Vec<(Builtin, Box<BuiltinFn>)>) -> Result<RunResults> {
+        let n_qubits = self.count_qubits(source, options.max_qubits)?;
+        self.run_on_qubits(sim, n_qubits, source, options, args, externs)
+    }
+
+    /// Runs the program `shots` times as with `run`, each on a fresh
+    /// simulator, stopping at the first shot that fails. The program is
+    /// only walked to count its qubits once, before the first shot.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use parser::interpreter::RunOptions;
+    ///
+    /// let source: Arc<str> = "
+    ///     def qmain() {
+    ///         h(%0);
+    ///         cnot(%0, %1);
+    ///         measure_all();
+    ///     }
+    /// ".into();
+    /// let program = parser::parse_program(&source).unwrap();
+    /// let shots = program.run_shots(&source, RunOptions::default(), &[], 20).unwrap();
+    /// assert_eq!(shots.len(), 20);
+    /// assert!(shots.iter().all(|shot| shot.measurements[0] == shot.measurements[1]));
+    /// ```
+    ///
+    /// Either simulator gives the same distribution of results, e.g. for a
+    /// GHZ state:
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use parser::interpreter::{BitOrder, Histogram, RunOptions, SimulatorKind};
+    ///
+    /// let source: Arc<str> = "
+    ///     def qmain() {
+    ///         h(%0);
+    ///         cnot(%0, %1);
+    ///         cnot(%1, %2);
+    ///         measure_all();
+    ///     }
+    /// ".into();
+    /// let program = parser::parse_program(&source).unwrap();
+    /// for simulator in [SimulatorKind::Sparse, SimulatorKind::Dense] {
+    ///     let options = RunOptions { simulator, ..RunOptions::default() };
+    ///     let mut histogram = Histogram::default();
+    ///     for shot in program.run_shots(&source, options, &[], 1000).unwrap() {
+    ///         histogram.record(&shot.measurements, BitOrder::Big);
+    ///     }
+    ///     assert_eq!(histogram.count("000") + histogram.count("111"), 1000);
+    ///     // Each outcome should come up about half of the time; this is more
+    ///     // than six standard deviations away from that.
+    ///     assert!((400..=600).contains(&histogram.count("000")), "{simulator:?}");
+    ///     assert!((400..=600).contains(&histogram.count("111")), "{simulator:?}");
+    /// }
+    /// ```
+    pub fn run_shots(&self, source: &Arc<str>, options: RunOptions, args: &[String], shots: usize) -> Result<Vec<RunResults>> {
+        let n_qubits = self.count_qubits(source, options.max_qubits)?;
+        options.trace(1, format_args!("Counted {n_qubits} qubits for {shots} shot(s)..."));
+        (0..shots)
+            .map(|shot| {
+                options.trace(1, format_args!("Running shot {shot}..."));
+                match options.simulator {
+                    SimulatorKind::Sparse => self.run_on_qubits(QuantumSim::<SparseState>::new(), n_qubits, source, options, args, vec![]),
+                    SimulatorKind::Dense => self.run_on_qubits(DenseSimulator::new(), n_qubits, source, options, args, vec![]),
+                }
+            })
+            .collect()
+    }
+
+    /// Runs the program as with `run_on`, but with `n_qubits` qubits
+    /// allocated up front, as counted by `count_qubits`, rather than
+    /// walking the program to count them again. The count is used as
+    /// given, so functions that act on every qubit act on exactly that
+    /// many:
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use parser::interpreter::{DenseSimulator, RunOptions};
+    ///
+    /// let source: Arc<str> = "
+    ///     def qmain() {
+    ///         x(%0);
+    ///         measure_all();
+    ///     }
+    /// ".into();
+    /// let program = parser::parse_program(&source).unwrap();
+    /// assert_eq!(program.count_qubits(&source, 24).unwrap(), 1);
+    /// let results = program.run_on_qubits(DenseSimulator::new(), 3, &source, RunOptions::default(), &[], vec![]).unwrap();
+    /// assert_eq!(results.measurements, vec![true, false, false]);
+    /// ```
+    pub fn run_on_qubits<S: Simulator>(&self, sim: S, n_qubits: usize, source: &Arc<str>, options: RunOptions, args: &[String], externs: Vec<(Builtin, Box<BuiltinFn>)>) -> Result<RunResults> {
+        let sim = RefCell::new(CountingSimulator::new(sim));
+        let n_qubits_used = Cell::new(n_qubits);
+        let results = RefCell::new(RunResults::default());
+        options.trace(1, format_args!("Using {n_qubits} qubits..."));
+        let qubit_ids = (0..n_qubits).map(|_| sim.borrow_mut().allocate()).collect::<Vec<_>>();
+        options.trace(1, format_args!("qubit_ids = {qubit_ids:?}"));
+        let mut table = FunctionTable::build(source, self, options)?;
+        table.register_builtins(source, &sim, &n_qubits_used, &results)?;
+        for (builtin, f) in externs {
+            table.register_builtin(source, builtin, f)?;
+        }
 
         let qmain = table
             .fns
             .get(&Identifier("qmain".to_string()))
             .ok_or(QKaledioscopeError::NoQMainError)?;
+        let args = match qmain {
+            FunctionTableEntry::Interpreted(element) => parse_qmain_args(source, function_prototype(element), args)?
+                .into_iter()
+                .map(Located::synthesized)
+                .collect(),
+            FunctionTableEntry::Builtin(..) => vec![],
+        };
 
-        qmain.run_in(source, &table, vec![])?;
+        let return_value = match qmain.run_in(source, &table, args)? {
+            InterpreterValue::Unit => None,
+            value => Some(value),
+        };
 
-        Ok(())
+        let sim = sim.borrow();
+        Ok(RunResults {
+            return_value,
+            n_gates: sim.n_gates,
+            n_measurements: sim.n_measurements,
+            gate_counts: sim.gate_counts.clone(),
+            depth: sim.depth,
+            ..results.take()
+        })
     }
 }
 
 impl Located<Expression> {
-    pub fn eval_in(&self, source: &str, fn_table: &FunctionTable, symbol_table: &mut LocalSymbolTable) -> Result<InterpreterValue> {
+    /// Evaluates an expression, looking up any variables it uses in
+    /// `symbol_table`.
+    ///
+    /// Each bit of a register can be read by its index. Indices are numbers,
+    /// and must be whole and in range when the program runs:
+    ///
+    /// ```
+    /// use parser::interpreter::{run_program, InterpreterValue, RunOptions};
+    ///
+    /// let results = run_program("
+    ///     def qmain() -> (bit, bit, bit) {
+    ///         x(%0);
+    ///         x(%2);
+    ///         var r: bit[3] = [false, false, false];
+    ///         r[0] = measure %0;
+    ///         r[1] = measure %1;
+    ///         r[2] = measure %2;
+    ///         return (r[0], r[1], r[2]);
+    ///     }
+    /// ", RunOptions::default()).unwrap();
+    /// assert!(matches!(
+    ///     results.return_value,
+    ///     Some(InterpreterValue::Tuple(bits)) if matches!(
+    ///         bits.as_slice(),
+    ///         [InterpreterValue::Bit(true), InterpreterValue::Bit(false), InterpreterValue::Bit(true)]
+    ///     )
+    /// ));
+    /// ```
+    ///
+    /// Chained comparisons hold if each comparison between neighboring
+    /// operands does, with each operand evaluated exactly once, from left to
+    /// right:
+    ///
+    /// ```
+    /// use parser::interpreter::{run_program, InterpreterValue, RunOptions};
+    ///
+    /// let results = run_program("
+    ///     extern print(s : string);
+    ///     def middle() -> number {
+    ///         print(\"evaluated\");
+    ///         return 5.0;
+    ///     }
+    ///     def qmain() -> bit {
+    ///         return 0.0 <= middle() < 10.0;
+    ///     }
+    /// ", RunOptions::default()).unwrap();
+    /// assert_eq!(results.output, vec!["evaluated"]);
+    /// assert!(matches!(results.return_value, Some(InterpreterValue::Bit(true))));
+    /// ```
+    pub fn eval_in(&self, source: &Arc<str>, fn_table: &FunctionTable, symbol_table: &mut LocalSymbolTable) -> Result<InterpreterValue> {
         Ok(match &self.value {
             Expression::BitLiteral(bit) => InterpreterValue::Bit(*bit),
+            Expression::StringLiteral(s) => InterpreterValue::Str(s.clone()),
             Expression::NumberLiteral(num) => InterpreterValue::Number(*num),
-            Expression::QubitLiteral(idx) => InterpreterValue::QubitRef(*idx),
-            Expression::Identifier(ident) => {
-                let value = *(symbol_table.get(&ident).ok_or(QKaledioscopeError::UndefinedVariableError {
-                    name: ident.0.clone(),
-                    src: source.to_string(),
+            Expression::QubitLiteral(idx) => match fn_table.n_qubits {
+                Some(n_qubits) if *idx >= n_qubits.get() => return Err(QKaledioscopeError::QubitIndexError {
+                    index: *idx,
+                    n_qubits: n_qubits.get(),
+                    src: source.clone(),
                     span: self.as_sourcespan(),
-                })?);
-                value
+                }),
+                _ => InterpreterValue::QubitRef(*idx),
+            },
+            Expression::Identifier(ident) => match fn_table.lookup(symbol_table, ident) {
+                Some(value) => value,
+                // Names of built-in constants and gates that aren't shadowed
+                // by a variable, constant, or global refer to those
+                // built-ins.
+                None => match (builtin_constant(&ident.0), fn_table.builtin_gate(ident)) {
+                    (Some(value), _) => InterpreterValue::Number(value),
+                    (None, Some(gate)) => InterpreterValue::Gate(gate),
+                    _ => return Err(undefined_variable_error(source, fn_table, symbol_table, ident, self.as_sourcespan()))
+                }
             },
             Expression::Call(ident, args) => {
-                // TODO: raise nice error instead of unwrapping.
-                let function = fn_table.fns.get(&ident.value).unwrap();
-                // We don't use map here so that we can more easily break out on first error...
-                // it doesn't make sense to continue interpreting past a crash.
-                let mut arg_values = vec![];
-                for arg in args.iter() {
-                    arg_values.push(arg.eval_in(source, fn_table, symbol_table)?);
+                // Check that there's a value to be had before evaluating any
+                // arguments, so that a function without a return type isn't
+                // run only for its lack of a result to be rejected.
+                if let Some(function) = fn_table.fns.get(&ident.value).filter(|function| !function.has_return_type()) {
+                    return Err(QKaledioscopeError::VoidCallError {
+                        name: ident.value.0.clone(),
+                        src: source.clone(),
+                        call_span: self.as_sourcespan(),
+                        decl_span: function.decl_span(),
+                    });
+                }
+                call_in(source, fn_table, symbol_table, ident, args, self.as_sourcespan())?
+            },
+            Expression::Not(operand) => InterpreterValue::Bit(!operand.eval_condition_in(source, fn_table, symbol_table)?),
+            // Every operand is evaluated, even once one comparison fails, so
+            // that any calls in the chain run no matter what they return.
+            Expression::ChainedComparison(first, rest) => {
+                let mut lhs = first.eval_number_in(source, fn_table, symbol_table)?;
+                let mut holds = true;
+                for (op, operand) in rest {
+                    let rhs = operand.eval_number_in(source, fn_table, symbol_table)?;
+                    holds &= match op.value {
+                        BinaryOperator::Less => lhs < rhs,
+                        BinaryOperator::LessEqual => lhs <= rhs,
+                        BinaryOperator::Greater => lhs > rhs,
+                        BinaryOperator::GreaterEqual => lhs >= rhs,
+                        _ => unreachable!("Only ordering comparisons are chained.")
+                    };
+                    lhs = rhs;
+                }
+                InterpreterValue::Bit(holds)
+            },
+            Expression::Tuple(elements) => {
+                let mut values = vec![];
+                for element in elements {
+                    values.push(element.eval_in(source, fn_table, symbol_table)?);
+                }
+                InterpreterValue::Tuple(values)
+            },
+            Expression::Register(elements) => {
+                let mut bits = vec![];
+                for element in elements {
+                    bits.push(element.eval_condition_in(source, fn_table, symbol_table)?);
+                }
+                InterpreterValue::Register(bits)
+            },
+            Expression::Index(ident, index) => {
+                let register = fn_table.lookup(symbol_table, &ident.value);
+                let bits = match register {
+                    Some(InterpreterValue::Register(bits)) => bits,
+                    Some(value) => return Err(QKaledioscopeError::TypeError {
+                        expected: "a register".to_string(),
+                        actual: value.get_type().to_string(),
+                        src: source.clone(),
+                        expr_span: ident.as_sourcespan(),
+                        type_span: None,
+                        help: None,
+                    }),
+                    None => return Err(undefined_variable_error(source, fn_table, symbol_table, &ident.value, ident.as_sourcespan())),
+                };
+                let idx = register_index(source, index.eval_number_in(source, fn_table, symbol_table)?, bits.len(), index.as_sourcespan())?;
+                InterpreterValue::Bit(bits[idx])
+            },
+            // Measurements are carried out by the m built-in, which checks
+            // that it's given a qubit and records the result.
+            Expression::Measure(operand) => {
+                let qubit = Located::new(operand.eval_in(source, fn_table, symbol_table)?, operand.location);
+                match fn_table.fns.get(&Identifier("m".to_string())) {
+                    Some(m @ FunctionTableEntry::Builtin(..)) => m.run_in(source, fn_table, vec![qubit])?,
+                    _ => unreachable!("Measurements can't be evaluated until built-ins have been registered.")
+                }
+            },
+            Expression::BinaryOp(lhs, op, rhs) => match op.value {
+                BinaryOperator::Equal | BinaryOperator::NotEqual => {
+                    // Equality is defined between two numbers or two bits,
+                    // but not between qubits, gates, or strings.
+                    let lhs_value = lhs.eval_in(source, fn_table, symbol_table)?;
+                    let rhs_value = rhs.eval_in(source, fn_table, symbol_table)?;
+                    let equal = match (&lhs_value, &rhs_value) {
+                        (InterpreterValue::Number(l), InterpreterValue::Number(r)) => l == r,
+                        (InterpreterValue::Bit(l), InterpreterValue::Bit(r)) => l == r,
+                        (InterpreterValue::QubitRef(_) | InterpreterValue::Gate(_) | InterpreterValue::Str(_) | InterpreterValue::Tuple(_) | InterpreterValue::Register(_), _) => return Err(QKaledioscopeError::TypeError {
+                            expected: format!("{} or {}", Type::Number, Type::Bit),
+                            actual: lhs_value.get_type().to_string(),
+                            src: source.clone(),
+                            expr_span: lhs.as_sourcespan(),
+                            type_span: None,
+                            help: None,
+                        }),
+                        _ => return Err(QKaledioscopeError::TypeError {
+                            expected: lhs_value.get_type().to_string(),
+                            actual: rhs_value.get_type().to_string(),
+                            src: source.clone(),
+                            expr_span: rhs.as_sourcespan(),
+                            type_span: None,
+                            help: literal_help(&lhs_value.get_type(), &rhs_value.get_type()),
+                        }),
+                    };
+                    InterpreterValue::Bit(equal == (op.value == BinaryOperator::Equal))
+                },
+                _ => {
+                    let lhs = lhs.eval_number_in(source, fn_table, symbol_table)?;
+                    let rhs = rhs.eval_number_in(source, fn_table, symbol_table)?;
+                    match op.value {
+                        BinaryOperator::Add => InterpreterValue::Number(lhs + rhs),
+                        BinaryOperator::Subtract => InterpreterValue::Number(lhs - rhs),
+                        BinaryOperator::Multiply => InterpreterValue::Number(lhs * rhs),
+                        BinaryOperator::Divide => InterpreterValue::Number(lhs / rhs),
+                        BinaryOperator::Less => InterpreterValue::Bit(lhs < rhs),
+                        BinaryOperator::LessEqual => InterpreterValue::Bit(lhs <= rhs),
+                        BinaryOperator::Greater => InterpreterValue::Bit(lhs > rhs),
+                        BinaryOperator::GreaterEqual => InterpreterValue::Bit(lhs >= rhs),
+                        BinaryOperator::Equal | BinaryOperator::NotEqual => unreachable!()
+                    }
                 }
-                // TODO: Check if the return is none and raise a nice error.
-                function.run_in(source, fn_table, arg_values)?.unwrap()
             }
         })
     }
+
+    /// Evaluates an expression used as an operand to an arithmetic operator,
+    /// raising a type error if it doesn't evaluate to a number.
+    fn eval_number_in(&self, source: &Arc<str>, fn_table: &FunctionTable, symbol_table: &mut LocalSymbolTable) -> Result<f64> {
+        match self.eval_in(source, fn_table, symbol_table)? {
+            InterpreterValue::Number(num) => Ok(num),
+            value => Err(QKaledioscopeError::TypeError {
+                expected: Type::Number.to_string(),
+                actual: value.get_type().to_string(),
+                src: source.clone(),
+                expr_span: self.as_sourcespan(),
+                type_span: None,
+                help: literal_help(&Type::Number, &value.get_type()),
+            })
+        }
+    }
+
+    /// Evaluates an expression used as the condition of an `if` or `while`
+    /// statement (or as the operand to `not`), raising a type error if it
+    /// doesn't evaluate to a bit.
+    ///
+    /// ```
+    /// use miette::Diagnostic;
+    /// use parser::interpreter::{run_program, RunOptions};
+    ///
+    /// let err = run_program("
+    ///     def qmain() {
+    ///         if 1.0 {}
+    ///     }
+    /// ", RunOptions::default()).unwrap_err();
+    /// assert!(err.help().unwrap().to_string().contains("`true` or `false`"));
+    /// ```
+    ///
+    /// That includes conditions that come from a call:
+    ///
+    /// ```
+    /// use parser::interpreter::{run_program, RunOptions};
+    /// use parser::QKaledioscopeError;
+    ///
+    /// let source = "
+    ///     def count() -> number {
+    ///         return 1.0;
+    ///     }
+    ///     def qmain() {
+    ///         while count() {}
+    ///     }
+    /// ";
+    /// let result = run_program(source, RunOptions::default());
+    /// let condition_start = source.find("count() {}").unwrap();
+    /// assert!(matches!(
+    ///     result,
+    ///     Err(QKaledioscopeError::TypeError { expected, actual, expr_span, .. })
+    ///         if expected == "bit" && actual == "number" && expr_span.offset() == condition_start
+    /// ));
+    /// ```
+    pub fn eval_condition_in(&self, source: &Arc<str>, fn_table: &FunctionTable, symbol_table: &mut LocalSymbolTable) -> Result<bool> {
+        match self.eval_in(source, fn_table, symbol_table)? {
+            InterpreterValue::Bit(bit) => Ok(bit),
+            value => Err(QKaledioscopeError::TypeError {
+                expected: Type::Bit.to_string(),
+                actual: value.get_type().to_string(),
+                src: source.clone(),
+                expr_span: self.as_sourcespan(),
+                type_span: None,
+                help: literal_help(&Type::Bit, &value.get_type()),
+            })
+        }
+    }
 }
 
 impl FunctionTableEntry<'_> {
-    // TODO: Add args here.
-    pub fn run_in(&self, source: &str, table: &FunctionTable, args: Vec<InterpreterValue>) -> Result<Option<InterpreterValue>> {
+    /// The number of arguments that this function expects to be called with.
+    pub fn arity(&self) -> usize {
+        match self {
+            FunctionTableEntry::Builtin(builtin, _) => builtin.arguments.len(),
+            FunctionTableEntry::Interpreted(file_element) => function_prototype(file_element).value.arguments.len()
+        }
+    }
+
+    pub fn has_return_type(&self) -> bool {
+        match self {
+            FunctionTableEntry::Builtin(builtin, _) => builtin.return_type.is_some(),
+            FunctionTableEntry::Interpreted(file_element) => function_prototype(file_element).value.return_type.is_some()
+        }
+    }
+
+    /// Where this function was declared or defined, if it came from source.
+    pub fn decl_span(&self) -> Option<SourceSpan> {
+        match self {
+            FunctionTableEntry::Builtin(..) => None,
+            FunctionTableEntry::Interpreted(file_element) => Some(function_prototype(file_element).as_sourcespan())
+        }
+    }
+
+    /// Raises an error if a call at the given span passes the wrong number of
+    /// arguments to this function. Built-ins called without an `extern`
+    /// aren't checked until they're run:
+    ///
+    /// ```
+    /// use parser::interpreter::{run_program, RunOptions};
+    ///
+    /// let run = |call: &str| run_program(&format!("
+    ///     def qmain() {{
+    ///         {call};
+    ///     }}
+    /// "), RunOptions::default()).unwrap_err().to_string();
+    /// assert_eq!(run("h(%0, %1)"), "Function h takes 1 argument(s), but was called with 2.");
+    /// assert_eq!(run("cnot(%0)"), "Function cnot takes 2 argument(s), but was called with 1.");
+    /// ```
+    pub fn check_arity(&self, source: &Arc<str>, name: &Identifier, n_args: usize, span: SourceSpan) -> Result<()> {
+        if n_args != self.arity() {
+            return Err(QKaledioscopeError::ArityError {
+                name: name.0.clone(),
+                expected: self.arity(),
+                actual: n_args,
+                src: source.clone(),
+                span,
+            });
+        }
+        Ok(())
+    }
+
+    /// Calls this function with the given arguments, evaluating to
+    /// `InterpreterValue::Unit` if it doesn't have a return type.
+    pub fn run_in(&self, source: &Arc<str>, table: &FunctionTable, args: Vec<Located<InterpreterValue>>) -> Result<InterpreterValue> {
         match self {
-            FunctionTableEntry::Builtin(f) =>
-                f(&args),
+            FunctionTableEntry::Builtin(builtin, f) => {
+                // Built-ins don't have prototypes in source to check against
+                // ahead of time, so we check their arguments as they're called.
+                for (arg, expected) in args.iter().zip(&builtin.arguments) {
+                    if arg.value.get_type() != *expected {
+                        return Err(QKaledioscopeError::TypeError {
+                            expected: expected.to_string(),
+                            actual: arg.value.get_type().to_string(),
+                            src: source.clone(),
+                            expr_span: arg.as_sourcespan(),
+                            type_span: None,
+                            help: literal_help(expected, &arg.value.get_type()),
+                        });
+                    }
+                }
+                f(source, &args)
+            },
             FunctionTableEntry::Interpreted(file_element) => match &file_element.value {
                 // TODO: Try looking up extern.
                 FileElement::Declaration(prototype) => Err(QKaledioscopeError::LinkingError {
                     name: prototype.value.name.value.0.to_string(),
-                    src: source.to_string(),
-                    // TODO: Don't unwrap here.
-                    span: (prototype.location.unwrap().0, prototype.location.unwrap().1 - prototype.location.unwrap().0)
+                    src: source.clone(),
+                    span: prototype.as_sourcespan()
                 }),
                 // TODO: populate args into symbol table, using prototype.
                 FileElement::Definition { prototype, body } => {
                     let mut symbol_table = LocalSymbolTable::new();
                     // TODO: Validate prototypes don't have repeated identifiers.
-                    // TODO: Validate right number and types of args.
+                    // TODO: Validate types of args.
                     for (ident, arg) in prototype.value.arguments.iter().zip(args) {
-                        symbol_table.insert(ident.value.0.value.clone(), arg);
+                        symbol_table.insert(ident.value.0.value.clone(), arg.value);
+                    }
+                    // Make sure that what we return matches the declared
+                    // return type before handing it back to the caller.
+                    match (exec_body_in(body, source, table, &mut symbol_table)?, &prototype.value.return_type) {
+                        (Flow::Return { value: Some(value), span }, None) =>
+                            Err(QKaledioscopeError::TypeError {
+                                expected: Type::Unit.to_string(),
+                                actual: value.get_type().to_string(),
+                                src: source.clone(),
+                                expr_span: span,
+                                type_span: Some(prototype.as_sourcespan()),
+                                help: None,
+                            }),
+                        (Flow::Return { value: Some(value), .. }, Some(return_type)) if value.get_type() == return_type.value =>
+                            Ok(value),
+                        (Flow::Return { value, span }, Some(return_type)) =>
+                            Err(QKaledioscopeError::TypeError {
+                                expected: return_type.value.to_string(),
+                                actual: value.as_ref().map_or(Type::Unit, InterpreterValue::get_type).to_string(),
+                                src: source.clone(),
+                                expr_span: span,
+                                type_span: Some(return_type.as_sourcespan()),
+                                help: value.as_ref().and_then(|value| literal_help(&return_type.value, &value.get_type())),
+                            }),
+                        (Flow::Continue, Some(return_type)) =>
+                            Err(QKaledioscopeError::MissingReturnError {
+                                name: prototype.value.name.value.0.clone(),
+                                src: source.clone(),
+                                span: return_type.as_sourcespan(),
+                                return_type: return_type.value.to_string(),
+                            }),
+                        (Flow::Return { value: None, .. }, None) | (Flow::Continue, None) => Ok(InterpreterValue::Unit)
                     }
-                    for statement in body {
-                        match &statement.value {
-                            Statement::VariableDeclaration(ident, type_sig, expr) => {
-                                let value = expr.eval_in(source, table, &mut symbol_table)?;
-                                match (&type_sig.value, &value) {
-                                    (Type::Bit, InterpreterValue::Bit(_)) => Ok(()),
-                                    (Type::Number, InterpreterValue::Number(_)) => Ok(()),
-                                    (Type::Qubit, InterpreterValue::QubitRef(_)) => Ok(()),
-                                    _ => Err(QKaledioscopeError::TypeError {
-                                        // TODO: Nicer printouts for these types.
-                                        expected: format!("{:?}", &type_sig.value).to_string(),
-                                        actual: match &value {
-                                            InterpreterValue::Bit(_) => "bit",
-                                            InterpreterValue::Number(_) => "number",
-                                            InterpreterValue::QubitRef(_) => "qubit"
-                                        }.to_string(),
-                                        expr_span: expr.as_sourcespan(),
-                                        type_span: type_sig.as_sourcespan(),
-                                        src: source.to_string()
-                                    })
-                                }?;
-                                // TODO: Check if the variable was already defined and throw if so.
-                                symbol_table.insert(ident.value.clone(), value);
-                                println!("symbol_table: {symbol_table:?}");
-                            },
-                            Statement::Return(expr) => {
-                                let value = expr.eval_in(source, table, &mut symbol_table)?;
-                                return Ok(Some(value));
-                            },
-                            Statement::Call(ident, args) => {
-                                // TODO: Deduplicate with Expression::Call case.
-                                let function = table.fns.get(&ident.value).ok_or_else(|| QKaledioscopeError::UndefinedFunctionError {
-                                    name: ident.value.0.to_string(),
-                                    span: ident.as_sourcespan(),
-                                    src: source.to_string(),
-                                })?;
-                                // We don't use map here so that we can more easily break out on first error...
-                                // it doesn't make sense to continue interpreting past a crash.
-                                let mut arg_values = vec![];
-                                for arg in args.iter() {
-                                    arg_values.push(arg.eval_in(source, table, &mut symbol_table)?);
-                                }
-                                // TODO: Check if the return is some, raise an error.
-                                function.run_in(source, table, arg_values)?;
-                            },
-                            _ => todo!("Statement kind {:?} not yet supported for interpreted execution.", statement.value)
-                        }
+                },
+                FileElement::Constant { .. } | FileElement::Global { .. } | FileElement::Include(_) =>
+                    unreachable!("Only functions should be registered in the function table."),
+            }
+        }
+    }
+}
+
+/// Evaluates the arguments to a call, then calls the function being called
+/// with them.
+fn call_in(source: &Arc<str>, table: &FunctionTable, symbol_table: &mut LocalSymbolTable, ident: &Located<Identifier>, args: &[Located<Expression>], span: SourceSpan) -> Result<InterpreterValue> {
+    let function = table.fns.get(&ident.value).ok_or_else(|| QKaledioscopeError::UndefinedFunctionError {
+        name: ident.value.0.to_string(),
+        span: ident.as_sourcespan(),
+        src: source.clone(),
+    })?;
+    function.check_arity(source, &ident.value, args.len(), span)?;
+    // We don't use map here so that we can more easily break out on first error...
+    // it doesn't make sense to continue interpreting past a crash.
+    let mut arg_values = vec![];
+    for arg in args.iter() {
+        arg_values.push(Located::new(arg.eval_in(source, table, symbol_table)?, arg.location));
+    }
+    function.run_in(source, table, arg_values)
+}
+
+/// What should happen after a statement has been executed.
+pub enum Flow {
+    /// Carry on with the next statement in the enclosing body.
+    Continue,
+    /// Stop executing the current function and return the given value, if
+    /// any. The span is that of the returned expression, or of the `return`
+    /// statement itself if no value was given.
+    Return { value: Option<InterpreterValue>, span: SourceSpan },
+}
+
+fn exec_body_in(body: &[Located<Statement>], source: &Arc<str>, table: &FunctionTable, symbol_table: &mut LocalSymbolTable) -> Result<Flow> {
+    // Pop the body's scope even if it fails, as the REPL keeps using the
+    // same symbol table after an error.
+    symbol_table.push_scope();
+    let flow = exec_statements_in(body, source, table, symbol_table);
+    symbol_table.pop_scope();
+    flow
+}
+
+fn exec_statements_in(body: &[Located<Statement>], source: &Arc<str>, table: &FunctionTable, symbol_table: &mut LocalSymbolTable) -> Result<Flow> {
+    for statement in body {
+        // A return from inside a nested block (e.g.: an if or a while body)
+        // needs to propagate all the way out to the enclosing function.
+        if let flow @ Flow::Return { .. } = statement.exec_in(source, table, symbol_table)? {
+            return Ok(flow);
+        }
+    }
+    Ok(Flow::Continue)
+}
+
+impl Located<Statement> {
+    /// Runs a single statement, updating `symbol_table` with any variables
+    /// that it declares or assigns to.
+    ///
+    /// Functions can return tuples, which can then be split back apart into
+    /// separate variables:
+    ///
+    /// ```
+    /// use parser::interpreter::{run_program, InterpreterValue, RunOptions};
+    ///
+    /// let results = run_program("
+    ///     def pair() -> (bit, bit) {
+    ///         return (true, false);
+    ///     }
+    ///     def qmain() -> (bit, bit) {
+    ///         var (x, y): (bit, bit) = pair();
+    ///         return (y, x);
+    ///     }
+    /// ", RunOptions::default()).unwrap();
+    /// assert!(matches!(
+    ///     results.return_value,
+    ///     Some(InterpreterValue::Tuple(values)) if matches!(
+    ///         values.as_slice(),
+    ///         [InterpreterValue::Bit(false), InterpreterValue::Bit(true)]
+    ///     )
+    /// ));
+    /// ```
+    ///
+    /// Variables declared without a type take the type of their value, and
+    /// keep it as if it'd been written out, so later assignments still have
+    /// to match it:
+    ///
+    /// ```
+    /// use parser::ast::Type;
+    /// use parser::interpreter::{run_program, InterpreterValue, RunOptions};
+    /// use parser::QKaledioscopeError;
+    ///
+    /// let results = run_program("
+    ///     def qmain() -> number {
+    ///         var x = 1.0;
+    ///         return x;
+    ///     }
+    /// ", RunOptions::default()).unwrap();
+    /// assert_eq!(results.return_value.unwrap().get_type(), Type::Number);
+    ///
+    /// let results = run_program("
+    ///     def qmain() -> bit {
+    ///         var q = %0;
+    ///         x(q);
+    ///         var b = measure q;
+    ///         return b;
+    ///     }
+    /// ", RunOptions::default()).unwrap();
+    /// assert!(matches!(results.return_value, Some(InterpreterValue::Bit(true))));
+    ///
+    /// let mismatch = |declaration: &str, assignment: &str| match run_program(&format!("
+    ///     def qmain() {{
+    ///         {declaration}
+    ///         {assignment}
+    ///     }}
+    /// "), RunOptions::default()) {
+    ///     Err(QKaledioscopeError::TypeError { expected, actual, .. }) => (expected, actual),
+    ///     _ => panic!("{assignment} should have been a type error"),
+    /// };
+    /// let types = |expected: &str, actual: &str| (expected.to_string(), actual.to_string());
+    /// assert_eq!(mismatch("var x = 1.0;", "x = true;"), types("number", "bit"));
+    /// assert_eq!(mismatch("var b = measure %0;", "b = 1.0;"), types("bit", "number"));
+    /// assert_eq!(mismatch("var q = %0;", "q = true;"), types("qubit", "bit"));
+    /// ```
+    ///
+    /// The condition of a `while` loop is evaluated again before each
+    /// iteration, so any calls in it are made each time around the loop:
+    ///
+    /// ```
+    /// use parser::interpreter::{run_program, InterpreterValue, RunOptions};
+    ///
+    /// let results = run_program("
+    ///     var calls: number = 0.0;
+    ///     def keep_going() -> bit {
+    ///         calls = calls + 1.0;
+    ///         return calls <= 3.0;
+    ///     }
+    ///     def qmain() -> number {
+    ///         var iterations: number = 0.0;
+    ///         while keep_going() {
+    ///             iterations = iterations + 1.0;
+    ///         }
+    ///         return iterations;
+    ///     }
+    /// ", RunOptions::default()).unwrap();
+    /// assert!(matches!(results.return_value, Some(InterpreterValue::Number(n)) if n == 3.0));
+    /// ```
+    ///
+    /// Functions without a return type can use a bare `return;` to exit
+    /// early, even from inside a nested block:
+    ///
+    /// ```
+    /// use parser::interpreter::{run_program, RunOptions};
+    ///
+    /// let results = run_program("
+    ///     extern print(s : string);
+    ///     def greet(early : bit) {
+    ///         if early {
+    ///             print(\"early\");
+    ///             return;
+    ///         }
+    ///         print(\"late\");
+    ///     }
+    ///     def qmain() {
+    ///         greet(true);
+    ///         greet(false);
+    ///     }
+    /// ", RunOptions::default()).unwrap();
+    /// assert_eq!(results.output, vec!["early".to_string(), "late".to_string()]);
+    /// ```
+    pub fn exec_in(&self, source: &Arc<str>, table: &FunctionTable, symbol_table: &mut LocalSymbolTable) -> Result<Flow> {
+        match &self.value {
+            Statement::VariableDeclaration(ident, type_sig, expr) => {
+                let value = expr.eval_in(source, table, symbol_table)?;
+                // Variables without a type take on the type of whatever
+                // they're declared with, so there's nothing to check.
+                if let Some(type_sig) = type_sig {
+                    if value.get_type() != type_sig.value {
+                        return Err(QKaledioscopeError::TypeError {
+                            expected: type_sig.value.to_string(),
+                            actual: value.get_type().to_string(),
+                            expr_span: expr.as_sourcespan(),
+                            type_span: Some(type_sig.as_sourcespan()),
+                            help: literal_help(&type_sig.value, &value.get_type()),
+                            src: source.clone()
+                        });
                     }
-                    Ok(None)
                 }
+                // TODO: Check if the variable was already defined and throw if so.
+                symbol_table.insert(ident.value.clone(), value);
+                table.options.trace(2, format_args!("symbol_table: {symbol_table:?}"));
+            },
+            Statement::TupleDeclaration(idents, type_sig, expr) => {
+                let value = expr.eval_in(source, table, symbol_table)?;
+                if let Some(type_sig) = type_sig {
+                    if value.get_type() != type_sig.value {
+                        return Err(QKaledioscopeError::TypeError {
+                            expected: type_sig.value.to_string(),
+                            actual: value.get_type().to_string(),
+                            expr_span: expr.as_sourcespan(),
+                            type_span: Some(type_sig.as_sourcespan()),
+                            help: literal_help(&type_sig.value, &value.get_type()),
+                            src: source.clone()
+                        });
+                    }
+                }
+                // Without a type, we still need a tuple with exactly one
+                // value for each name being declared.
+                let values = match value {
+                    InterpreterValue::Tuple(values) if values.len() == idents.len() => values,
+                    value => return Err(QKaledioscopeError::TypeError {
+                        expected: format!("a tuple of {} values", idents.len()),
+                        actual: value.get_type().to_string(),
+                        expr_span: expr.as_sourcespan(),
+                        type_span: None,
+                        help: None,
+                        src: source.clone()
+                    })
+                };
+                for (ident, value) in idents.iter().zip(values) {
+                    symbol_table.insert(ident.value.clone(), value);
+                }
+                table.options.trace(2, format_args!("symbol_table: {symbol_table:?}"));
+            },
+            Statement::Assignment(ident, expr) => {
+                let value = expr.eval_in(source, table, symbol_table)?;
+                assign_in(source, table, symbol_table, ident, |existing| {
+                    if existing.get_type() != value.get_type() {
+                        return Err(QKaledioscopeError::TypeError {
+                            expected: existing.get_type().to_string(),
+                            actual: value.get_type().to_string(),
+                            expr_span: expr.as_sourcespan(),
+                            type_span: None,
+                            help: literal_help(&existing.get_type(), &value.get_type()),
+                            src: source.clone()
+                        });
+                    }
+                    *existing = value;
+                    Ok(())
+                })?;
+                table.options.trace(2, format_args!("symbol_table: {symbol_table:?}"));
+            },
+            Statement::IndexAssignment(ident, index, expr) => {
+                let bit = expr.eval_condition_in(source, table, symbol_table)?;
+                let idx = index.eval_number_in(source, table, symbol_table)?;
+                assign_in(source, table, symbol_table, ident, |existing| {
+                    let bits = match existing {
+                        InterpreterValue::Register(bits) => bits,
+                        value => return Err(QKaledioscopeError::TypeError {
+                            expected: "a register".to_string(),
+                            actual: value.get_type().to_string(),
+                            expr_span: ident.as_sourcespan(),
+                            type_span: None,
+                            help: None,
+                            src: source.clone()
+                        })
+                    };
+                    let idx = register_index(source, idx, bits.len(), index.as_sourcespan())?;
+                    bits[idx] = bit;
+                    Ok(())
+                })?;
+                table.options.trace(2, format_args!("symbol_table: {symbol_table:?}"));
+            },
+            Statement::Return(Some(expr)) => {
+                let value = expr.eval_in(source, table, symbol_table)?;
+                return Ok(Flow::Return { value: Some(value), span: expr.as_sourcespan() });
+            },
+            Statement::Return(None) =>
+                return Ok(Flow::Return { value: None, span: self.as_sourcespan() }),
+            // Barriers don't do anything to the simulator, but we still make
+            // sure that each one only refers to valid qubits.
+            Statement::Barrier(qubits) => {
+                for qubit in qubits {
+                    let value = qubit.eval_in(source, table, symbol_table)?;
+                    if value.get_type() != Type::Qubit {
+                        return Err(QKaledioscopeError::TypeError {
+                            expected: Type::Qubit.to_string(),
+                            actual: value.get_type().to_string(),
+                            expr_span: qubit.as_sourcespan(),
+                            type_span: None,
+                            help: None,
+                            src: source.clone()
+                        });
+                    }
+                }
+            },
+            // Calls made as statements discard whatever they return.
+            Statement::Call(ident, args) => {
+                call_in(source, table, symbol_table, ident, args, self.as_sourcespan())?;
+            },
+            Statement::If { condition, true_body, false_body } => {
+                let body = if condition.eval_condition_in(source, table, symbol_table)? {
+                    true_body
+                } else {
+                    false_body
+                };
+                return exec_body_in(body, source, table, symbol_table);
+            },
+            Statement::While { condition, body } => {
+                // NB: The condition is re-evaluated before every iteration,
+                //     so that calls in the condition run each time around.
+                while condition.eval_condition_in(source, table, symbol_table)? {
+                    if let flow @ Flow::Return { .. } = exec_body_in(body, source, table, symbol_table)? {
+                        return Ok(flow);
+                    }
+                }
+            },
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Finds the variable that an assignment to `ident` changes, which is the
+/// local variable of that name if there is one in scope, and otherwise the
+/// global of that name. The variable's value is passed to `assign` to be
+/// changed in place.
+fn assign_in<T>(
+    source: &Arc<str>,
+    table: &FunctionTable,
+    symbol_table: &mut LocalSymbolTable,
+    ident: &Located<Identifier>,
+    assign: impl FnOnce(&mut InterpreterValue) -> Result<T>,
+) -> Result<T> {
+    if let Some(existing) = symbol_table.get_mut(&ident.value) {
+        return assign(existing);
+    }
+    if let Some((existing, _)) = table.globals.borrow_mut().get_mut(&ident.value) {
+        return assign(existing);
+    }
+    // Constants can't be assigned to, so we only suggest variables and
+    // globals.
+    let globals = table.globals.borrow();
+    let candidates = symbol_table.names().chain(globals.keys()).map(|name| name.0.as_str());
+    Err(QKaledioscopeError::UndefinedVariableError {
+        name: ident.value.0.clone(),
+        src: source.clone(),
+        span: ident.as_sourcespan(),
+        help: did_you_mean(&ident.value.0, candidates),
+    })
+}
+
+/// Builds the error for a name that doesn't refer to any value, suggesting
+/// the closest name that does.
+fn undefined_variable_error(source: &Arc<str>, table: &FunctionTable, symbol_table: &LocalSymbolTable, name: &Identifier, span: SourceSpan) -> QKaledioscopeError {
+    let globals = table.globals.borrow();
+    let candidates = symbol_table.names()
+        .chain(table.constants.keys())
+        .chain(globals.keys())
+        .map(|name| name.0.as_str())
+        .chain(BUILTIN_CONSTANTS.iter().map(|(name, _)| *name))
+        .chain(SINGLE_QUBIT_GATES.iter().copied());
+    QKaledioscopeError::UndefinedVariableError {
+        name: name.0.clone(),
+        src: source.clone(),
+        span,
+        help: did_you_mean(&name.0, candidates),
+    }
+}
+
+/// Checks that an index into a register of the given size is a whole number
+/// that's in range, returning it as a `usize` if so.
+pub(crate) fn register_index(source: &Arc<str>, index: f64, size: usize, span: SourceSpan) -> Result<usize> {
+    if index.fract() != 0.0 || index < 0.0 || index >= size as f64 {
+        return Err(QKaledioscopeError::RegisterIndexError {
+            index,
+            size,
+            src: source.clone(),
+            span,
+        });
+    }
+    Ok(index as usize)
+}
+
+/// Converts arguments given on the command line into values for each of the
+/// arguments declared by `qmain`'s prototype.
+fn parse_qmain_args(source: &Arc<str>, prototype: &Located<Prototype>, args: &[String]) -> Result<Vec<InterpreterValue>> {
+    let error = |message: String| QKaledioscopeError::QMainArgumentError {
+        message,
+        src: source.clone(),
+        span: prototype.as_sourcespan(),
+    };
+    let arguments = &prototype.value.arguments;
+    if arguments.len() != args.len() {
+        return Err(error(format!("expected {} argument(s), but got {}.", arguments.len(), args.len())));
+    }
+    arguments
+        .iter()
+        .zip(args)
+        .map(|(decl, arg)| {
+            let ArgumentDeclaration(ident, type_sig) = &decl.value;
+            match &type_sig.value {
+                Type::Number => arg
+                    .parse()
+                    .map(InterpreterValue::Number)
+                    .map_err(|_| error(format!("expected a number for {}, but got {arg:?}.", ident.value))),
+                Type::Bit => match arg.as_str() {
+                    "true" => Ok(InterpreterValue::Bit(true)),
+                    "false" => Ok(InterpreterValue::Bit(false)),
+                    _ => Err(error(format!("expected true or false for {}, but got {arg:?}.", ident.value))),
+                },
+                Type::String => Ok(InterpreterValue::Str(arg.clone())),
+                // Qubits only exist inside the simulator, so there's no way
+                // to write one down on the command line.
+                ty => Err(error(format!("{} has type {ty}, which can't be passed from the command line.", ident.value))),
             }
+        })
+        .collect()
+}
+
+/// Writes out a sequence of measurement results as a string of 0s and 1s.
+fn format_measurements(results: &[bool], bit_order: BitOrder) -> String {
+    let digits = results.iter().map(|&r| if r { '1' } else { '0' });
+    match bit_order {
+        BitOrder::Big => digits.collect(),
+        BitOrder::Little => digits.rev().collect(),
+    }
+}
+
+/// Counts how many times each gate was applied over one or more runs of a
+/// program, along with the deepest circuit that any run made.
+#[derive(Debug, Default)]
+struct GateStats {
+    counts: BTreeMap<String, usize>,
+    depth: usize,
+}
+impl GateStats {
+    fn record(&mut self, results: &RunResults) {
+        for (gate, count) in &results.gate_counts {
+            *self.counts.entry(gate.clone()).or_default() += count;
+        }
+        self.depth = self.depth.max(results.depth);
+    }
+}
+impl std::fmt::Display for GateStats {
+    /// Formats the counts as a table with a row for each gate, followed by
+    /// the depth.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let gate_width = self.counts.keys().map(String::len).chain(["gate".len()]).max().unwrap();
+        let count_width = self.counts.values().map(|count| count.to_string().len()).chain(["count".len()]).max().unwrap();
+        writeln!(f, "{:<gate_width$}  {:>count_width$}", "gate", "count")?;
+        for (gate, count) in &self.counts {
+            writeln!(f, "{gate:<gate_width$}  {count:>count_width$}")?;
         }
+        writeln!(f, "depth: {}", self.depth)
     }
 }
 
-pub fn run_interpret_cmd(source_file: PathBuf) -> miette::Result<()> {
-    // TODO: Extract common functionality.
-    let fname = source_file.to_str().map(|s| s.to_string());
-    let source = fs::read_to_string(&source_file).map_err(|e| QKaledioscopeError::IOError {
-        cause: e,
-        subject: fname
+/// Counts how often each sequence of measurement results came up over
+/// several runs of a program. Each sequence is keyed by a string of 0s and
+/// 1s, with one digit for each measurement, written in whichever order
+/// results were recorded with.
+///
+/// ```
+/// use std::sync::Arc;
+/// use parser::interpreter::{BitOrder, Histogram, RunOptions};
+///
+/// // Both qubits of a Bell pair are always measured with the same result.
+/// let source: Arc<str> = "
+///     def qmain() {
+///         h(%0);
+///         cnot(%0, %1);
+///         m(%0);
+///         m(%1);
+///     }
+/// ".into();
+/// let program = parser::parse_program(&source).unwrap();
+/// let mut histogram = Histogram::default();
+/// for shot in program.run_shots(&source, RunOptions::default(), &[], 100).unwrap() {
+///     histogram.record(&shot.measurements, BitOrder::Big);
+/// }
+/// assert_eq!(histogram.n_shots(), 100);
+/// assert_eq!(histogram.count("00") + histogram.count("11"), 100);
+/// assert_eq!(histogram.count("01") + histogram.count("10"), 0);
+/// let table = histogram.to_string();
+/// assert!(table.lines().skip(1).all(|row| row.starts_with("00 ") || row.starts_with("11 ")));
+/// ```
+#[derive(Debug, Default, Serialize)]
+pub struct Histogram {
+    counts: BTreeMap<String, usize>,
+    n_shots: usize,
+}
+impl Histogram {
+    /// Adds the measurement results from a single run, keyed by a string
+    /// written in the given bit order.
+    pub fn record(&mut self, results: &[bool], bit_order: BitOrder) {
+        *self.counts.entry(format_measurements(results, bit_order)).or_default() += 1;
+        self.n_shots += 1;
+    }
+
+    /// How many runs have been recorded.
+    pub fn n_shots(&self) -> usize {
+        self.n_shots
+    }
+
+    /// How many runs gave the given sequence of results, written as a string
+    /// of 0s and 1s.
+    pub fn count(&self, results: &str) -> usize {
+        self.counts.get(results).copied().unwrap_or(0)
+    }
+
+    /// The fraction of runs that gave the given sequence of results.
+    pub fn probability(&self, results: &str) -> f64 {
+        self.count(results) as f64 / self.n_shots as f64
+    }
+}
+impl std::fmt::Display for Histogram {
+    /// Formats a histogram as a table with a row for each sequence of
+    /// results that came up, along with its count and probability.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Runs that didn't measure anything have an empty key, which would
+        // be hard to spot in a table.
+        let label = |results: &str| if results.is_empty() { "(none)".to_string() } else { results.to_string() };
+        let results_width = self.counts.keys().map(|results| label(results).len()).chain(["results".len()]).max().unwrap();
+        let count_width = self.n_shots.to_string().len().max("count".len());
+        writeln!(f, "{:<results_width$}  {:>count_width$}  probability", "results", "count")?;
+        for (results, count) in &self.counts {
+            writeln!(f, "{:<results_width$}  {count:>count_width$}  {:>11.4}", label(results), self.probability(results))?;
+        }
+        Ok(())
+    }
+}
+
+/// The results of running a program one or more times, as printed with
+/// `--output-format json`.
+#[derive(Debug, Serialize)]
+struct JsonResults {
+    /// The measurement results from each run, each written as a string of
+    /// 0s and 1s.
+    shots: Vec<String>,
+    /// How often each sequence of results came up, if the program was run
+    /// more than once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    histogram: Option<Histogram>,
+}
+
+/// Parses, checks, and runs a program given as source, without printing
+/// anything. This is the main entry point for embedding the interpreter, as
+/// it doesn't need to touch the filesystem.
+///
+/// ```
+/// let results = parser::interpret_str("
+///     extern x(q : qubit);
+///     extern m(q : qubit) -> bit;
+///     extern print(s : string);
+///     def qmain() {
+///         x(%0);
+///         var r: bit = m(%0);
+///         print(\"done\");
+///     }
+/// ").unwrap();
+/// assert_eq!(results.measurements, vec![true]);
+/// assert_eq!(results.output, vec!["done".to_string()]);
+/// ```
+///
+/// A `qmain` with nothing in it is fine, and simply does nothing:
+///
+/// ```
+/// let results = parser::interpret_str("def qmain() {}").unwrap();
+/// assert!(results.measurements.is_empty());
+/// ```
+pub fn interpret_str(source: &str) -> Result<RunResults> {
+    run_program(source, RunOptions::default())
+}
+
+/// Parses, checks, and runs a program given as source as with
+/// `interpret_str`, but with the given options. Output from the program's
+/// print built-ins is always captured in the results, and is only printed
+/// as well if `options.print_output` is set.
+///
+/// ```
+/// use parser::interpreter::{run_program, InterpreterValue, RunOptions};
+///
+/// let results = run_program("
+///     extern h(q : qubit);
+///     extern cnot(c : qubit, t : qubit);
+///     extern m(q : qubit) -> bit;
+///     extern print(s : string);
+///     def qmain() -> bit {
+///         h(%0);
+///         cnot(%0, %1);
+///         var first: bit = m(%0);
+///         var second: bit = m(%1);
+///         print(\"measured a Bell pair\");
+///         return first;
+///     }
+/// ", RunOptions::default()).unwrap();
+/// // Both halves of a Bell pair are always measured to be the same.
+/// assert_eq!(results.measurements[0], results.measurements[1]);
+/// assert!(matches!(results.return_value, Some(InterpreterValue::Bit(b)) if b == results.measurements[0]));
+/// assert_eq!(results.output, vec!["measured a Bell pair".to_string()]);
+/// ```
+///
+/// Functions can call each other before they're defined, including
+/// recursively:
+///
+/// ```
+/// use parser::interpreter::{run_program, InterpreterValue, RunOptions};
+///
+/// let results = run_program("
+///     def qmain() -> bit[2] {
+///         var r: bit[2] = [false, false];
+///         r[0] = is_even(4.0);
+///         r[1] = is_odd(4.0);
+///         return r;
+///     }
+///     def is_even(n: number) -> bit {
+///         if n == 0.0 {
+///             return true;
+///         }
+///         return is_odd(n - 1.0);
+///     }
+///     def is_odd(n: number) -> bit {
+///         if n == 0.0 {
+///             return false;
+///         }
+///         return is_even(n - 1.0);
+///     }
+/// ", RunOptions::default()).unwrap();
+/// assert!(matches!(results.return_value, Some(InterpreterValue::Register(bits)) if bits == vec![true, false]));
+/// ```
+///
+/// Globals start out with the value they're declared with, and keep
+/// whatever is assigned to them across calls:
+///
+/// ```
+/// use parser::interpreter::{run_program, InterpreterValue, RunOptions};
+///
+/// let results = run_program("
+///     var count: number = 0.0;
+///     def increment() {
+///         count = count + 1.0;
+///     }
+///     def qmain() -> number {
+///         increment();
+///         increment();
+///         increment();
+///         return count;
+///     }
+/// ", RunOptions::default()).unwrap();
+/// assert!(matches!(results.return_value, Some(InterpreterValue::Number(n)) if n == 3.0));
+/// ```
+pub fn run_program(source: &str, options: RunOptions) -> Result<RunResults> {
+    let source: Arc<str> = source.into();
+    let mut program = parse_shared(&source)?;
+    prepare_program(&source, &mut program, options, PhaseTimer::default())?;
+    program.run(&source, options, &[])
+}
+
+/// Checks a program, then folds its constants if `options` asks for it,
+/// which together are everything done to a program between building its
+/// AST and running it.
+fn prepare_program(source: &Arc<str>, program: &mut Program, options: RunOptions, timer: PhaseTimer) -> Result<()> {
+    timer.time("check", || -> Result<()> {
+        scope::scope_check(source, program)?;
+        checker::check_program(source, program)
     })?;
-    let source = source.as_str();
-    let mut program = vec![];
+    if options.fold_constants {
+        timer.time("fold constants", || folding::fold_constants(source, program));
+    }
+    Ok(())
+}
 
-    let pairs = QKaledioscopeParser::parse(Rule::program, source)
-        .map_err(|e| rule_error_as_parse_error(source, e))?;
-    for pair in pairs {
-        // Ignore the end of the file, but try to parse everything else.
-        if !matches!(pair.as_rule(), Rule::EOI) {
-            // TODO: write util fn to try parse multiple.
-            let element = FileElement::try_parse(source, pair)?;
-            program.push(element);
+pub fn run_interpret_cmd(source_file: PathBuf, from_ast: bool, options: RunOptions, args: Vec<String>, shots: usize) -> miette::Result<()> {
+    let timer = PhaseTimer { enabled: options.time };
+    let (mut program, source) = if from_ast {
+        timer.time("load AST", || load_ast(source_file))?
+    } else {
+        build_ast_timed(source_file, timer)?
+    };
+    prepare_program(&source, &mut program, options, timer)?;
+    // Folding constants only ever replaces expressions with literals, so
+    // there's nothing it could do to change which warnings are found.
+    checker::report_warnings(&source, &program);
+
+    // Each shot gets its own simulator, so we only need to collect how often
+    // each sequence of measurement results came up.
+    let mut histogram = Histogram::default();
+    let mut results = vec![];
+    let (mut n_gates, mut n_measurements) = (0, 0);
+    let mut stats = GateStats::default();
+    timer.time("run", || -> Result<()> {
+        for shot_results in program.run_shots(&source, options, &args, shots)? {
+            histogram.record(&shot_results.measurements, options.bit_order);
+            results.push(format_measurements(&shot_results.measurements, options.bit_order));
+            n_gates += shot_results.n_gates;
+            n_measurements += shot_results.n_measurements;
+            stats.record(&shot_results);
         }
+        Ok(())
+    })?;
+    if options.time {
+        eprintln!("gates applied: {n_gates}");
+        eprintln!("measurements made: {n_measurements}");
+    }
+    if options.stats {
+        eprint!("{stats}");
+    }
+    match options.output_format {
+        OutputFormat::Text if shots > 1 => {
+            println!("Measurement results over {shots} shots:");
+            print!("{histogram}");
+        },
+        OutputFormat::Text => (),
+        OutputFormat::Json => {
+            let output = JsonResults {
+                shots: results,
+                histogram: if shots > 1 { Some(histogram) } else { None },
+            };
+            println!("{}", serde_json::to_string(&output).map_err(QKaledioscopeError::JsonError)?);
+        },
     }
 
-    let program = Program(program);
-    program.run(&source)?;
+    Ok(())
+}
 
+pub fn run_list_builtins_cmd() -> miette::Result<()> {
+    for builtin in builtins() {
+        println!("{builtin}");
+    }
     Ok(())
 }
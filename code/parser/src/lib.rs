@@ -0,0 +1,33 @@
+#[macro_use]
+extern crate pest_derive;
+
+// NB: The modules below are listed roughly in the order that's easiest to
+//     read and follow along with. Each module requires mostly only what's
+//     defined in previously modules; for example, `ast` depends on `parser`,
+//     but not the other way around. 
+
+pub mod parser;
+pub mod ast;
+pub mod graphviz;
+pub mod ast_builder;
+pub mod checker;
+pub mod formatter;
+pub mod interpreter;
+pub mod scope;
+pub mod folding;
+pub mod repl;
+#[cfg(feature = "codegen")]
+pub mod codegen;
+
+pub mod error;
+mod util;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use ast_builder::parse_program;
+pub use interpreter::interpret_str;
+#[cfg(feature = "codegen")]
+pub use codegen::compile_str;
+pub use error::{QKaledioscopeError, Result};
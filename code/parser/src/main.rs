@@ -1,22 +1,11 @@
-#[macro_use]
-extern crate pest_derive;
-
-use std::{path::PathBuf};
+use std::{num::NonZeroUsize, path::PathBuf};
 use clap::{self, StructOpt};
 
-// NB: The modules below are listed roughly in the order that's easiest to
-//     read and follow along with. Each module requires mostly only what's
-//     defined in previously modules; for example, `ast` depends on `parser`,
-//     but not the other way around. 
-
-pub mod parser;
-pub mod ast;
-pub mod ast_builder;
-pub mod interpreter;
-pub mod codegen;
-
-pub mod error;
-mod util;
+// NB: Everything but the command line itself lives in the library half of
+//     this crate (see lib.rs), so that other crates can use it too.
+use parser::{ast_builder, checker, formatter, interpreter, repl};
+#[cfg(feature = "codegen")]
+use parser::codegen;
 
 #[derive(clap::Parser, Debug)]
 struct Args {
@@ -30,29 +19,128 @@ pub enum Action {
     Parse {
         source_file: PathBuf,
     },
+    /// Parses a Quantum Kalediscope program and prints each token matched by
+    /// the parser, along with its span.
+    Tokens {
+        source_file: PathBuf,
+    },
     /// Parses a Quantum Kalediscope program and prints an abstract syntax tree
     /// for the program.
     BuildAst {
         source_file: PathBuf,
+        /// The format to print the abstract syntax tree in.
+        #[clap(long, arg_enum, default_value = "json")]
+        format: ast_builder::AstFormat,
     },
     /// Interprets a Quantum Kalediscope program and runs it on a full-state
     /// quantum simulator.
     Interpret {
         source_file: PathBuf,
+        /// Reads the program as a JSON abstract syntax tree, as printed by
+        /// build-ast, instead of as source.
+        #[clap(long)]
+        from_ast: bool,
+        /// Traces what the interpreter is doing to stderr. Pass twice to
+        /// also trace variables as they change.
+        #[clap(short, long, parse(from_occurrences))]
+        verbose: u64,
+        /// An argument to pass to qmain, given once for each argument in
+        /// the order they're declared in. Values may start with a hyphen,
+        /// so that negative numbers can be passed.
+        #[clap(long = "arg", number_of_values = 1, allow_hyphen_values = true)]
+        args: Vec<String>,
+        /// How many times to run the program. When running more than once,
+        /// how often each sequence of measurement results came up is
+        /// printed at the end. Must be at least one.
+        #[clap(long, default_value = "1")]
+        shots: NonZeroUsize,
+        /// The format to print measurement results in.
+        #[clap(long, arg_enum, default_value = "text")]
+        output_format: interpreter::OutputFormat,
+        /// Which simulator to run the program on.
+        #[clap(long, arg_enum, default_value = "sparse")]
+        simulator: interpreter::SimulatorKind,
+        /// The most qubits a program may use before refusing to run it.
+        #[clap(long, default_value_t = interpreter::DEFAULT_MAX_QUBITS)]
+        max_qubits: usize,
+        /// Folds expressions that only use literals into a single literal
+        /// before running the program.
+        #[clap(long)]
+        fold_constants: bool,
+        /// Prints how long each phase takes to stderr, along with how many
+        /// gates and measurements the simulator carried out.
+        #[clap(long)]
+        time: bool,
+        /// Which end of printed strings of measurement results the first
+        /// result goes at.
+        #[clap(long, arg_enum, default_value = "big")]
+        bit_order: interpreter::BitOrder,
+        /// Prints how many times each gate was applied to stderr, along with
+        /// the depth of the circuit that was run.
+        #[clap(long)]
+        stats: bool,
     },
+    /// Parses a Quantum Kalediscope program and prints it back out in a
+    /// canonical format.
+    Fmt {
+        source_file: PathBuf,
+        /// Instead of printing the formatted program, exit with an error if
+        /// formatting would change it.
+        #[clap(long)]
+        check: bool,
+    },
+    /// Starts an interactive session, reading declarations, definitions,
+    /// statements, and expressions from stdin and running each one on a
+    /// simulator that persists between inputs.
+    Repl,
+    /// Checks a Quantum Kalediscope program for errors without running or
+    /// compiling it, printing nothing if no problems are found.
+    Check {
+        source_file: PathBuf,
+    },
+    #[cfg(feature = "codegen")]
     Compile {
         source_file: PathBuf,
-        // TODO: output file
+        /// How qubit literals should be lowered into QIR.
+        #[clap(long, arg_enum, default_value = "static")]
+        qubit_allocation: codegen::QubitAllocation,
+        /// Prints the control-flow graph of each compiled function as
+        /// Graphviz DOT instead of printing QIR.
+        #[clap(long)]
+        cfg: bool,
+        /// Writes output to a file instead of printing it. QIR is written as
+        /// text to .ll files, as LLVM bitcode to .bc files, and as an object
+        /// file to .o files.
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        /// Folds expressions that only use literals into a single literal
+        /// before compiling the program.
+        #[clap(long)]
+        fold_constants: bool,
+        /// Prints how long each phase takes to stderr.
+        #[clap(long)]
+        time: bool,
         // TODO: verbosity
-    }
+    },
+    /// Lists the built-in functions provided by the interpreter, along with
+    /// their signatures.
+    ListBuiltins,
 }
 
 fn main() -> miette::Result<()> {
     let args = Args::parse();
     match args.action {
-        Action::Parse { source_file } => parser::run_parse_cmd(source_file),
-        Action::BuildAst { source_file } => ast_builder::run_build_cmd(source_file),
-        Action::Interpret { source_file } => interpreter::run_interpret_cmd(source_file),
-        Action::Compile { source_file } => codegen::run_compile_cmd(source_file),
+        Action::Parse { source_file } => parser::parser::run_parse_cmd(source_file),
+        Action::Tokens { source_file } => parser::parser::run_tokens_cmd(source_file),
+        Action::BuildAst { source_file, format } => ast_builder::run_build_cmd(source_file, format),
+        Action::Interpret { source_file, from_ast, verbose, args, shots, output_format, simulator, max_qubits, fold_constants, time, bit_order, stats } =>
+            interpreter::run_interpret_cmd(source_file, from_ast, interpreter::RunOptions { verbosity: verbose, output_format, simulator, print_output: true, max_qubits, fold_constants, time, bit_order, stats }, args, shots.get()),
+        Action::Repl => repl::run_repl_cmd(),
+        Action::Check { source_file } => checker::run_check_cmd(source_file),
+        Action::Fmt { source_file, check } => formatter::run_format_cmd(source_file, check),
+        #[cfg(feature = "codegen")]
+        Action::Compile { source_file, qubit_allocation, cfg, output, fold_constants, time } =>
+            codegen::run_compile_cmd(source_file, codegen::CompileOptions { qubit_allocation, emit_cfg: cfg, output, fold_constants, time }),
+        Action::ListBuiltins => interpreter::run_list_builtins_cmd(),
     }
 }
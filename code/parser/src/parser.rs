@@ -1,29 +1,68 @@
-use std::{fs, path::PathBuf};
+use std::path::PathBuf;
 
 use pest::{iterators::Pairs, Parser};
 
-use crate::error::{rule_error_as_parse_error, QKaledioscopeError, Result};
+use crate::{error::{rule_error_as_parse_error, Result}, util::read_source};
 
 #[derive(Parser, Debug)]
 #[grammar = "qkaledioscope.pest"]
 pub struct QKaledioscopeParser;
 
-pub fn parse<'a>(source: &'a str) -> Result<Pairs<'a, Rule>> {
+pub fn parse<'a>(source: &'a std::sync::Arc<str>) -> Result<Pairs<'a, Rule>> {
     let pairs = QKaledioscopeParser::parse(Rule::program, source)
         .map_err(|e| rule_error_as_parse_error(source, e))?;
     Ok(pairs)
 }
 
 pub fn run_parse_cmd(source_file: PathBuf) -> miette::Result<()> {
-    let fname = source_file.to_str().map(|s| s.to_string());
-    let source = fs::read_to_string(&source_file).map_err(|e| QKaledioscopeError::IOError {
-        cause: e,
-        subject: fname,
-    })?;
+    let source: std::sync::Arc<str> = read_source(&source_file)?.into();
 
-    let pairs = parse(source.as_str())?;
+    let pairs = parse(&source)?;
     let json = pairs.to_json();
 
     println!("{json}");
     Ok(())
 }
+
+/// Lists every token that the parser matched, in order, with the rule, byte
+/// span, and text of each token. Semicolons are kept, so that they show up
+/// alongside everything else:
+///
+/// ```
+/// use parser::parser::{tokens, Rule};
+/// let source: std::sync::Arc<str> = "extern f(x : number);".into();
+/// let tokens = tokens(&source).unwrap();
+/// let rules = tokens.iter().map(|(rule, _, text)| (*rule, *text)).collect::<Vec<_>>();
+/// assert_eq!(rules[..8], [
+///     (Rule::declaration, "extern f(x : number);"),
+///     (Rule::prototype, "f(x : number)"),
+///     (Rule::Ident, "f"),
+///     (Rule::arg_list, "(x : number)"),
+///     (Rule::arg_decl, "x : number"),
+///     (Rule::Ident, "x"),
+///     (Rule::number_type, "number"),
+///     (Rule::Semicolon, ";"),
+/// ]);
+/// assert_eq!(tokens[7].1, (20, 21));
+/// ```
+pub fn tokens<'a>(source: &'a std::sync::Arc<str>) -> Result<Vec<(Rule, (usize, usize), &'a str)>> {
+    let tokens = parse(source)?
+        .flatten()
+        .map(|pair| {
+            let span = pair.as_span();
+            (pair.as_rule(), (span.start(), span.end()), pair.as_str())
+        })
+        .collect();
+    Ok(tokens)
+}
+
+/// Prints every token that the parser matched, in order, as a flat table
+/// with the rule, byte span, and text of each token.
+pub fn run_tokens_cmd(source_file: PathBuf) -> miette::Result<()> {
+    let source: std::sync::Arc<str> = read_source(&source_file)?.into();
+
+    for (rule, (start, end), text) in tokens(&source)? {
+        println!("{:<24} {:<12} {:?}", format!("{rule:?}"), format!("{start}..{end}"), text);
+    }
+    Ok(())
+}
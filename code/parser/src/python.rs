@@ -0,0 +1,66 @@
+//! Python bindings for parsing, interpreting, and compiling programs, built
+//! when the `python` feature is enabled. Each function takes a program as
+//! source, and raises `QKaledioscopeError` with the rendered diagnostic as
+//! its message if anything goes wrong.
+
+use pyo3::{prelude::*, create_exception, exceptions::PyException, types::{PyDict, PyTuple}};
+
+use crate::{ast_builder::ast_to_json, error, interpreter::{run_program, InterpreterValue, RunOptions}};
+
+create_exception!(qkaledioscope, QKaledioscopeError, PyException);
+
+fn to_py_err(err: error::QKaledioscopeError) -> PyErr {
+    QKaledioscopeError::new_err(format!("{:?}", miette::Report::new(err)))
+}
+
+fn value_to_py(py: Python, value: InterpreterValue) -> PyObject {
+    match value {
+        InterpreterValue::QubitRef(idx) => idx.into_py(py),
+        InterpreterValue::Number(n) => n.into_py(py),
+        InterpreterValue::Bit(b) => b.into_py(py),
+        InterpreterValue::Gate(gate) => gate.name().into_py(py),
+        InterpreterValue::Str(s) => s.into_py(py),
+        InterpreterValue::Tuple(values) =>
+            PyTuple::new(py, values.into_iter().map(|value| value_to_py(py, value))).into_py(py),
+        InterpreterValue::Register(bits) => bits.into_py(py),
+        InterpreterValue::Unit => py.None(),
+    }
+}
+
+/// Parses a program, returning its abstract syntax tree as JSON, in the
+/// same format as the build-ast command prints.
+#[pyfunction]
+fn parse(source: &str) -> PyResult<String> {
+    let program = crate::parse_program(source).map_err(to_py_err)?;
+    let json = ast_to_json(&program, source).map_err(to_py_err)?;
+    Ok(json.to_string())
+}
+
+/// Runs a program once, returning a dict with the result of each
+/// measurement, each line of output, and the value returned by `qmain`.
+#[pyfunction]
+fn interpret(py: Python, source: &str) -> PyResult<PyObject> {
+    let results = run_program(source, RunOptions::default()).map_err(to_py_err)?;
+    let dict = PyDict::new(py);
+    dict.set_item("measurements", results.measurements)?;
+    dict.set_item("output", results.output)?;
+    dict.set_item("return_value", results.return_value.map(|value| value_to_py(py, value)))?;
+    Ok(dict.into())
+}
+
+/// Compiles a program into QIR, returning the IR as text.
+#[cfg(feature = "codegen")]
+#[pyfunction]
+fn compile(source: &str) -> PyResult<String> {
+    crate::compile_str(source).map_err(to_py_err)
+}
+
+#[pymodule]
+fn qkaledioscope(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add("QKaledioscopeError", py.get_type::<QKaledioscopeError>())?;
+    m.add_function(wrap_pyfunction!(parse, m)?)?;
+    m.add_function(wrap_pyfunction!(interpret, m)?)?;
+    #[cfg(feature = "codegen")]
+    m.add_function(wrap_pyfunction!(compile, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,188 @@
+use std::{cell::{Cell, RefCell}, io::{self, BufRead, Write}, sync::Arc};
+
+use pest::Parser;
+use qqs::{QuantumSim, sparsestate::SparseState};
+
+use crate::{
+    ast::{Expression, FileElement, Located, Statement},
+    ast_builder::{mask_outside, TryParse},
+    checker::{check_constant, check_global},
+    error::{rule_error_as_parse_error, QKaledioscopeError, Result},
+    interpreter::{count_qubits_in, Flow, FunctionTable, LocalSymbolTable, RunOptions, RunResults, Simulator},
+    parser::{QKaledioscopeParser, Rule},
+};
+
+// NB: Everything entered into the REPL is kept in a single growing session
+//     string, and each new input is parsed with the rest of the session
+//     blanked out. That way, spans always refer to the session as a whole,
+//     and errors in functions defined on earlier lines still render with the
+//     right source snippets.
+
+/// A single complete piece of input to the REPL.
+enum ReplInput {
+    Element(Located<FileElement>),
+    Statement(Located<Statement>),
+    Expression(Located<Expression>),
+}
+
+/// Parses everything in the session from `start` onwards as a single
+/// declaration, definition, statement, or expression.
+fn parse_input(session: &Arc<str>, start: usize) -> Result<ReplInput> {
+    let masked = mask_outside(session, start, session.len());
+    let pair = QKaledioscopeParser::parse(Rule::repl_input, masked.as_str())
+        .map_err(|err| rule_error_as_parse_error(session, err))?
+        .next()
+        .unwrap();
+    Ok(match pair.as_rule() {
+        Rule::repl_statement =>
+            ReplInput::Statement(Statement::try_parse(session, pair.into_inner().next().unwrap())?),
+        Rule::repl_expression =>
+            ReplInput::Expression(Expression::try_parse(session, pair.into_inner().next().unwrap())?),
+        _ => ReplInput::Element(FileElement::try_parse(session, pair)?),
+    })
+}
+
+/// Decides whether buffered input is ready to be parsed, based on whether
+/// its curly braces are balanced, ignoring any comments and string
+/// literals. Returns `None` if the input has nothing but whitespace and
+/// comments.
+///
+/// ```
+/// use parser::repl::is_complete;
+/// assert_eq!(is_complete("def f() {"), Some(false));
+/// assert_eq!(is_complete("def f() {\n}"), Some(true));
+/// assert_eq!(is_complete("print(\"{\");"), Some(true));
+/// assert_eq!(is_complete("print(\"# not a comment\"); def f() {"), Some(false));
+/// assert_eq!(is_complete("print(\"\\\" }\"); def f() {"), Some(false));
+/// assert_eq!(is_complete("# just a comment {"), None);
+/// ```
+pub fn is_complete(buffer: &str) -> Option<bool> {
+    let mut depth = 0i64;
+    let mut has_code = false;
+    // String literals can't span lines, so each line starts outside of one.
+    for line in buffer.lines() {
+        let mut chars = line.chars();
+        let mut in_string = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if in_string => {
+                    chars.next();
+                },
+                '"' => in_string = !in_string,
+                _ if in_string => (),
+                '#' => break,
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => (),
+            }
+            has_code |= !c.is_whitespace();
+        }
+    }
+    if !has_code {
+        return None;
+    }
+    Some(depth <= 0)
+}
+
+/// Allocates qubits until at least `required` are available, so that qubit
+/// literals can be used as soon as they're typed in. `required` should
+/// already have been checked against the largest number of qubits allowed,
+/// since a single stray literal could otherwise ask for far too many.
+fn ensure_qubits(sim: &RefCell<impl Simulator>, n_qubits: &Cell<usize>, required: usize) {
+    while n_qubits.get() < required {
+        sim.borrow_mut().allocate();
+        n_qubits.set(n_qubits.get() + 1);
+    }
+}
+
+pub fn run_repl_cmd() -> miette::Result<()> {
+    let sim = RefCell::new(QuantumSim::<SparseState>::new());
+    let n_qubits = Cell::new(0usize);
+    // Output is printed as it happens, so there's no need to hold onto the
+    // results of each input.
+    let results = RefCell::new(RunResults::default());
+    let options = RunOptions { print_output: true, ..RunOptions::default() };
+    let mut table = FunctionTable::new(options);
+    table.register_builtins(&Arc::from(""), &sim, &n_qubits, &results)?;
+    let mut symbol_table = LocalSymbolTable::new();
+
+    let mut session = String::new();
+    let mut buffer = String::new();
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+
+    loop {
+        print!("{}", if buffer.is_empty() { "> " } else { "... " });
+        io::stdout().flush().map_err(|e| QKaledioscopeError::IOError { cause: e, subject: None })?;
+
+        let line = match lines.next() {
+            Some(line) => line.map_err(|e| QKaledioscopeError::IOError {
+                cause: e,
+                subject: Some("<stdin>".to_string()),
+            })?,
+            None => break,
+        };
+        buffer.push_str(&line);
+        buffer.push('\n');
+
+        match is_complete(&buffer) {
+            None => {
+                buffer.clear();
+                continue;
+            },
+            Some(false) => continue,
+            Some(true) => (),
+        }
+
+        let start = session.len();
+        session.push_str(&buffer);
+        buffer.clear();
+        // Errors from this input all share one copy of the session so far.
+        let shared: Arc<str> = session.as_str().into();
+
+        let input = match parse_input(&shared, start) {
+            Ok(input) => input,
+            Err(err) => {
+                // Input that doesn't parse isn't part of the session, so that
+                // it can simply be typed in again.
+                session.truncate(start);
+                eprintln!("{:?}", miette::Report::new(err));
+                continue;
+            }
+        };
+
+        let result = match input {
+            ReplInput::Element(element) => {
+                let checked = match &element.value {
+                    FileElement::Definition { body, .. } => count_qubits_in(&shared, body, options.max_qubits)
+                        .map(|required| ensure_qubits(&sim, &n_qubits, required)),
+                    FileElement::Constant { name, expr, .. } => check_constant(&shared, &name.value, expr),
+                    FileElement::Global { name, expr, .. } => check_global(&shared, &name.value, expr),
+                    _ => Ok(())
+                };
+                // Functions need to live as long as the function table, which
+                // lasts for the rest of the session anyway.
+                let element: &'static Located<FileElement> = Box::leak(Box::new(element));
+                checked.and_then(|_| table.register(&shared, element))
+            },
+            ReplInput::Statement(statement) => statement.count_qubits(&shared, options.max_qubits).and_then(|required| {
+                ensure_qubits(&sim, &n_qubits, required);
+                statement.exec_in(&shared, &table, &mut symbol_table).map(|flow| {
+                    if let Flow::Return { value: Some(value), .. } = flow {
+                        println!("{value:?}");
+                    }
+                })
+            }),
+            ReplInput::Expression(expr) => expr.count_qubits(&shared, options.max_qubits).and_then(|required| {
+                ensure_qubits(&sim, &n_qubits, required);
+                expr.eval_in(&shared, &table, &mut symbol_table).map(|value| println!("{value:?}"))
+            }),
+        };
+        if let Err(err) = result {
+            eprintln!("{:?}", miette::Report::new(err));
+        }
+        results.take();
+    }
+
+    Ok(())
+}
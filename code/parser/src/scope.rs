@@ -0,0 +1,314 @@
+use std::{collections::{HashMap, HashSet}, sync::Arc};
+
+use miette::SourceSpan;
+
+use crate::{ast::{Program, FileElement, Prototype, Statement, Expression, Located, Identifier, ArgumentDeclaration, Visitor, walk_body, walk_statement, walk_expression}, error::{QKaledioscopeError, Result, did_you_mean}, interpreter::{builtin_constant, builtins, BUILTIN_CONSTANTS, SINGLE_QUBIT_GATES}};
+
+// NB: Variables are scoped to the block they're declared in, including any
+//     blocks nested inside of it, and can only be used after they've been
+//     declared. Functions, constants, and globals are visible everywhere, but
+//     each constant or global can only refer to the constants and globals
+//     declared before it, since they're evaluated in order. Constants and
+//     globals share a namespace, but only globals can be assigned to.
+
+/// Checks that every variable, constant, and function used in a program has
+/// been declared where it's used, and that no name is declared again where
+/// an earlier declaration of it is still visible. Names that aren't defined
+/// come with a suggestion of what might have been meant, if something close
+/// to them is:
+///
+/// ```
+/// use std::sync::Arc;
+/// use miette::Diagnostic;
+///
+/// let source: Arc<str> = "
+///     def qmain() -> number {
+///         var counter: number = 0.0;
+///         return countr;
+///     }
+/// ".into();
+/// let program = parser::parse_program(&source).unwrap();
+/// let err = parser::scope::scope_check(&source, &program).unwrap_err();
+/// assert_eq!(err.help().unwrap().to_string(), "Did you mean `counter`?");
+/// ```
+///
+/// That includes variables in nested blocks, which can't shadow those in
+/// the blocks around them:
+///
+/// ```
+/// use std::sync::Arc;
+/// use parser::QKaledioscopeError;
+///
+/// let source: Arc<str> = "
+///     def qmain() {
+///         var n: number = 0.0;
+///         if true {
+///             var n: number = 1.0;
+///         }
+///     }
+/// ".into();
+/// let program = parser::parse_program(&source).unwrap();
+/// let result = parser::scope::scope_check(&source, &program);
+/// assert!(matches!(result, Err(QKaledioscopeError::DuplicateNameError { name, .. }) if name == "n"));
+/// ```
+pub fn scope_check(source: &Arc<str>, program: &Program) -> Result<()> {
+    let mut checker = ScopeChecker::new(source);
+    for element in &program.0 {
+        if let Some(prototype) = element.value.prototype() {
+            checker.declare_function(prototype, matches!(element.value, FileElement::Definition { .. }))?;
+        }
+    }
+    for element in &program.0 {
+        match &element.value {
+            FileElement::Constant { name, expr, .. } => {
+                checker.visit_expression(expr);
+                checker.finish()?;
+                checker.declare_constant(name)?;
+            },
+            FileElement::Global { name, expr, .. } => {
+                checker.visit_expression(expr);
+                checker.finish()?;
+                checker.declare_constant(name)?;
+                checker.globals.insert(&name.value);
+            },
+            FileElement::Include(path) => return Err(QKaledioscopeError::UnresolvedIncludeError {
+                path: path.clone(),
+                src: source.clone(),
+                span: element.as_sourcespan(),
+            }),
+            FileElement::Declaration(_) | FileElement::Definition { .. } => (),
+        }
+    }
+    // Definitions are only checked once every constant and global has been
+    // declared, since functions can refer to any of them.
+    for element in &program.0 {
+        checker.visit_file_element(element);
+    }
+    checker.finish()
+}
+
+fn duplicate_name_error(source: &Arc<str>, name: &Identifier, old_span: Option<SourceSpan>, new_span: SourceSpan) -> QKaledioscopeError {
+    QKaledioscopeError::DuplicateNameError {
+        src: source.clone(),
+        name: name.0.clone(),
+        old_is_builtin: old_span.is_none(),
+        old_span,
+        new_span,
+    }
+}
+
+struct ScopeChecker<'a> {
+    source: &'a Arc<str>,
+    builtins: HashSet<&'static str>,
+    functions: HashMap<&'a Identifier, SourceSpan>,
+    constants: HashMap<&'a Identifier, SourceSpan>,
+    /// Which of the names in `constants` are globals, and so can be assigned
+    /// to.
+    globals: HashSet<&'a Identifier>,
+    /// The variables declared in each block enclosing the statement being
+    /// checked, innermost last.
+    scopes: Vec<HashMap<Identifier, SourceSpan>>,
+    /// The first error found while walking the program, if any.
+    error: Option<QKaledioscopeError>,
+}
+
+impl<'a> ScopeChecker<'a> {
+    fn new(source: &'a Arc<str>) -> Self {
+        ScopeChecker {
+            source,
+            builtins: builtins().into_iter().map(|builtin| builtin.name).collect(),
+            functions: HashMap::new(),
+            constants: HashMap::new(),
+            globals: HashSet::new(),
+            scopes: vec![],
+            error: None,
+        }
+    }
+
+    /// Keeps track of an error found while walking, unless there's already
+    /// an earlier one.
+    fn report(&mut self, error: QKaledioscopeError) {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+    }
+
+    /// Raises the first error found by walking so far, if any.
+    fn finish(&mut self) -> Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => Ok(())
+        }
+    }
+
+    fn declare_function(&mut self, prototype: &'a Located<Prototype>, is_definition: bool) -> Result<()> {
+        let name = &prototype.value.name.value;
+        // As in the interpreter, declaring a built-in with extern just asks
+        // for that built-in, but defining one would shadow it.
+        if self.builtins.contains(name.0.as_str()) {
+            return if is_definition {
+                Err(duplicate_name_error(self.source, name, None, prototype.as_sourcespan()))
+            } else {
+                Ok(())
+            };
+        }
+        match self.functions.get(name) {
+            Some(old_span) => Err(duplicate_name_error(self.source, name, Some(*old_span), prototype.as_sourcespan())),
+            None => {
+                self.functions.insert(name, prototype.as_sourcespan());
+                Ok(())
+            }
+        }
+    }
+
+    fn declare_constant(&mut self, name: &'a Located<Identifier>) -> Result<()> {
+        match self.constants.get(&name.value) {
+            Some(old_span) => Err(duplicate_name_error(self.source, &name.value, Some(*old_span), name.as_sourcespan())),
+            None => {
+                self.constants.insert(&name.value, name.as_sourcespan());
+                Ok(())
+            }
+        }
+    }
+
+    fn declare_variable(&mut self, name: &Located<Identifier>) {
+        // NB: Codegen keeps a single map of the variables in each function,
+        //     so a variable declared in a nested block can't shadow one from
+        //     an enclosing block.
+        if let Some(old_span) = self.variable(&name.value) {
+            let error = duplicate_name_error(self.source, &name.value, Some(old_span), name.as_sourcespan());
+            self.report(error);
+            return;
+        }
+        self.scopes
+            .last_mut()
+            .expect("Variables can only be declared inside a function.")
+            .insert(name.value.clone(), name.as_sourcespan());
+    }
+
+    /// Finds where a variable visible from the current scope was declared.
+    fn variable(&self, name: &Identifier) -> Option<SourceSpan> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).copied())
+    }
+
+    /// Whether a name refers to a variable or global visible from the
+    /// current scope, such that it can be assigned to.
+    fn is_assignable(&self, name: &Identifier) -> bool {
+        self.variable(name).is_some() || self.globals.contains(name)
+    }
+
+    /// Lists the names of the variables visible from the current scope.
+    fn variable_names(&self) -> impl Iterator<Item = &str> {
+        self.scopes.iter().flat_map(|scope| scope.keys()).map(|name| name.0.as_str())
+    }
+
+    /// Builds the error for a name that isn't defined, suggesting whichever
+    /// of `candidates` is closest to it.
+    fn undefined_variable_error<'b>(&self, name: &Identifier, span: SourceSpan, candidates: impl IntoIterator<Item = &'b str>) -> QKaledioscopeError {
+        QKaledioscopeError::UndefinedVariableError {
+            name: name.0.clone(),
+            src: self.source.clone(),
+            span,
+            help: did_you_mean(&name.0, candidates),
+        }
+    }
+
+    fn check_assignable(&mut self, ident: &Located<Identifier>) {
+        if !self.is_assignable(&ident.value) {
+            let globals = self.globals.iter().map(|name| name.0.as_str());
+            let error = self.undefined_variable_error(&ident.value, ident.as_sourcespan(), self.variable_names().chain(globals));
+            self.report(error);
+        }
+    }
+
+    fn check_function(&mut self, ident: &Located<Identifier>) {
+        if !self.functions.contains_key(&ident.value) && !self.builtins.contains(ident.value.0.as_str()) {
+            self.report(QKaledioscopeError::UndefinedFunctionError {
+                name: ident.value.0.clone(),
+                src: self.source.clone(),
+                span: ident.as_sourcespan(),
+            });
+        }
+    }
+}
+
+impl Visitor for ScopeChecker<'_> {
+    fn visit_file_element(&mut self, element: &Located<FileElement>) {
+        // Constants and globals are checked up front by scope_check, so only
+        // definitions are left to walk.
+        if let FileElement::Definition { prototype, body } = &element.value {
+            self.scopes.push(HashMap::new());
+            for argument in &prototype.value.arguments {
+                let ArgumentDeclaration(ident, _) = &argument.value;
+                self.declare_variable(ident);
+            }
+            self.visit_body(body);
+            self.scopes.pop();
+        }
+    }
+
+    fn visit_body(&mut self, body: &[Located<Statement>]) {
+        self.scopes.push(HashMap::new());
+        walk_body(self, body);
+        self.scopes.pop();
+    }
+
+    fn visit_statement(&mut self, statement: &Located<Statement>) {
+        match &statement.value {
+            // The variable isn't in scope until after its value has been
+            // computed.
+            Statement::VariableDeclaration(ident, _, expr) => {
+                self.visit_expression(expr);
+                self.declare_variable(ident);
+            },
+            Statement::TupleDeclaration(idents, _, expr) => {
+                self.visit_expression(expr);
+                for ident in idents {
+                    self.declare_variable(ident);
+                }
+            },
+            Statement::Assignment(ident, _) | Statement::IndexAssignment(ident, ..) => {
+                walk_statement(self, statement);
+                self.check_assignable(ident);
+            },
+            Statement::Call(ident, _) => {
+                self.check_function(ident);
+                walk_statement(self, statement);
+            },
+            _ => walk_statement(self, statement)
+        }
+    }
+
+    fn visit_expression(&mut self, expr: &Located<Expression>) {
+        match &expr.value {
+            // Besides variables and constants, the names of built-in
+            // constants and gates can be used as values.
+            Expression::Identifier(ident) => {
+                let is_defined = self.variable(ident).is_some() ||
+                    self.constants.contains_key(ident) ||
+                    builtin_constant(&ident.0).is_some() ||
+                    SINGLE_QUBIT_GATES.contains(&ident.0.as_str());
+                if !is_defined {
+                    let candidates = self.variable_names()
+                        .chain(self.constants.keys().map(|name| name.0.as_str()))
+                        .chain(BUILTIN_CONSTANTS.iter().map(|(name, _)| *name))
+                        .chain(SINGLE_QUBIT_GATES.iter().copied());
+                    let error = self.undefined_variable_error(ident, expr.as_sourcespan(), candidates);
+                    self.report(error);
+                }
+            },
+            // Registers can only be read from variables, constants, and
+            // globals.
+            Expression::Index(ident, _) => {
+                if self.variable(&ident.value).is_none() && !self.constants.contains_key(&ident.value) {
+                    let constants = self.constants.keys().map(|name| name.0.as_str());
+                    let error = self.undefined_variable_error(&ident.value, ident.as_sourcespan(), self.variable_names().chain(constants));
+                    self.report(error);
+                }
+            },
+            Expression::Call(ident, _) => self.check_function(ident),
+            _ => ()
+        }
+        walk_expression(self, expr)
+    }
+}
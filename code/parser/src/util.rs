@@ -1,12 +1,52 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, fs, io::{self, Read}, path::Path, time::Instant};
+
+use crate::error::{QKaledioscopeError, Result};
+
+/// Reads the source of a Quantum Kaledioscope program, treating a path of
+/// `-` as standard input.
+pub(crate) fn read_source(source_file: &Path) -> Result<String> {
+    if source_file == Path::new("-") {
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source).map_err(|e| QKaledioscopeError::IOError {
+            cause: e,
+            subject: Some("<stdin>".to_string()),
+        })?;
+        Ok(source)
+    } else {
+        fs::read_to_string(source_file).map_err(|e| QKaledioscopeError::IOError {
+            cause: e,
+            subject: source_file.to_str().map(|s| s.to_string()),
+        })
+    }
+}
+
+/// Measures how long each phase of a command takes, printing the time to
+/// stderr as each phase finishes. Does nothing unless enabled, so that it
+/// can be passed along unconditionally.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct PhaseTimer {
+    pub(crate) enabled: bool,
+}
+
+impl PhaseTimer {
+    pub(crate) fn time<T>(&self, phase: &str, f: impl FnOnce() -> T) -> T {
+        if !self.enabled {
+            return f();
+        }
+        let start = Instant::now();
+        let result = f();
+        eprintln!("{phase}: {:?}", start.elapsed());
+        result
+    }
+}
 
 pub(crate) trait ResultIter<T, E>
-where Self: Iterator<Item = Result<T, E>> {
-    fn try_collect(self) -> Result<Vec<T>, Vec<E>>;
+where Self: Iterator<Item = std::result::Result<T, E>> {
+    fn try_collect(self) -> std::result::Result<Vec<T>, Vec<E>>;
 }
 
-impl<T, E, I> ResultIter<T, E> for I where I: Iterator<Item = Result<T, E>>, E: Debug {
-    fn try_collect(self) -> Result<Vec<T>, Vec<E>> {
+impl<T, E, I> ResultIter<T, E> for I where I: Iterator<Item = std::result::Result<T, E>>, E: Debug {
+    fn try_collect(self) -> std::result::Result<Vec<T>, Vec<E>> {
         let mut items: Vec<_> = self.collect();
         if items.iter().any(|e| e.is_err()) {
             Err(items
@@ -0,0 +1,30 @@
+//! An entry point for running programs from JavaScript, built when the
+//! `wasm` feature is enabled. Programs are given as source and run on an
+//! in-memory simulator, so nothing here touches the filesystem.
+
+use miette::{Diagnostic, NarratableReportHandler};
+use serde_json::json;
+use wasm_bindgen::prelude::*;
+
+use crate::interpreter::{run_program, RunOptions};
+
+/// Renders a diagnostic as plain text, since there's no terminal to draw
+/// fancy diagnostics on.
+fn render(diagnostic: &dyn Diagnostic) -> String {
+    let mut rendered = String::new();
+    // Writing to a String can't fail.
+    NarratableReportHandler::new().render_report(&mut rendered, diagnostic).unwrap();
+    rendered
+}
+
+/// Runs a program once, returning its results as JSON. On success, the
+/// JSON has `"ok": true` and the `RunResults` from the run under
+/// `"results"`; otherwise it has `"ok": false` and an `"error"` with the
+/// rendered diagnostic.
+#[wasm_bindgen]
+pub fn run(source: &str) -> String {
+    match run_program(source, RunOptions::default()) {
+        Ok(results) => json!({ "ok": true, "results": results }),
+        Err(err) => json!({ "ok": false, "error": render(&err) }),
+    }.to_string()
+}
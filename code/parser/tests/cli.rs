@@ -0,0 +1,112 @@
+//! Runs the command line interface on small programs, checking what it
+//! prints, e.g.:
+//!     cargo test --test cli
+#![cfg(not(target_arch = "wasm32"))]
+
+use std::{fs, io::Write, path::PathBuf, process::{Command, Output, Stdio}};
+
+/// Writes `source` to a file of its own in the temporary directory, so that
+/// it can be passed to the command line by path.
+fn source_file(name: &str, source: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("qkaledioscope-cli-{name}.qk"));
+    fs::write(&path, source).unwrap();
+    path
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_parser")).args(args).output().unwrap()
+}
+
+/// Runs the command line as with `run`, writing `stdin` to its standard
+/// input.
+fn run_with_stdin(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_parser"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+const PRINT_ARG: &str = "
+    extern print_n(n : number);
+    def qmain(n: number) {
+        print_n(n);
+    }
+";
+
+#[test]
+fn passes_args_to_qmain() {
+    let path = source_file("passes_args_to_qmain", PRINT_ARG);
+    let output = run(&["interpret", "--arg", "3.0", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "→ Number(3.0)\n");
+}
+
+#[test]
+fn passes_negative_args_to_qmain() {
+    let path = source_file("passes_negative_args_to_qmain", PRINT_ARG);
+    let output = run(&["interpret", "--arg", "-3.0", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "→ Number(-3.0)\n");
+}
+
+#[test]
+fn reads_source_from_stdin() {
+    let output = run_with_stdin(&["interpret", "--arg", "2.0", "-"], PRINT_ARG);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "→ Number(2.0)\n");
+}
+
+#[test]
+fn reports_errors_in_source_from_stdin() {
+    let output = run_with_stdin(&["check", "-"], "def qmain() { return countr; }");
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("countr"));
+}
+
+#[test]
+fn prints_results_as_json() {
+    let path = source_file("prints_results_as_json", "
+        def qmain() {
+            x(%0);
+            m(%0);
+            m(%1);
+        }
+    ");
+    let output = run(&["interpret", "--output-format", "json", "--shots", "3", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 1);
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(json["shots"], serde_json::json!(["10", "10", "10"]));
+    assert_eq!(json["histogram"]["counts"], serde_json::json!({ "10": 3 }));
+    assert_eq!(json["histogram"]["n_shots"], 3);
+}
+
+#[test]
+fn repl_rejects_too_many_qubits() {
+    let output = run_with_stdin(&["repl"], "h(%100);\n");
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("needs 101 qubits"));
+}
+
+#[test]
+fn counts_qubits_once_for_all_shots() {
+    let path = source_file("counts_qubits_once_for_all_shots", "
+        def qmain() {
+            h(%0);
+            cnot(%0, %1);
+            measure_all();
+        }
+    ");
+    let output = run(&["interpret", "-v", "--shots", "3", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr.matches("Counted 2 qubits").count(), 1);
+    assert_eq!(stderr.matches("Running shot").count(), 3);
+    assert_eq!(stderr.matches("Using 2 qubits").count(), 3);
+}
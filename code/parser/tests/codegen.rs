@@ -0,0 +1,76 @@
+//! Checks the IR generated for small programs, e.g.:
+//!     cargo test --test codegen
+#![cfg(feature = "codegen")]
+
+/// Finds the instructions in the body of a function, leaving out the
+/// allocas and stores that spill its arguments onto the stack.
+fn instructions<'a>(ir: &'a str, define: &str) -> Vec<&'a str> {
+    ir.lines()
+        .skip_while(|line| !line.starts_with(define))
+        .skip(1)
+        .take_while(|line| *line != "}")
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.ends_with(':'))
+        .filter(|line| !line.contains(" = alloca ") && !line.starts_with("store "))
+        .collect()
+}
+
+#[test]
+fn multiplies_before_adding() {
+    let ir = parser::compile_str("
+        def f(a: number, b: number, c: number) -> number {
+            return a + b * c;
+        }
+        def qmain() {}
+    ").unwrap();
+    assert_eq!(instructions(&ir, "define double @f(double %a, double %b, double %c)"), [
+        "%0 = load double, double* %a1, align 8",
+        "%1 = load double, double* %b2, align 8",
+        "%2 = load double, double* %c3, align 8",
+        "%multmp = fmul double %1, %2",
+        "%addtmp = fadd double %0, %multmp",
+        "ret double %addtmp",
+    ]);
+}
+
+#[test]
+fn branches_on_less_than() {
+    let ir = parser::compile_str("
+        extern x(q : qubit);
+        def qmain() {
+            var a: number = 1.0;
+            var b: number = 2.0;
+            if a < b {
+                x(%0);
+            }
+        }
+    ").unwrap();
+    let body = instructions(&ir, "define void @qmain()");
+    let cmp = body.iter().position(|line| line.starts_with("%cmptmp = fcmp olt double ")).unwrap();
+    assert_eq!(body[cmp + 1], "br i1 %cmptmp, label %then, label %else");
+}
+
+#[test]
+fn not_equal_holds_for_nan() {
+    let ir = parser::compile_str("
+        def f(a: number, b: number) -> bit {
+            return a != b;
+        }
+        def qmain() {}
+    ").unwrap();
+    assert!(instructions(&ir, "define i1 @f(double %a, double %b)").contains(&"%cmptmp = fcmp une double %0, %1"));
+}
+
+#[test]
+fn allocates_inferred_types() {
+    let ir = parser::compile_str("
+        def qmain() {
+            var n = 1.0;
+            var b = measure %0;
+            var q = %1;
+        }
+    ").unwrap();
+    assert!(ir.contains("%n = alloca double"));
+    assert!(ir.contains("%b = alloca i1"));
+    assert!(ir.contains("%q = alloca %Qubit*"));
+}
@@ -0,0 +1,28 @@
+//! Runs the WASM entry point in a headless browser or Node, e.g.:
+//!     wasm-pack test --node --no-default-features --features wasm
+#![cfg(all(target_arch = "wasm32", feature = "wasm"))]
+
+use serde_json::Value;
+use wasm_bindgen_test::wasm_bindgen_test;
+
+#[wasm_bindgen_test]
+fn runs_deterministic_program() {
+    let json: Value = serde_json::from_str(&parser::wasm::run("
+        extern x(q : qubit);
+        extern m(q : qubit) -> bit;
+        def qmain() -> bit {
+            x(%0);
+            return m(%0);
+        }
+    ")).unwrap();
+    assert_eq!(json["ok"], true);
+    assert_eq!(json["results"]["measurements"], serde_json::json!([true]));
+    assert_eq!(json["results"]["return_value"], serde_json::json!({ "Bit": true }));
+}
+
+#[wasm_bindgen_test]
+fn renders_errors_as_plain_text() {
+    let json: Value = serde_json::from_str(&parser::wasm::run("def main() {}")).unwrap();
+    assert_eq!(json["ok"], false);
+    assert!(json["error"].as_str().unwrap().contains("qmain"));
+}